@@ -118,6 +118,9 @@ void apply_kernelsu_rules()
 	// Allow all binder transactions
 	ksu_allow(db, ALL, KERNEL_SU_DOMAIN, "binder", ALL);
 
+	// the manager asks ksud daemon on its abstract socket, ksud checks the peer uid
+	ksu_allow(db, ALL, KERNEL_SU_DOMAIN, "unix_stream_socket", "connectto");
+
 	// Allow system server devpts
 	ksu_allow(db, "system_server", "untrusted_app_all_devpts", "chr_file",
 		  "read");
@@ -2,7 +2,7 @@ use anyhow::Result;
 use const_format::concatcp;
 use rust_embed::RustEmbed;
 
-use crate::{defs::BINARY_DIR, utils};
+use crate::{defs::BINARY_DIR, restorecon, utils};
 
 pub const RESETPROP_PATH: &str = concatcp!(BINARY_DIR, "resetprop");
 pub const BUSYBOX_PATH: &str = concatcp!(BINARY_DIR, "busybox");
@@ -17,12 +17,28 @@ struct Asset;
 #[folder = "bin/x86_64"]
 struct Asset;
 
+/// if every binary is extracted, they may be of an older version
+pub fn binaries_exist() -> bool {
+    Asset::iter().all(|file| std::path::Path::new(&format!("{BINARY_DIR}{file}")).exists())
+}
+
 pub fn ensure_binaries() -> Result<()> {
-    for file in Asset::iter() {
-        utils::ensure_binary(
-            format!("{BINARY_DIR}{file}"),
-            &Asset::get(&file).unwrap().data,
-        )?
-    }
-    Ok(())
+    // unchanged binaries are skipped, the others are extracted at the same time
+    std::thread::scope(|scope| {
+        let handles = Asset::iter()
+            .map(|file| {
+                scope.spawn(move || {
+                    let path = format!("{BINARY_DIR}{file}");
+                    utils::ensure_binary(&path, &Asset::get(&file).unwrap().data)?;
+                    restorecon::set_binary_con(&path)
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle
+                .join()
+                .map_err(|_| anyhow::anyhow!("extract binary panicked"))??;
+        }
+        Ok(())
+    })
 }
@@ -0,0 +1,158 @@
+use anyhow::{ensure, Context, Result};
+use const_format::concatcp;
+use log::{info, warn};
+use std::{path::Path, process::Command};
+
+use crate::{assets, defs, utils};
+
+const BACKUP_DIR: &str = concatcp!(defs::WORKING_DIR, ".backup/");
+const MANIFEST_NAME: &str = "manifest.json";
+const SU_POLICY_NAME: &str = "su_policy.json";
+const IMAGE_NAME: &str = "modules.img";
+const CONFIG_DIR_NAME: &str = "config";
+
+// the files of the working dir which make up the state, the images and logs are not copied,
+// the module img location points to the storage of this device
+// the old config files are kept so that an old backup is restored, config.rs moves them into
+// CONFIG_PATH
+pub const CONFIG_FILES: &[&str] = &[
+    defs::CONFIG_PATH,
+    defs::KSURC_PATH,
+    defs::SHRINK_IMG_FLAG_PATH,
+    defs::EROFS_IMG_FLAG_PATH,
+    defs::MAGISK_COEXIST_FLAG_PATH,
+    defs::ISOLATE_MOUNT_FLAG_PATH,
+    defs::LOW_PRIORITY_FLAG_PATH,
+    defs::OVERLAY_PARTITIONS_PATH,
+    defs::OVERLAY_OPTIONS_PATH,
+    defs::HOSTS_FRAGMENT_PATH,
+    defs::MODULE_TRUSTED_KEYS_PATH,
+    defs::NO_NETWORK_FLAG_PATH,
+    defs::MODULE_BLOCKLIST_PATH,
+    defs::MODULE_PRIORITY_PATH,
+    defs::MODULE_PINLIST_PATH,
+    defs::MODULE_SAFE_MODE_DISABLED_PATH,
+    defs::SCRIPT_UMASK_PATH,
+    defs::STRICT_PROPS_FLAG_PATH,
+    defs::READONLY_MODULES_FLAG_PATH,
+];
+
+fn tar(args: &[&str]) -> Result<()> {
+    let status = Command::new(assets::BUSYBOX_PATH)
+        .arg("tar")
+        .args(args)
+        .status()
+        .with_context(|| "Failed to exec tar")?;
+    ensure!(status.success(), "tar {} failed", args.join(" "));
+    Ok(())
+}
+
+fn file_name(path: &str) -> &str {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or(path)
+}
+
+// a pending update is the newest state of the modules
+fn get_current_image() -> String {
+    let update_flag = Path::new(defs::WORKING_DIR).join(defs::UPDATE_FILE_NAME);
+    if update_flag.exists() && Path::new(defs::MODULE_UPDATE_IMG).exists() {
+        return defs::MODULE_UPDATE_IMG.to_string();
+    }
+    utils::get_module_img()
+}
+
+fn write_backup_dir() -> Result<()> {
+    let dir = Path::new(BACKUP_DIR);
+    let manifest = serde_json::json!({
+        "versionCode": defs::VERSION_CODE.trim(),
+        "versionName": defs::VERSION_NAME.trim(),
+        "kernelVersion": crate::ksu::get_version(),
+    });
+    std::fs::write(dir.join(MANIFEST_NAME), manifest.to_string())?;
+
+    let policy = crate::ksu::export_su_policy()?;
+    std::fs::write(dir.join(SU_POLICY_NAME), policy.to_string())?;
+
+    let config_dir = dir.join(CONFIG_DIR_NAME);
+    utils::ensure_dir_exists(&config_dir)?;
+    for file in CONFIG_FILES {
+        if Path::new(file).is_file() {
+            std::fs::copy(file, config_dir.join(file_name(file)))
+                .with_context(|| format!("Failed to copy {file}"))?;
+        }
+    }
+
+    let img = get_current_image();
+    if Path::new(&img).exists() {
+        // the image is large, a hard link saves the copy when it is on the same storage
+        let target = dir.join(IMAGE_NAME);
+        unsafe { libc::sync() };
+        if std::fs::hard_link(&img, &target).is_err() {
+            std::fs::copy(&img, &target).with_context(|| format!("Failed to copy {img}"))?;
+        }
+    } else {
+        warn!("no module image, only the config is backed up");
+    }
+    Ok(())
+}
+
+/// bundle the module image, the su policy and the config into the tar <out>
+pub fn backup(out: &str) -> Result<()> {
+    assets::ensure_binaries().with_context(|| "Failed to extract assets")?;
+    utils::ensure_clean_dir(BACKUP_DIR)?;
+    let result = write_backup_dir().and_then(|_| tar(&["-cf", out, "-C", BACKUP_DIR, "."]));
+    let _ = std::fs::remove_dir_all(BACKUP_DIR);
+    result?;
+    println!("- Backup saved to {out}");
+    Ok(())
+}
+
+fn restore_backup_dir() -> Result<()> {
+    let dir = Path::new(BACKUP_DIR);
+    let manifest = std::fs::read_to_string(dir.join(MANIFEST_NAME))
+        .with_context(|| "not a ksud backup, manifest.json not found")?;
+    let manifest: serde_json::Value = serde_json::from_str(&manifest)?;
+    let version_code = manifest["versionCode"].as_str().unwrap_or_default();
+    if version_code != defs::VERSION_CODE.trim() {
+        warn!(
+            "the backup is made by ksud {version_code}, this is {}",
+            defs::VERSION_CODE.trim()
+        );
+        println!(
+            "- Warning: the backup is made by ksud {version_code}, this is {}",
+            defs::VERSION_CODE.trim()
+        );
+    }
+
+    let img = dir.join(IMAGE_NAME);
+    if img.exists() {
+        crate::module::restore_image(&img.to_string_lossy())?;
+        println!("- Modules will be restored on reboot");
+    }
+
+    let config_dir = dir.join(CONFIG_DIR_NAME);
+    for file in CONFIG_FILES {
+        let src = config_dir.join(file_name(file));
+        if src.is_file() {
+            info!("restore {file}");
+            std::fs::copy(&src, file).with_context(|| format!("Failed to restore {file}"))?;
+        }
+    }
+
+    match std::fs::read_to_string(dir.join(SU_POLICY_NAME)) {
+        Ok(policy) => crate::ksu::import_su_policy(&serde_json::from_str(&policy)?)?,
+        Err(e) => warn!("no su policy in the backup: {e}"),
+    }
+    Ok(())
+}
+
+/// restore a backup of `backup`, the modules are staged as an update for the next boot
+pub fn restore(input: &str) -> Result<()> {
+    assets::ensure_binaries().with_context(|| "Failed to extract assets")?;
+    utils::ensure_clean_dir(BACKUP_DIR)?;
+    let result = tar(&["-xf", input, "-C", BACKUP_DIR]).and_then(|_| restore_backup_dir());
+    let _ = std::fs::remove_dir_all(BACKUP_DIR);
+    result
+}
@@ -1,17 +1,29 @@
 use anyhow::{Ok, Result};
 use clap::Parser;
 
-#[cfg(target_os = "android")]
-use android_logger::Config;
-#[cfg(target_os = "android")]
-use log::LevelFilter;
-
-use crate::{apk_sign, debug, defs, event, module, utils};
+use crate::{apk_sign, debug, defs, event, logger, module, utils};
 
 /// KernelSU userspace cli
 #[derive(Parser, Debug)]
 #[command(author, version = defs::VERSION_NAME, about, long_about = None)]
 struct Args {
+    /// print logs as json lines, same as env KSUD_LOG_FORMAT=json
+    #[arg(long, global = true)]
+    log_json: bool,
+
+    /// print debug logs, twice for trace logs
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// only print warnings and errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// run in this dir as / in private namespaces, so that no path or mount is of the live
+    /// system. same as env KSUD_ROOT
+    #[arg(long, global = true)]
+    root_dir: Option<String>,
+
     #[command(subcommand)]
     command: Commands,
 }
@@ -27,19 +39,73 @@ enum Commands {
         command: Module,
     },
 
+    /// Inspect systemless mounts of modules
+    Mount {
+        #[command(subcommand)]
+        command: Mount,
+    },
+
     /// Trigger `post-fs-data` event
-    PostFsData,
+    PostFsData {
+        /// only print what would be done, without mounting or executing anything
+        #[arg(long)]
+        dry_run: bool,
+    },
 
     /// Trigger `service` event
-    Services,
+    Services {
+        /// max number of service scripts running at the same time, default is the number of CPUs
+        #[arg(short, long)]
+        jobs: Option<usize>,
+    },
 
     /// Trigger `boot-complete` event
     BootCompleted,
 
+    /// Manage hosts entries merged into /system/etc/hosts
+    Hosts {
+        #[command(subcommand)]
+        command: Hosts,
+    },
+
+    /// Show how long the boot stages took
+    Profile {
+        #[command(subcommand)]
+        command: Profile,
+    },
+
+    /// Show the versions of ksud and the kernel
+    Version,
+
+    /// Show the apps which are granted root, the manager grants and revokes it
+    Su {
+        #[command(subcommand)]
+        command: Su,
+    },
+
+    /// Save the modules, the su policy and the config into a tar
+    Backup {
+        /// output tar file path
+        out: String,
+    },
+
+    /// Restore a tar made by `backup`, the modules are applied on the next boot
+    Restore {
+        /// backup tar file path
+        input: String,
+    },
+
+    /// Collect what is needed to debug a boot for a bug report
+    Diag {
+        #[command(subcommand)]
+        command: Diag,
+    },
+
     /// Install KernelSU userspace component to system
     Install,
 
     /// SELinux policy Patch tool
+    #[command(alias = "selinux")]
     Sepolicy {
         #[command(subcommand)]
         command: Sepolicy,
@@ -72,10 +138,26 @@ enum Debug {
     /// Get kernel version
     Version,
 
+    /// Show which modules the overlay and bind mounts come from
+    MountInfo,
+
     /// For testing
     Test,
 }
 
+#[derive(clap::Subcommand, Debug)]
+enum Diag {
+    /// Pack the boot reports, module logs, mounts, versions and config into a zip
+    Collect {
+        /// output zip file path
+        out: String,
+
+        /// hide the paths of user data, e.g. /data/data/<package> and /sdcard/<file>
+        #[arg(long)]
+        redact: bool,
+    },
+}
+
 #[derive(clap::Subcommand, Debug)]
 enum Sepolicy {
     /// Patch sepolicy
@@ -95,20 +177,147 @@ enum Sepolicy {
         /// sepolicy statements
         sepolicy: String,
     },
+
+    /// List the module rules loaded in this boot, grouped by module, and the failed ones
+    Status,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Mount {
+    /// list the overlay lowerdirs of each partition computed from modules
+    List {
+        /// module dir, default is /data/adb/modules
+        module_dir: Option<String>,
+    },
+
+    /// mount modules in the mount namespace of process <pid>, which must not be the global one
+    EnterNs {
+        /// process id
+        pid: i32,
+
+        /// umount modules instead, the process will see the stock filesystem
+        #[arg(long)]
+        clean: bool,
+    },
+
+    /// umount all module overlays and the module image, so that the image can be modified
+    UmountAll,
+
+    /// overlay the partitions of modules which were not there at post-fs-data and are now
+    Retry,
+
+    /// umount all modules and mount only module <id>, to check whether it breaks something
+    Only {
+        /// module id
+        id: String,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Profile {
+    /// print the time of each post-fs-data step in the last boot
+    LastBoot,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Su {
+    /// print the granted uids and their packages as json
+    List,
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Hosts {
+    /// add entry <ip> <host>
+    Add {
+        /// ip address
+        ip: String,
+        /// host name
+        host: String,
+    },
+
+    /// remove entries of <host>
+    Remove {
+        /// host name
+        host: String,
+    },
+}
+
+#[derive(clap::Subcommand, Debug)]
+enum Stage {
+    /// stage the install of module <ZIP>
+    Install {
+        /// module zip file path, `-` reads it from stdin
+        zip: String,
+    },
+
+    /// stage the uninstall of module <id>, its uninstall.sh is not run
+    Uninstall {
+        /// module id
+        id: String,
+    },
+
+    /// stage enabling module <id>
+    Enable {
+        /// module id
+        id: String,
+    },
+
+    /// stage disabling module <id>
+    Disable {
+        /// module id
+        id: String,
+    },
 }
 
 #[derive(clap::Subcommand, Debug)]
 enum Module {
     /// Install module <ZIP>
     Install {
+        /// module zip file path, `-` reads it from stdin
+        zip: String,
+
+        /// print the phase and percent of the install as json lines, the other output goes to stderr
+        #[arg(long)]
+        progress: bool,
+
+        /// work on the module image directly, without a booted system, e.g. in recovery
+        #[arg(long)]
+        direct: bool,
+    },
+
+    /// check module <ZIP> without installing it, the errors and warnings are printed as json
+    Lint {
         /// module zip file path
         zip: String,
     },
 
-    /// Uninstall module <id>
+    /// Update the installed module with <ZIP>, keeping its data
+    Update {
+        /// module zip file path, `-` reads it from stdin
+        zip: String,
+
+        /// allow a lower versionCode than the installed one
+        #[arg(long)]
+        downgrade: bool,
+
+        /// print the phase and percent of the install as json lines, the other output goes to stderr
+        #[arg(long)]
+        progress: bool,
+    },
+
+    /// Uninstall module <id>, refuse it if the other modules depend on it
     Uninstall {
         /// module id
         id: String,
+
+        /// uninstall it even if the other modules depend on it
+        #[arg(long)]
+        force: bool,
+
+        /// work on the module image directly, without a booted system, e.g. in recovery, the
+        /// other modules are not checked
+        #[arg(long)]
+        direct: bool,
     },
 
     /// enable module <id>
@@ -123,57 +332,258 @@ enum Module {
         id: String,
     },
 
+    /// set the mount priority of module <id>, the higher one wins a conflict. it is kept across
+    /// updates of the module, without <priority> the module's own one is used again
+    Reorder {
+        /// module id
+        id: String,
+
+        /// mount priority
+        #[arg(allow_negative_numbers = true)]
+        priority: Option<i32>,
+    },
+
+    /// keep module <id> enabled in safe mode, which disables all the others
+    Pin {
+        /// module id
+        id: String,
+
+        /// confirm it, safe mode can't recover a boot broken by a pinned module
+        #[arg(long)]
+        confirm: bool,
+    },
+
+    /// let safe mode disable module <id> again
+    Unpin {
+        /// module id
+        id: String,
+    },
+
+    /// never mount or execute module <id>, even if it is enabled
+    Block {
+        /// module id
+        id: String,
+    },
+
+    /// remove module <id> from the blocklist
+    Unblock {
+        /// module id
+        id: String,
+    },
+
     /// list all modules
-    List,
-}
+    List {
+        /// print the metadata, state, partitions and scripts of each module
+        #[arg(long)]
+        json: bool,
+    },
 
-pub fn run() -> Result<()> {
-    #[cfg(target_os = "android")]
-    android_logger::init_once(
-        Config::default()
-            .with_max_level(LevelFilter::Trace) // limit log level
-            .with_tag("KernelSU"), // logs will show under mytag tag
-    );
+    /// print what module <id> changes on the system as json
+    Info {
+        /// module id
+        id: String,
+    },
+
+    /// show the script logs of module <id> in the last boots
+    Logs {
+        /// module id
+        id: String,
+    },
 
-    #[cfg(not(target_os = "android"))]
-    env_logger::init();
+    /// pack the installed module <id> into a zip, without the files created at runtime
+    Export {
+        /// module id
+        id: String,
 
+        /// output zip file path
+        out: String,
+    },
+
+    /// let the runonce scripts of module <id> run again
+    ResetRunonce {
+        /// module id
+        id: String,
+    },
+
+    /// mount module <id> on a throwaway dir to check conflicts and SELinux contexts
+    MountTest {
+        /// module id
+        id: String,
+    },
+
+    /// verify the module images against their checksums
+    Verify,
+
+    /// stage a module change, the staged changes are applied together by stage-commit
+    Stage {
+        #[command(subcommand)]
+        command: Stage,
+    },
+
+    /// print the mount layout and the conflicts of the staged changes
+    StagePreview,
+
+    /// apply the staged changes on the next boot
+    StageCommit,
+
+    /// discard the staged changes
+    StageAbort,
+
+    /// shrink the module images to their contents, the free blocks of a mounted one are discarded
+    Trim,
+
+    /// print the files which more than one enabled module provides
+    Conflicts,
+
+    /// check `updateJson` of the modules and print the ones which have updates as json
+    CheckUpdates,
+
+    /// show how module <id> was mounted in this boot
+    Status {
+        /// module id
+        id: String,
+    },
+}
+
+// the global mount namespace, and a private copy of it if `unshare`. the sandbox of --root-dir
+// is a private namespace already, and the global one is outside of it
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn enter_private_mnt_ns(unshare: bool) -> Result<()> {
+    if utils::is_sandboxed() {
+        return Ok(());
+    }
+    utils::switch_mnt_ns(1)?;
+    if unshare {
+        utils::unshare_mnt_ns()?;
+    }
+    Ok(())
+}
+
+pub fn run() -> Result<()> {
     // the kernel executes su with argv[0] = "su" and replace it with us
     let arg0 = std::env::args().next().unwrap_or_default();
     if arg0 == "su" || arg0 == "/system/bin/su" {
+        logger::init(logger::json_enabled(false), logger::get_level(0, false));
         return crate::ksu::root_shell();
     }
 
     let cli = Args::parse();
 
+    // before the logger, no other thread may be running when the namespaces are unshared
+    if let Some(root) = cli.root_dir.clone().or_else(utils::root_dir) {
+        utils::enter_root_dir(&root)?;
+    }
+
+    // before any event runs, so that all of it is logged with this level
+    logger::init(
+        logger::json_enabled(cli.log_json),
+        logger::get_level(cli.verbose, cli.quiet),
+    );
+
     log::info!("command: {:?}", cli.command);
 
     let result = match cli.command {
         Commands::Daemon => event::daemon(),
-        Commands::PostFsData => event::on_post_data_fs(),
+        Commands::PostFsData { dry_run } => event::on_post_data_fs(dry_run),
         Commands::BootCompleted => event::on_boot_completed(),
 
         Commands::Module { command } => {
             #[cfg(any(target_os = "linux", target_os = "android"))]
             {
-                utils::switch_mnt_ns(1)?;
-                utils::unshare_mnt_ns()?;
+                // lint reads only the zip, it also runs off the device, e.g. in CI
+                if !matches!(command, Module::Lint { .. }) {
+                    enter_private_mnt_ns(true)?;
+                }
             }
             match command {
-                Module::Install { zip } => module::install_module(&zip),
-                Module::Uninstall { id } => module::uninstall_module(&id),
+                Module::Install {
+                    zip,
+                    progress,
+                    direct: false,
+                } => module::install_module(&zip, progress),
+                Module::Install { zip, progress, .. } => {
+                    module::install_module_direct(&zip, progress)
+                }
+                Module::Update {
+                    zip,
+                    downgrade,
+                    progress,
+                } => module::upgrade_module(&zip, downgrade, progress),
+                Module::Uninstall {
+                    id,
+                    force,
+                    direct: false,
+                } => module::uninstall_module(&id, force),
+                Module::Uninstall { id, .. } => module::uninstall_module_direct(&id),
                 Module::Enable { id } => module::enable_module(&id),
                 Module::Disable { id } => module::disable_module(&id),
-                Module::List => module::list_modules(),
+                Module::Block { id } => module::block_module(&id),
+                Module::Unblock { id } => module::unblock_module(&id),
+                Module::Pin { id, confirm } => module::pin_module(&id, confirm),
+                Module::Unpin { id } => module::unpin_module(&id),
+                Module::Reorder { id, priority } => module::reorder_module(&id, priority),
+                Module::List { json } => module::list_modules(json),
+                Module::Status { id } => module::module_status(&id),
+                Module::Info { id } => module::print_module_info(&id),
+                Module::Verify => module::verify_images(),
+                Module::Lint { zip } => module::lint_module(&zip),
+                Module::Trim => module::trim_images(),
+                Module::Stage { command } => match command {
+                    Stage::Install { zip } => module::stage_install(&zip),
+                    Stage::Uninstall { id } => module::stage_uninstall(&id),
+                    Stage::Enable { id } => module::stage_enable(&id, true),
+                    Stage::Disable { id } => module::stage_enable(&id, false),
+                },
+                Module::StagePreview => module::stage_preview(),
+                Module::StageCommit => module::stage_commit(),
+                Module::StageAbort => module::stage_abort(),
+                Module::Conflicts => event::print_conflicts(defs::MODULE_DIR),
+                Module::CheckUpdates => crate::update_check::check_updates(),
+                Module::Export { id, out } => module::export_module(&id, &out),
+                Module::ResetRunonce { id } => module::reset_runonce(&id),
+                Module::MountTest { id } => event::mount_test(&id),
+                Module::Logs { id } => module::print_module_logs(&id),
+            }
+        }
+        Commands::Mount { command } => {
+            // umount-all, retry and only work on the global namespace
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            enter_private_mnt_ns(!matches!(
+                command,
+                Mount::UmountAll | Mount::Retry | Mount::Only { .. }
+            ))?;
+            match command {
+                Mount::List { module_dir } => event::dump_mount_layout(module_dir.as_deref()),
+                Mount::EnterNs { pid, clean } => event::enter_ns(pid, clean),
+                Mount::UmountAll => event::umount_all(),
+                Mount::Retry => event::retry_pending_mounts(),
+                Mount::Only { id } => event::mount_only(&id),
             }
         }
+        Commands::Hosts { command } => match command {
+            Hosts::Add { ip, host } => crate::hosts::add_host(&ip, &host),
+            Hosts::Remove { host } => crate::hosts::remove_host(&host),
+        },
+        Commands::Profile { command } => match command {
+            Profile::LastBoot => crate::profile::print_last_boot(),
+        },
+        Commands::Version => crate::ksu::print_version(),
+        Commands::Su { command } => match command {
+            Su::List => crate::ksu::list_su(),
+        },
+        Commands::Backup { out } => crate::backup::backup(&out),
+        Commands::Restore { input } => crate::backup::restore(&input),
+        Commands::Diag { command } => match command {
+            Diag::Collect { out, redact } => crate::diag::collect(&out, redact),
+        },
         Commands::Install => event::install(),
         Commands::Sepolicy { command } => match command {
             Sepolicy::Patch { sepolicy } => crate::sepolicy::live_patch(&sepolicy),
             Sepolicy::Apply { file } => crate::sepolicy::apply_file(file),
             Sepolicy::Check { sepolicy } => crate::sepolicy::check_rule(&sepolicy),
+            Sepolicy::Status => crate::module::print_sepolicy_status(),
         },
-        Commands::Services => event::on_services(),
+        Commands::Services { jobs } => event::on_services(jobs),
 
         Commands::Debug { command } => match command {
             Debug::SetManager { apk } => debug::set_manager(&apk),
@@ -186,6 +596,7 @@ pub fn run() -> Result<()> {
                 println!("Kernel Version: {}", crate::ksu::get_version());
                 Ok(())
             }
+            Debug::MountInfo => debug::mount_info(),
             Debug::Su => crate::ksu::grant_root(),
             Debug::Test => todo!(),
         },
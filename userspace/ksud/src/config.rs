@@ -0,0 +1,255 @@
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::{
+    path::Path,
+    sync::{Arc, OnceLock, RwLock},
+};
+use toml::{value::Table, Value};
+
+use crate::defs;
+
+// bump it and append a migration when a key is renamed or changes its meaning
+const CONFIG_VERSION: i64 = 1;
+
+// MIGRATIONS[n] upgrades a config of version n to n + 1
+const MIGRATIONS: [fn(&mut Table) -> Vec<&'static str>; CONFIG_VERSION as usize] = [migrate_v0];
+
+/// the settings of CONFIG_PATH, a missing or invalid key keeps its default
+#[derive(Debug)]
+pub struct Config {
+    /// the partitions which modules may overlay, all of them if None
+    pub overlay_partitions: Option<Vec<String>>,
+    /// extra overlayfs options of the module mounts
+    pub overlay_options: Vec<String>,
+    pub script_umask: u32,
+    pub post_fs_data_timeout: u64,
+    pub common_script_timeout: u64,
+    /// run the service scripts with a low cpu and io priority
+    pub low_priority: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            overlay_partitions: None,
+            overlay_options: Vec::new(),
+            script_umask: defs::DEFAULT_SCRIPT_UMASK,
+            post_fs_data_timeout: defs::POST_FS_DATA_SCRIPT_TIMEOUT,
+            common_script_timeout: defs::COMMON_SCRIPT_TIMEOUT,
+            low_priority: false,
+        }
+    }
+}
+
+fn get_strings(table: &Table, key: &str) -> Option<Vec<String>> {
+    let value = table.get(key)?;
+    let Some(array) = value.as_array() else {
+        warn!("config: {key} must be an array of strings");
+        return None;
+    };
+    Some(
+        array
+            .iter()
+            .filter_map(Value::as_str)
+            .map(ToString::to_string)
+            .collect(),
+    )
+}
+
+fn get_bool(table: &Table, key: &str) -> Option<bool> {
+    let value = table.get(key)?.as_bool();
+    if value.is_none() {
+        warn!("config: {key} must be true or false");
+    }
+    value
+}
+
+fn get_u64(table: &Table, key: &str) -> Option<u64> {
+    let value = table.get(key)?;
+    let value = value.as_integer().and_then(|v| u64::try_from(v).ok());
+    if value.is_none() {
+        warn!("config: {key} must be a positive integer");
+    }
+    value
+}
+
+impl Config {
+    fn from_table(table: &Table) -> Self {
+        let overlay_partitions = get_strings(table, "overlay_partitions").map(|partitions| {
+            partitions
+                .iter()
+                .map(|p| p.trim().trim_matches('/').to_string())
+                .filter(|p| !p.is_empty())
+                .collect()
+        });
+        let mut config = Self {
+            overlay_partitions,
+            ..Self::default()
+        };
+        if let Some(options) = get_strings(table, "overlay_options") {
+            config.overlay_options = options;
+        }
+        if let Some(mask) = table.get("script_umask") {
+            match mask
+                .as_str()
+                .and_then(|m| u32::from_str_radix(m.trim(), 8).ok())
+                .filter(|m| *m <= 0o777)
+            {
+                Some(mask) => config.script_umask = mask,
+                None => warn!(
+                    "config: script_umask must be an octal string up to \"777\", e.g. \"022\""
+                ),
+            }
+        }
+        if let Some(timeout) = get_u64(table, "post_fs_data_timeout") {
+            config.post_fs_data_timeout = timeout;
+        }
+        if let Some(timeout) = get_u64(table, "common_script_timeout") {
+            config.common_script_timeout = timeout;
+        }
+        if let Some(low) = get_bool(table, "low_priority") {
+            config.low_priority = low;
+        }
+        config
+    }
+}
+
+// the settings were separate files before config.toml, they are moved into it.
+// returns the files which are merged and can be removed
+fn migrate_v0(table: &mut Table) -> Vec<&'static str> {
+    let mut merged = Vec::new();
+    let read_list = |path: &str, separator: fn(char) -> bool| {
+        std::fs::read_to_string(path).ok().map(|content| {
+            content
+                .lines()
+                .map(str::trim)
+                .filter(|line| !line.starts_with('#'))
+                .flat_map(|line| line.split(separator))
+                .map(str::trim)
+                .filter(|item| !item.is_empty())
+                .map(|item| Value::String(item.to_string()))
+                .collect::<Vec<_>>()
+        })
+    };
+
+    if let Some(partitions) = read_list(defs::OVERLAY_PARTITIONS_PATH, |_| false) {
+        table.insert("overlay_partitions".to_string(), Value::Array(partitions));
+        merged.push(defs::OVERLAY_PARTITIONS_PATH);
+    }
+    if let Some(options) = read_list(defs::OVERLAY_OPTIONS_PATH, |c| {
+        c == ',' || c.is_whitespace()
+    }) {
+        table.insert("overlay_options".to_string(), Value::Array(options));
+        merged.push(defs::OVERLAY_OPTIONS_PATH);
+    }
+    if let Ok(mask) = std::fs::read_to_string(defs::SCRIPT_UMASK_PATH) {
+        table.insert(
+            "script_umask".to_string(),
+            Value::String(mask.trim().to_string()),
+        );
+        merged.push(defs::SCRIPT_UMASK_PATH);
+    }
+    if Path::new(defs::LOW_PRIORITY_FLAG_PATH).exists() {
+        table.insert("low_priority".to_string(), Value::Boolean(true));
+        merged.push(defs::LOW_PRIORITY_FLAG_PATH);
+    }
+    merged
+}
+
+fn save(table: &Table) -> Result<()> {
+    let content = toml::to_string(table)?;
+    // renamed into place, a power loss never leaves half a config
+    let tmp = format!("{}.tmp", defs::CONFIG_PATH);
+    std::fs::write(&tmp, content).with_context(|| format!("Failed to write {tmp}"))?;
+    std::fs::rename(&tmp, defs::CONFIG_PATH)
+        .with_context(|| format!("Failed to write {}", defs::CONFIG_PATH))
+}
+
+// CONFIG_PATH upgraded to CONFIG_VERSION in memory with its version before and the files merged
+// into it, None if it is invalid
+fn read() -> Option<(Table, i64, Vec<&'static str>)> {
+    let mut table = match std::fs::read_to_string(defs::CONFIG_PATH) {
+        Ok(content) => match toml::from_str::<Table>(&content) {
+            Ok(table) => table,
+            Err(_) => {
+                // never overwrite a config the user may still fix
+                warn!("{} is invalid, use the defaults", defs::CONFIG_PATH);
+                return None;
+            }
+        },
+        Err(_) => Table::new(),
+    };
+
+    let version = table
+        .get("version")
+        .and_then(Value::as_integer)
+        .unwrap_or(0);
+    if version > CONFIG_VERSION {
+        warn!(
+            "{} is of version {version}, newer than {CONFIG_VERSION}, the unknown keys are ignored",
+            defs::CONFIG_PATH
+        );
+        return Some((table, version, Vec::new()));
+    }
+
+    let start = usize::try_from(version).unwrap_or(0);
+    let mut merged = Vec::new();
+    for migrate in &MIGRATIONS[start..] {
+        merged.extend(migrate(&mut table));
+    }
+    // the old files may show up again, e.g. restored from an old backup, they still win
+    if start > 0 {
+        merged.extend(migrate_v0(&mut table));
+    }
+    Some((table, version, merged))
+}
+
+static CONFIG: OnceLock<RwLock<Arc<Config>>> = OnceLock::new();
+
+fn load() -> Config {
+    read().map_or_else(Config::default, |(table, _, _)| Config::from_table(&table))
+}
+
+fn get_lock() -> &'static RwLock<Arc<Config>> {
+    CONFIG.get_or_init(|| RwLock::new(Arc::new(load())))
+}
+
+/// the settings of CONFIG_PATH, it is read on the first use and again by `reload`
+pub fn get() -> Arc<Config> {
+    get_lock()
+        .read()
+        .map_or_else(|e| e.into_inner().clone(), |config| config.clone())
+}
+
+/// read CONFIG_PATH again, the ones `get` returned before keep the old settings
+pub fn reload() {
+    let config = Arc::new(load());
+    match get_lock().write() {
+        Ok(mut current) => *current = config,
+        Err(e) => *e.into_inner() = config,
+    }
+}
+
+/// write an older config back upgraded and remove the files merged into it, only post-fs-data
+/// does it so that the other commands never race it
+pub fn migrate() {
+    let Some((mut table, version, merged)) = read() else {
+        return;
+    };
+    if version > CONFIG_VERSION || (version == CONFIG_VERSION && merged.is_empty()) {
+        return;
+    }
+    table.insert("version".to_string(), Value::Integer(CONFIG_VERSION));
+    // a fresh install has nothing to migrate, the file is only written for a real config
+    if table.len() > 1 || Path::new(defs::CONFIG_PATH).exists() {
+        match save(&table) {
+            Ok(()) => {
+                info!("{} is upgraded from version {version}", defs::CONFIG_PATH);
+                for file in merged {
+                    let _ = std::fs::remove_file(file);
+                }
+            }
+            Err(e) => warn!("upgrade {} failed: {e:#}", defs::CONFIG_PATH),
+        }
+    }
+}
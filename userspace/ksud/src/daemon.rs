@@ -0,0 +1,226 @@
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::io::{BufRead, BufReader, Write};
+
+#[cfg(target_os = "android")]
+use std::os::android::net::SocketAddrExt;
+#[cfg(target_os = "linux")]
+use std::os::linux::net::SocketAddrExt;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::os::unix::{
+    io::AsRawFd,
+    net::{SocketAddr, UnixListener, UnixStream},
+};
+
+use crate::{defs, utils};
+
+// the phase of this boot, the last post-fs-data stage until the boot is completed
+fn get_phase() -> String {
+    if utils::getprop("sys.boot_completed").as_deref() == Some("1") {
+        return "boot-completed".to_string();
+    }
+    let stage = std::fs::read_to_string(defs::BOOT_STAGE_PATH).unwrap_or_default();
+    format!("post-fs-data: {}", stage.trim())
+}
+
+// (installed, enabled) modules
+fn get_module_count() -> (usize, usize) {
+    let Ok(dir) = std::fs::read_dir(defs::MODULE_DIR) else {
+        return (0, 0);
+    };
+    let modules = dir
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.join("module.prop").exists())
+        .collect::<Vec<_>>();
+    let enabled = modules
+        .iter()
+        .filter(|path| !crate::module::is_disabled(path))
+        .count();
+    (modules.len(), enabled)
+}
+
+// the boot count is only left after a boot which didn't complete
+fn get_unfinished_boots() -> u32 {
+    std::fs::read_to_string(defs::BOOT_COUNT_PATH)
+        .ok()
+        .and_then(|c| c.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+// the free space of /data, `low` if the boot skips what it can do without
+fn get_storage() -> serde_json::Value {
+    let free = utils::get_free_space(defs::ADB_DIR).ok();
+    serde_json::json!({
+        "free": free,
+        "low": free.map_or(false, |free| free < defs::LOW_SPACE_THRESHOLD),
+    })
+}
+
+fn handle_request(request: &str) -> serde_json::Value {
+    let cmd = serde_json::from_str::<serde_json::Value>(request)
+        .ok()
+        .and_then(|r| r["cmd"].as_str().map(ToString::to_string));
+    match cmd.as_deref() {
+        Some("phase") => serde_json::json!({ "phase": get_phase() }),
+        Some("modules") => {
+            let (installed, enabled) = get_module_count();
+            serde_json::json!({ "installed": installed, "enabled": enabled })
+        }
+        Some("safe_mode") => serde_json::json!({ "safe_mode": utils::is_safe_mode() }),
+        Some("storage") => get_storage(),
+        Some("last_boot") => {
+            let unfinished = get_unfinished_boots();
+            serde_json::json!({ "success": unfinished == 0, "unfinished_boots": unfinished })
+        }
+        Some("status") => {
+            let (installed, enabled) = get_module_count();
+            let unfinished = get_unfinished_boots();
+            serde_json::json!({
+                "phase": get_phase(),
+                "modules": { "installed": installed, "enabled": enabled },
+                "safe_mode": utils::is_safe_mode(),
+                "storage": get_storage(),
+                "last_boot": { "success": unfinished == 0, "unfinished_boots": unfinished },
+                "version": crate::ksu::get_version_info(),
+            })
+        }
+        Some(cmd) => serde_json::json!({ "error": format!("unknown cmd: {cmd}") }),
+        None => serde_json::json!({ "error": "request must be a json object with \"cmd\"" }),
+    }
+}
+
+// the uid of the process on the other end of the socket
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn get_peer_uid(stream: &UnixStream) -> Result<u32> {
+    let mut cred = unsafe { std::mem::zeroed::<libc::ucred>() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            std::ptr::addr_of_mut!(cred).cast(),
+            &mut len,
+        )
+    };
+    anyhow::ensure!(
+        ret == 0,
+        "SO_PEERCRED failed: {}",
+        std::io::Error::last_os_error()
+    );
+    Ok(cred.uid)
+}
+
+// root, or the manager of any user
+fn is_allowed_peer(uid: u32, manager_uid: Option<u32>) -> bool {
+    const PER_USER_RANGE: u32 = 100_000;
+    uid == 0
+        || manager_uid.map_or(false, |manager| {
+            uid % PER_USER_RANGE == manager % PER_USER_RANGE
+        })
+}
+
+// one json request per line, each is answered with one json line
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn handle_client(stream: UnixStream) -> Result<()> {
+    let uid = get_peer_uid(&stream)?;
+    // the manager may be reinstalled with another uid, look it up on every connection
+    let manager_uid = crate::ksu::get_package_uid(defs::MANAGER_PACKAGE);
+    anyhow::ensure!(
+        is_allowed_peer(uid, manager_uid),
+        "uid {uid} is not allowed to connect"
+    );
+
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        writeln!(writer, "{}", handle_request(&line))?;
+        writer.flush()?;
+    }
+    Ok(())
+}
+
+// the signals are blocked in every thread and taken by this one, so the handling is not limited
+// to what is async-signal-safe. SIGHUP reads the config again instead of exiting, which it does
+// by default
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn handle_signals() -> Result<()> {
+    let mut set = unsafe { std::mem::zeroed::<libc::sigset_t>() };
+    unsafe {
+        libc::sigemptyset(&mut set);
+        for sig in [libc::SIGTERM, libc::SIGINT, libc::SIGHUP] {
+            libc::sigaddset(&mut set, sig);
+        }
+        let ret = libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut());
+        anyhow::ensure!(ret == 0, "pthread_sigmask failed: {ret}");
+    }
+
+    std::thread::spawn(move || loop {
+        let mut sig = 0;
+        if unsafe { libc::sigwait(&set, &mut sig) } != 0 {
+            continue;
+        }
+        if sig == libc::SIGHUP {
+            crate::config::reload();
+            info!("SIGHUP, {} is read again", defs::CONFIG_PATH);
+            continue;
+        }
+        info!("signal {sig}, shutdown");
+        log::logger().flush();
+        std::process::exit(0);
+    });
+    Ok(())
+}
+
+/// answer the status requests on the abstract socket DAEMON_SOCKET_NAME, only root and the
+/// manager can talk to it. SIGTERM and SIGINT exit, the socket goes with the process
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn run() -> Result<()> {
+    // before any other thread is spawned, so that they inherit the blocked signals
+    handle_signals()?;
+
+    // an abstract socket has no file, so the manager app can reach it without a path under
+    // /data/adb. the one of a running daemon is in use and this one fails to bind
+    let addr = SocketAddr::from_abstract_name(defs::DAEMON_SOCKET_NAME)?;
+    let listener = UnixListener::bind_addr(&addr)
+        .with_context(|| format!("Failed to bind @{}", defs::DAEMON_SOCKET_NAME))?;
+    info!("listening on @{}", defs::DAEMON_SOCKET_NAME);
+
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                std::thread::spawn(move || {
+                    if let Err(e) = handle_client(stream) {
+                        warn!("client error: {e}");
+                    }
+                });
+            }
+            Err(e) => warn!("accept failed: {e}"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn run() -> Result<()> {
+    unimplemented!()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peer_is_root_or_manager_of_any_user() {
+        assert!(is_allowed_peer(0, None));
+        assert!(is_allowed_peer(10_123, Some(10_123)));
+        // the manager of the second user
+        assert!(is_allowed_peer(1_010_123, Some(10_123)));
+        assert!(!is_allowed_peer(10_124, Some(10_123)));
+        assert!(!is_allowed_peer(10_123, None));
+    }
+}
@@ -59,3 +59,81 @@ pub fn set_manager(pkg: &str) -> Result<()> {
     set_kernel_param(sign.0, sign.1)?;
     Ok(())
 }
+
+// $MODULE_DIR/<id>/... -> <id>
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn get_module_id_of(path: &Path, module_dir: &Path) -> Option<String> {
+    let relative = path.strip_prefix(module_dir).ok()?;
+    let id = relative.components().next()?;
+    Some(id.as_os_str().to_string_lossy().to_string())
+}
+
+/// print the overlay and bind mounts made from modules, which modules they come from and
+/// the mount they shadow
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn mount_info() -> Result<()> {
+    use std::collections::HashMap;
+
+    let mountinfos = procfs::process::Process::myself()?.mountinfo()?;
+    let module_dir = Path::new(crate::defs::MODULE_DIR);
+    let by_id = mountinfos
+        .iter()
+        .map(|m| (m.mnt_id, m))
+        .collect::<HashMap<_, _>>();
+    // the module image, bind mounts of its files have the same device
+    let image = mountinfos.iter().find(|m| m.mount_point == module_dir);
+
+    println!("{:<40} {:<8} {:<30} SHADOWS", "TARGET", "TYPE", "MODULES");
+    for m in &mountinfos {
+        let (kind, mut modules) = if m.fs_type == "overlay" {
+            let lowerdirs = m
+                .super_options
+                .get("lowerdir")
+                .and_then(Clone::clone)
+                .unwrap_or_default();
+            let modules = lowerdirs
+                .split(':')
+                .filter_map(|dir| get_module_id_of(Path::new(dir), module_dir))
+                .collect::<Vec<_>>();
+            ("overlay", modules)
+        } else if image.map_or(false, |image| {
+            image.majmin == m.majmin && !m.mount_point.starts_with(module_dir)
+        }) {
+            // the root of a bind mount is the path in the image: /<id>/system/...
+            let modules = get_module_id_of(Path::new(&m.root), Path::new("/"))
+                .into_iter()
+                .collect::<Vec<_>>();
+            ("bind", modules)
+        } else {
+            continue;
+        };
+        if modules.is_empty() {
+            continue;
+        }
+        modules.dedup();
+
+        let shadows = by_id.get(&m.pid).map_or_else(
+            || "-".to_string(),
+            |parent| {
+                format!(
+                    "{} ({} {})",
+                    parent.mount_point.display(),
+                    parent.fs_type,
+                    parent.mount_source.as_deref().unwrap_or("none")
+                )
+            },
+        );
+        println!(
+            "{:<40} {:<8} {:<30} {shadows}",
+            m.mount_point.display(),
+            kind,
+            modules.join(",")
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn mount_info() -> Result<()> {
+    unimplemented!()
+}
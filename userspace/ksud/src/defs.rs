@@ -1,5 +1,6 @@
 use const_format::concatcp;
 
+// the paths of the device, `--root-dir` chroots ksud into a sandbox tree which has them
 pub const ADB_DIR: &str = "/data/adb/";
 pub const WORKING_DIR: &str = concatcp!(ADB_DIR, "ksu/");
 pub const BINARY_DIR: &str = concatcp!(WORKING_DIR, "bin/");
@@ -7,6 +8,10 @@ pub const BINARY_DIR: &str = concatcp!(WORKING_DIR, "bin/");
 pub const KSURC_PATH: &str = concatcp!(WORKING_DIR, ".ksurc");
 pub const DAEMON_PATH: &str = concatcp!(ADB_DIR, "ksud");
 
+// the abstract socket which `ksud daemon` answers json line requests on, e.g. {"cmd":"status"}
+pub const DAEMON_SOCKET_NAME: &str = "ksud";
+pub const MANAGER_PACKAGE: &str = "me.weishu.kernelsu";
+
 #[cfg(target_os = "android")]
 pub const DAEMON_LINK_PATH: &str = concatcp!(BINARY_DIR, "ksud");
 
@@ -14,6 +19,92 @@ pub const MODULE_DIR: &str = concatcp!(ADB_DIR, "modules/");
 pub const MODULE_IMG: &str = concatcp!(WORKING_DIR, "modules.img");
 pub const MODULE_UPDATE_IMG: &str = concatcp!(WORKING_DIR, "modules_update.img");
 
+// a dir on /data which holds modules.img instead of WORKING_DIR, it must be mounted and
+// unlocked at post-fs-data
+pub const MODULE_IMG_LOCATION_PATH: &str = concatcp!(WORKING_DIR, "module_img_location");
+pub const MODULE_IMG_NAME: &str = "modules.img";
+
+// sha256 of a module image as it was built unmounted, it is checked before the boot mounts it,
+// by `ksud module verify` and stage-commit. the read-write mount of an ext4 image drops it
+pub const IMG_CHECKSUM_SUFFIX: &str = ".sha256";
+
+// a module image which failed to check or mount is renamed with this suffix
+pub const BAD_IMG_SUFFIX: &str = ".bad";
+
+// below this free space on /data, the boot skips what it can do without, e.g. extracting the
+// binaries again, and the daemon reports it
+pub const LOW_SPACE_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+// the layout version of the module image which built it, bump it when an older ksud can't mount
+// the new images correctly. an image without the sidecar is of version 0
+pub const IMG_FORMAT_SUFFIX: &str = ".format";
+pub const IMG_FORMAT_VERSION: u32 = 1;
+
+// an update image of a newer format than this ksud knows is renamed with this suffix
+pub const NEWER_IMG_SUFFIX: &str = ".newer";
+
+// shrink the module image on boot to save storage if this file exists
+pub const SHRINK_IMG_FLAG_PATH: &str = concatcp!(WORKING_DIR, ".shrink_img");
+
+// build the module image as read-only compressed erofs on the next install or update
+pub const EROFS_IMG_FLAG_PATH: &str = concatcp!(WORKING_DIR, ".erofs_img");
+// the images are mounted here to convert between ext4 and erofs
+pub const EROFS_STAGING_DIR: &str = concatcp!(WORKING_DIR, "erofs_staging/");
+
+// the last stage post-fs-data reached, "complete" if it finished
+pub const BOOT_STAGE_PATH: &str = concatcp!(WORKING_DIR, ".boot_stage");
+pub const BOOT_STAGE_COMPLETE: &str = "complete";
+
+// boots in a row which didn't reach boot-completed, all modules are disabled when it reaches MAX_BOOT_FAILURES
+pub const BOOT_COUNT_PATH: &str = concatcp!(WORKING_DIR, ".boot_count");
+
+// the boot id and the events already reported to the kernel in that boot
+pub const REPORTED_EVENTS_PATH: &str = concatcp!(WORKING_DIR, ".reported_events");
+pub const MAX_BOOT_FAILURES: u32 = 3;
+
+// run KernelSU modules in a private mount namespace instead of skipping them when Magisk is active
+pub const MAGISK_COEXIST_FLAG_PATH: &str = concatcp!(WORKING_DIR, ".magisk_coexist");
+
+// don't mount modules in the global mount namespace, see `ksud mount enter-ns`
+pub const ISOLATE_MOUNT_FLAG_PATH: &str = concatcp!(WORKING_DIR, ".isolate_mount");
+
+// run the service scripts with a low cpu and io priority, it is moved into CONFIG_PATH as
+// low_priority
+pub const LOW_PRIORITY_FLAG_PATH: &str = concatcp!(WORKING_DIR, ".low_priority");
+
+// the versioned settings of ksud, see config.rs
+pub const CONFIG_PATH: &str = concatcp!(WORKING_DIR, "config.toml");
+
+// the partitions which modules may overlay, one per line, all partitions if it doesn't exist.
+// it is moved into CONFIG_PATH as overlay_partitions
+pub const OVERLAY_PARTITIONS_PATH: &str = concatcp!(WORKING_DIR, "overlay_partitions");
+
+// extra overlayfs options of the module mounts, e.g. metacopy=on,redirect_dir=on, they are dropped
+// if the kernel rejects them. it is moved into CONFIG_PATH as overlay_options
+pub const OVERLAY_OPTIONS_PATH: &str = concatcp!(WORKING_DIR, "overlay_options");
+
+// hosts entries managed by `ksud hosts`, merged with the stock and module hosts on boot
+pub const HOSTS_FRAGMENT_PATH: &str = concatcp!(WORKING_DIR, "hosts");
+pub const HOSTS_MERGED_PATH: &str = concatcp!(WORKING_DIR, "hosts.merged");
+// the stock hosts as it was before the merged one covered it, to merge it again after a change
+pub const HOSTS_STOCK_PATH: &str = concatcp!(WORKING_DIR, "hosts.stock");
+
+// the top lowerdir of /system, it links system/<partition> to /<partition> over the modules
+// which keep that partition in system/<partition>
+pub const LEGACY_LINKS_DIR: &str = concatcp!(WORKING_DIR, "legacy_links");
+
+// `ksud post-fs-data --dry-run` mounts the module image here read-only
+pub const DRY_RUN_DIR: &str = concatcp!(WORKING_DIR, "dry_run");
+
+// `ksud module mount-test` mounts the module here in its own mount namespace
+pub const MOUNT_TEST_DIR: &str = concatcp!(WORKING_DIR, "mount_test/");
+
+pub const MOUNT_REPORT_PATH: &str = concatcp!(WORKING_DIR, "mount_report.json");
+// the module sepolicy rules loaded in this boot and their errors, see `ksud sepolicy status`
+pub const SEPOLICY_REPORT_PATH: &str = concatcp!(WORKING_DIR, "sepolicy_report.json");
+// how long each step of post-fs-data took in the last boot, see `ksud profile last-boot`
+pub const BOOT_TIMING_PATH: &str = concatcp!(WORKING_DIR, "boot_timing.json");
+
 pub const MODULE_UPDATE_TMP_IMG: &str = concatcp!(WORKING_DIR, "update_tmp.img");
 
 // warning: this directory should not change, or you need to change the code in module_installer.sh!!!
@@ -21,7 +112,85 @@ pub const MODULE_UPDATE_TMP_DIR: &str = concatcp!(ADB_DIR, "modules_update/");
 
 pub const DISABLE_FILE_NAME: &str = "disable";
 pub const UPDATE_FILE_NAME: &str = "update";
+// changes are staged in MODULE_UPDATE_IMG without the update flag, see `ksud module stage`
+pub const MODULE_STAGE_FLAG_PATH: &str = concatcp!(WORKING_DIR, ".module_stage");
+// ids uninstalled in the staged image, their uninstall.sh runs on stage-commit
+pub const MODULE_STAGED_UNINSTALL_PATH: &str = concatcp!(WORKING_DIR, ".module_stage_uninstall");
 pub const REMOVE_FILE_NAME: &str = "remove";
 
+// the partitions which the installed modules have files for, at the root of the module image
+pub const PARTITION_MANIFEST_NAME: &str = ".partitions";
+
+// the partitions which modules have files for but which were not there at post-fs-data, they
+// are overlaid once they appear by boot-completed or `ksud mount retry`
+pub const PENDING_PARTITIONS_PATH: &str = concatcp!(WORKING_DIR, ".pending_partitions");
+
+// ed25519 public keys in PEM, if any, only the module zips signed by one of them can be installed
+pub const MODULE_TRUSTED_KEYS_PATH: &str = concatcp!(WORKING_DIR, "keys");
+
+// ksud never accesses the network if it exists, e.g. to check module updates
+pub const NO_NETWORK_FLAG_PATH: &str = concatcp!(WORKING_DIR, ".no_network");
+
+// ids of the modules which are never mounted or executed, one per line, the modules can't remove it
+pub const MODULE_BLOCKLIST_PATH: &str = concatcp!(WORKING_DIR, "module_blocklist");
+// ids of the modules which safe mode or the failed boots disabled, out of the image so that it
+// works for a read-only one too, `ksud module enable` drops the id
+pub const MODULE_SAFE_MODE_DISABLED_PATH: &str = concatcp!(WORKING_DIR, "module_safemode_disabled");
+
+// ids of the modules which safe mode doesn't disable, one per line. a pinned module which breaks
+// the boot defeats safe mode, so pinning asks for a confirmation
+pub const MODULE_PINLIST_PATH: &str = concatcp!(WORKING_DIR, "module_pinlist");
+
+// `<id> <priority>` per line set by `ksud module reorder`, it wins over `priority` of module.prop
+// which an update of the module would overwrite
+pub const MODULE_PRIORITY_PATH: &str = concatcp!(WORKING_DIR, "module_priority");
+
+// a module declares `runonce=post-fs-data.sh,service.sh` in module.prop, such a script is skipped
+// after it touches <MODULE_RUNONCE_DIR>/<id>/<script>, which it gets as KSU_RUNONCE_MARKER. out of
+// the module image, which may be read-only
+pub const MODULE_RUNONCE_DIR: &str = concatcp!(WORKING_DIR, "runonce/");
+
+// output and exit status of module scripts in <MODULE_LOG_DIR>/<id>/, the logs of the last
+// MODULE_LOG_HISTORY boots are kept, the ones of the previous boots are gzipped and take at most
+// MODULE_LOG_MAX_SIZE bytes per module. out of the module image, which may be read-only
+pub const MODULE_LOG_DIR: &str = concatcp!(WORKING_DIR, "logs/");
+pub const MODULE_LOG_FILE_NAME: &str = "last_run.log";
+pub const MODULE_LOG_HISTORY: usize = 5;
+pub const MODULE_LOG_MAX_SIZE: u64 = 256 * 1024;
+
+// a prop which several modules set to different values is not set at all, unless one of them
+// lists it in `authoritativeProps` of module.prop, without it the module mounted on top wins
+pub const STRICT_PROPS_FLAG_PATH: &str = concatcp!(WORKING_DIR, ".strict_props");
+// remount the module dir read-only once the boot is completed, ksud makes it writable again
+// for its own changes
+pub const READONLY_MODULES_FLAG_PATH: &str = concatcp!(WORKING_DIR, ".readonly_modules");
+
+// the umask of ksud and the scripts, in octal, `umask` in module.prop overrides it for the module.
+// it is moved into CONFIG_PATH as script_umask
+pub const SCRIPT_UMASK_PATH: &str = concatcp!(WORKING_DIR, ".umask");
+pub const DEFAULT_SCRIPT_UMASK: u32 = 0o022;
+
+// post-fs-data.sh of each module must finish in this many seconds, or it will be killed
+pub const POST_FS_DATA_SCRIPT_TIMEOUT: u64 = 30;
+
+// the same for the scripts in /data/adb/*.d which ksud waits for
+pub const COMMON_SCRIPT_TIMEOUT: u64 = 30;
+
+// run in safe mode before the modules are disabled, from WORKING_DIR and from each module,
+// with the post-fs-data timeout
+pub const SAFE_MODE_SCRIPT_NAME: &str = "safemode.sh";
+pub const SAFE_MODE_SCRIPT_PATH: &str = concatcp!(WORKING_DIR, SAFE_MODE_SCRIPT_NAME);
+
+// run once in post-fs-data after the module image is mounted and before any module is mounted,
+// e.g. to set a prop which the modules rely on, with the post-fs-data timeout
+pub const PRE_MOUNT_SCRIPT_PATH: &str = concatcp!(WORKING_DIR, "pre-mount.sh");
+
+// bind mount module files when overlayfs is rejected by a partition. `mountMode=overlay|magic|auto`
+// in module.prop overrides it, and the older `magicMount=false` means overlay
+pub const MAGIC_MOUNT_FALLBACK: bool = true;
+
+// the kernel versions since major * 10000 + git count, older ones miss the commands ksud uses
+pub const MIN_KSU_KERNEL_VERSION: i32 = 10200;
+
 pub const VERSION_CODE: &str = include_str!(concat!(env!("OUT_DIR"), "/VERSION_CODE"));
 pub const VERSION_NAME: &str = include_str!(concat!(env!("OUT_DIR"), "/VERSION_NAME"));
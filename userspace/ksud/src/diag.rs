@@ -0,0 +1,102 @@
+use anyhow::{Context, Result};
+use log::info;
+use std::{fs::File, io::Write, path::Path};
+
+use crate::{backup, defs, module};
+
+// the reports of this boot, the mount report says why a module isn't applied
+const REPORTS: [&str; 3] = [
+    defs::BOOT_TIMING_PATH,
+    defs::MOUNT_REPORT_PATH,
+    defs::SEPOLICY_REPORT_PATH,
+];
+
+// the paths inside app data and the shared storage name the user's apps and files
+fn redact(content: &str) -> String {
+    let Ok(re) = regex::Regex::new(
+        r#"(/data/(?:user(?:_de)?/\d+|data|media/\d+)/|/storage/emulated/\d+/|/sdcard/)[^\s"',:;]+"#,
+    ) else {
+        return content.to_string();
+    };
+    re.replace_all(content, "${1}<redacted>").into_owned()
+}
+
+fn get_versions() -> String {
+    let kernel = std::fs::read_to_string("/proc/version").unwrap_or_default();
+    format!(
+        "ksud: {} ({})\nKernelSU kernel: {}\nkernel: {}\n",
+        defs::VERSION_NAME.trim(),
+        defs::VERSION_CODE.trim(),
+        crate::ksu::get_version(),
+        kernel.trim()
+    )
+}
+
+struct Bundle {
+    zip: zip::ZipWriter<File>,
+    redact: bool,
+}
+
+impl Bundle {
+    fn add(&mut self, name: &str, content: &[u8]) -> Result<()> {
+        self.zip
+            .start_file(name, zip::write::FileOptions::default())?;
+        if self.redact {
+            self.zip
+                .write_all(redact(&String::from_utf8_lossy(content)).as_bytes())?;
+        } else {
+            self.zip.write_all(content)?;
+        }
+        Ok(())
+    }
+
+    // a missing file is skipped, not every boot has every report
+    fn add_file(&mut self, dir: &str, path: &str) -> Result<()> {
+        let Ok(content) = std::fs::read(path) else {
+            return Ok(());
+        };
+        let name = Path::new(path)
+            .file_name()
+            .map_or_else(|| path.to_string(), |n| n.to_string_lossy().to_string());
+        self.add(&format!("{dir}{name}"), &content)
+    }
+}
+
+fn write_bundle(out: &str, redact: bool) -> Result<()> {
+    let file = File::create(out).with_context(|| format!("Failed to create {out}"))?;
+    let mut bundle = Bundle {
+        zip: zip::ZipWriter::new(file),
+        redact,
+    };
+
+    bundle.add("version.txt", get_versions().as_bytes())?;
+    for report in REPORTS {
+        bundle.add_file("", report)?;
+    }
+    // the mounts of the global namespace, the cli may be in a private one
+    let mounts = std::fs::read("/proc/1/mounts").or_else(|_| std::fs::read("/proc/self/mounts"));
+    if let Ok(mounts) = mounts {
+        bundle.add("proc_mounts.txt", &mounts)?;
+    }
+    for (id, log) in module::get_last_run_logs() {
+        bundle.add(&format!("logs/{id}.log"), &log)?;
+    }
+    for config in backup::CONFIG_FILES {
+        bundle.add_file("config/", config)?;
+    }
+    bundle.zip.finish()?;
+    Ok(())
+}
+
+/// pack everything a bug report of a boot needs into the zip <out>, the user paths are hidden
+/// if `redact`
+pub fn collect(out: &str, redact: bool) -> Result<()> {
+    let result = write_bundle(out, redact);
+    if result.is_err() {
+        let _ = std::fs::remove_file(out);
+    }
+    result?;
+    info!("diagnostics are collected to {out}");
+    println!("- Saved to {out}");
+    Ok(())
+}
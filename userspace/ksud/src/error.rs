@@ -0,0 +1,45 @@
+use std::fmt;
+
+/// the failures of mount and module which a caller may want to handle, they are returned inside
+/// anyhow::Error and can be matched with `downcast_ref::<KsudError>()`
+#[derive(Debug)]
+pub enum KsudError {
+    /// no module with this id is installed
+    ModuleNotFound(String),
+    /// e2fsck couldn't repair the image, with its exit code
+    ImageCorrupt(String, i32),
+    /// the image is neither ext4 nor erofs
+    UnknownImage(String),
+    /// the image doesn't match its saved checksum, with the expected and the actual sha256
+    ChecksumMismatch(String, String, String),
+    /// the kernel has no overlayfs
+    OverlayUnsupported,
+    /// the modules can't be changed before the boot is completed
+    Booting,
+    /// the dir is a read-only filesystem which can't be remounted read-write, e.g. erofs
+    ReadOnly(String),
+}
+
+impl fmt::Display for KsudError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KsudError::ModuleNotFound(id) => write!(f, "module: {id} not found!"),
+            KsudError::ImageCorrupt(img, code) => {
+                write!(f, "Failed to check image {img}, e2fsck exit code: {code}")
+            }
+            KsudError::UnknownImage(img) => write!(f, "unknown filesystem of image: {img}"),
+            KsudError::ChecksumMismatch(img, expected, actual) => write!(
+                f,
+                "checksum mismatch of {img}, expected: {expected}, actual: {actual}"
+            ),
+            KsudError::OverlayUnsupported => write!(f, "overlayfs is not supported by the kernel"),
+            KsudError::Booting => write!(f, "Android is Booting!"),
+            KsudError::ReadOnly(dir) => write!(
+                f,
+                "{dir} is a read-only erofs image, it is only changed by a module install or update"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for KsudError {}
@@ -1,181 +1,1305 @@
-use anyhow::{bail, Context, Result};
+use anyhow::{bail, ensure, Context, Result};
+use jwalk::{Parallelism::Serial, WalkDir};
 use log::{info, warn};
-use std::{collections::HashMap, path::Path};
+use retry::delay::Exponential;
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    path::{Path, PathBuf},
+};
 
 use crate::{
-    assets, defs, mount,
+    assets, defs, hosts, logger, mount, profile, restorecon,
     utils::{self, ensure_clean_dir, ensure_dir_exists},
 };
 
-fn mount_partition(partition: &str, lowerdir: &mut Vec<String>) -> Result<()> {
+// remount stock mounts at most 1 + STOCK_REMOUNT_RETRIES times, the delay is doubled each time
+const STOCK_REMOUNT_RETRIES: usize = 3;
+const STOCK_REMOUNT_BACKOFF_MS: u64 = 50;
+
+// `overlay` is false if the kernel has no overlayfs, the module files are bind mounted instead.
+// it is cleared by the first overlay mount which finds no overlayfs
+fn mount_partition(
+    module_dir: &str,
+    partition: &str,
+    lowerdir: &[String],
+    overlay: &mut bool,
+    dry_run: bool,
+) -> Result<()> {
     if lowerdir.is_empty() {
         warn!("partition: {partition} lowerdir is empty");
         return Ok(());
     }
 
-    // if /partition is a symlink and linked to /system/partition, then we don't need to overlay it separately
-    if Path::new(&format!("/{partition}")).read_link().is_ok() {
-        warn!("partition: {partition} is a symlink");
+    if !is_partition_present(partition) {
+        info!("partition: /{partition} is not a dir on this device, skip it");
         return Ok(());
     }
 
+    let Some(target) = resolve_partition(partition) else {
+        warn!("partition: {partition} is linked into /system");
+        return Ok(());
+    };
+
     // handle stock mounts under /partition, we should restore the mount point after overlay
     // because the overlayfs mount will "overlay" the bind mount such as /vendor/bt_firmware, /vendor/dsp
     // which will cause the system bootloop or bluetooth/dsp not working
-    let stock_mount = mount::StockMount::new(&format!("/{partition}/"))
+    let stock_mount = mount::StockMount::new(&format!("{target}/"))
         .with_context(|| format!("get stock mount of partition: {partition} failed"))?;
 
-    // add /partition as the lowerest dir
-    let lowest_dir = format!("/{partition}");
-    lowerdir.push(lowest_dir.clone());
+    // the modules of `magic` mode are bind mounted on top of the overlay of the others, so their
+    // files win over any overlaid one. `auto` ones are bind mounted too if the overlay fails,
+    // below the `magic` ones, both keep the module order
+    let modes = lowerdir
+        .iter()
+        .map(|dir| (dir, get_mount_mode(module_dir, dir)))
+        .collect::<Vec<_>>();
+    let select = |f: fn(MountMode) -> bool| {
+        modes
+            .iter()
+            .filter(|(_, mode)| f(*mode))
+            .map(|(dir, _)| dir.to_string())
+            .collect::<Vec<_>>()
+    };
+    let overlay_lowerdir = select(|mode| mode != MountMode::Magic);
+    let magic_lowerdir = select(|mode| mode == MountMode::Magic);
+    let fallback_lowerdir = [
+        magic_lowerdir.clone(),
+        select(|mode| mode == MountMode::Auto),
+    ]
+    .concat();
+
+    // the real dir of /partition is the lowerest dir
+    let lowest_dir = target;
+    info!(
+        "partition: {partition} lowerdir: {}:{lowest_dir}, magic: {}",
+        overlay_lowerdir.join(":"),
+        magic_lowerdir.join(", ")
+    );
+
+    if !*overlay || overlay_lowerdir.is_empty() {
+        let skipped = select(|mode| mode == MountMode::Overlay);
+        if !skipped.is_empty() {
+            warn!(
+                "partition: {partition} no overlayfs for the overlay only modules: {}",
+                skipped.join(", ")
+            );
+        }
+        ensure!(
+            !fallback_lowerdir.is_empty(),
+            "no overlayfs and the modules opt out of magic mount"
+        );
+        if dry_run {
+            println!(
+                "- Would magic mount {lowest_dir} with: {}",
+                fallback_lowerdir.join(", ")
+            );
+            return Ok(());
+        }
+        return mount::magic_mount(&fallback_lowerdir, &lowest_dir);
+    }
 
-    let lowerdir = lowerdir.join(":");
-    info!("partition: {partition} lowerdir: {lowerdir}");
+    if dry_run {
+        println!(
+            "- Would overlay {lowest_dir} with lowerdir: {}:{lowest_dir}",
+            overlay_lowerdir.join(":")
+        );
+        if !magic_lowerdir.is_empty() {
+            println!(
+                "- Would magic mount {lowest_dir} with: {}",
+                magic_lowerdir.join(", ")
+            );
+        }
+        return Ok(());
+    }
 
-    let result = mount::mount_overlay(&lowerdir, &lowest_dir);
+    let result = mount::mount_overlay_layers(&overlay_lowerdir, &lowest_dir);
 
-    if let Err(e) = stock_mount.remount() {
-        if result.is_ok() {
+    // the stock mounts may be busy for a moment, retry before dropping the overlay
+    let mut attempt = 0;
+    let remount = retry::retry(
+        Exponential::from_millis_with_factor(STOCK_REMOUNT_BACKOFF_MS, 2.0)
+            .take(STOCK_REMOUNT_RETRIES),
+        || {
+            attempt += 1;
+            let result = stock_mount.remount();
+            if let Err(e) = &result {
+                warn!("partition: {partition} remount stock attempt {attempt} failed: {e}");
+            }
+            result
+        },
+    );
+    if let Err(e) = remount {
+        if let Ok(stacked) = &result {
             // if mount overlay ok but stock remount failed, we should umount overlay
             warn!("remount stock failed: {:?}, umount overlay {lowest_dir}", e);
-            if mount::umount_dir(&lowest_dir).is_err() {
-                warn!("umount overlay {lowest_dir} failed");
+            for _ in 0..*stacked {
+                if mount::umount_dir(&lowest_dir).is_err() {
+                    warn!("umount overlay {lowest_dir} failed");
+                }
             }
         }
     }
 
-    result
+    // some partitions reject overlayfs, bind mount the module files one by one instead
+    if let Err(e) = &result {
+        if matches!(
+            e.downcast_ref::<crate::error::KsudError>(),
+            Some(crate::error::KsudError::OverlayUnsupported)
+        ) {
+            warn!(
+                "overlayfs is not supported by the kernel, fallback to magic mount for all partitions"
+            );
+            *overlay = false;
+        }
+        if fallback_lowerdir.is_empty() {
+            return result.map(|_| ());
+        }
+        warn!("partition: {partition} overlay failed: {e}, fallback to magic mount");
+        return mount::magic_mount(&fallback_lowerdir, &lowest_dir);
+    }
+
+    if !magic_lowerdir.is_empty() {
+        mount::magic_mount(&magic_lowerdir, &lowest_dir)?;
+    }
+    result.map(|_| ())
 }
 
-pub fn mount_systemlessly(module_dir: &str) -> Result<()> {
-    // construct overlay mount params
-    let dir = std::fs::read_dir(module_dir);
-    let Ok(dir) = dir else {
-            bail!("open {} failed", defs::MODULE_DIR);
-        };
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum MountMode {
+    Overlay,
+    Magic,
+    // overlay, magic mount if the overlay fails
+    Auto,
+}
+
+// `mountMode` of module.prop, without it `magicMount=false` means overlay only
+fn get_mount_mode(module_dir: &str, lowerdir: &str) -> MountMode {
+    // its symlinks only make sense on top of the other layers
+    if lowerdir == defs::LEGACY_LINKS_DIR {
+        return MountMode::Overlay;
+    }
+    let prop = get_module_of_lowerdir(module_dir, lowerdir)
+        .and_then(|module| crate::module::read_module_prop(module).ok())
+        .unwrap_or_default();
+    match prop.get("mountMode").map(|v| v.trim()) {
+        Some("overlay") => return MountMode::Overlay,
+        Some("magic") => return MountMode::Magic,
+        Some("auto") => return MountMode::Auto,
+        Some(mode) => warn!("{lowerdir}: unknown mountMode {mode}, use auto"),
+        None => {}
+    }
+    let magic = prop
+        .get("magicMount")
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(defs::MAGIC_MOUNT_FALLBACK);
+    if magic {
+        MountMode::Auto
+    } else {
+        MountMode::Overlay
+    }
+}
 
-    let mut system_lowerdir: Vec<String> = Vec::new();
+// what happened to each module in the last systemless mount, so that users can know
+// why a module does not take effect without logcat
+struct MountReport {
+    // the dir of the modules whose lowerdirs are reported
+    module_dir: String,
+    modules: BTreeMap<String, ModuleReport>,
+}
 
-    let partition = vec!["vendor", "product", "system_ext", "odm", "oem"];
-    let mut partition_lowerdir: HashMap<String, Vec<String>> = HashMap::new();
-    for ele in &partition {
-        partition_lowerdir.insert((*ele).to_string(), Vec::new());
+#[derive(Default)]
+struct ModuleReport {
+    applied: Vec<String>,
+    skipped: Vec<String>,
+}
+
+impl MountReport {
+    fn new(module_dir: &str) -> Self {
+        Self {
+            module_dir: module_dir.to_string(),
+            modules: BTreeMap::new(),
+        }
     }
 
-    for entry in dir.flatten() {
-        let module = entry.path();
-        if !module.is_dir() {
-            continue;
+    fn module(&mut self, id: &str) -> &mut ModuleReport {
+        self.modules.entry(id.to_string()).or_default()
+    }
+
+    // lowerdir is <module_dir>/<id>/<partition>
+    fn module_of_lowerdir(&mut self, lowerdir: &str) -> Option<&mut ModuleReport> {
+        let id = get_module_of_lowerdir(&self.module_dir, lowerdir)?
+            .file_name()?
+            .to_str()?
+            .to_string();
+        Some(self.module(&id))
+    }
+
+    fn applied(&mut self, lowerdirs: &[String], partition: &str) {
+        for lowerdir in lowerdirs {
+            if let Some(m) = self.module_of_lowerdir(lowerdir) {
+                m.applied.push(partition.to_string());
+            }
         }
-        let disabled = module.join(defs::DISABLE_FILE_NAME).exists();
-        if disabled {
-            info!("module: {} is disabled, ignore!", module.display());
-            continue;
+    }
+
+    fn skipped(&mut self, lowerdirs: &[String], partition: &str, reason: &str) {
+        for lowerdir in lowerdirs {
+            if let Some(m) = self.module_of_lowerdir(lowerdir) {
+                m.skipped.push(format!("{partition}: {reason}"));
+            }
         }
+    }
+
+    fn save(&self, path: &str) -> Result<()> {
+        let modules = self
+            .modules
+            .iter()
+            .map(|(id, m)| {
+                (
+                    id.clone(),
+                    serde_json::json!({
+                        "applied": m.applied,
+                        "skipped": m.skipped,
+                    }),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>();
+        let report = serde_json::json!({
+            "version": crate::ksu::get_version_info(),
+            "modules": modules,
+        });
+        std::fs::write(path, serde_json::to_string_pretty(&report)?)
+            .with_context(|| format!("Failed to write {path}"))
+    }
+}
+
+// the partitions besides /system which modules may overlay
+pub const KNOWN_PARTITIONS: [&str; 8] = [
+    "vendor",
+    "product",
+    "system_ext",
+    "odm",
+    "oem",
+    "vendor_dlkm",
+    "system_dlkm",
+    "odm_dlkm",
+];
 
-        let module_system = Path::new(&module).join("system");
-        if module_system.exists() {
-            system_lowerdir.push(format!("{}", module_system.display()));
+// the known partitions and the my_* partitions of some OEMs which exist on this device
+pub fn get_partitions() -> Vec<String> {
+    let mut partitions = KNOWN_PARTITIONS
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    if let Ok(dir) = std::fs::read_dir("/") {
+        let mut oem = dir
+            .flatten()
+            .filter_map(|entry| entry.file_name().to_str().map(ToString::to_string))
+            .filter(|name| name.starts_with("my_"))
+            .collect::<Vec<_>>();
+        oem.sort();
+        partitions.extend(oem);
+    }
+    partitions.retain(|p| is_partition_present(p));
+    partitions
+}
+
+// /partition exists as a dir on this device, a symlink to one counts too
+fn is_partition_present(partition: &str) -> bool {
+    Path::new("/").join(partition).is_dir()
+}
+
+/// write the partitions which the modules in module_dir have files for, it is updated whenever
+/// the module image is, so that the boot only probes them
+pub fn save_partition_manifest(module_dir: &str) -> Result<()> {
+    let partitions = get_partitions();
+    let mut touched = std::collections::BTreeSet::new();
+    for module in std::fs::read_dir(module_dir)?.flatten() {
+        for part in &partitions {
+            if module.path().join(part).is_dir() {
+                touched.insert(part.clone());
+            }
         }
+    }
+    let content = touched.iter().map(|p| format!("{p}\n")).collect::<String>();
+    let manifest = Path::new(module_dir).join(defs::PARTITION_MANIFEST_NAME);
+    std::fs::write(&manifest, content)
+        .with_context(|| format!("Failed to write {}", manifest.display()))
+}
+
+// the partitions in the manifest which exist on the device, all of them if there is no manifest
+fn get_module_partitions(module_dir: &str) -> Vec<String> {
+    let manifest = Path::new(module_dir).join(defs::PARTITION_MANIFEST_NAME);
+    let Ok(content) = std::fs::read_to_string(manifest) else {
+        return get_partitions();
+    };
+    content
+        .lines()
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .filter(|p| {
+            let present = is_partition_present(p);
+            if !present {
+                info!("partition: /{p} of the modules is not on this device, skip it");
+            }
+            present
+        })
+        .map(ToString::to_string)
+        .collect()
+}
+
+// the dir which really holds the files of /partition, the symlink chain is resolved fully.
+// None if it lands in /system (e.g. /vendor -> /system/vendor), then the /system overlay covers
+// it and we don't need to overlay it separately
+fn resolve_partition(partition: &str) -> Option<String> {
+    let path = format!("/{partition}");
+    let target = match std::fs::canonicalize(&path) {
+        Ok(target) => target,
+        Err(e) => {
+            warn!("partition: {path} can't be resolved: {e}");
+            return None;
+        }
+    };
+    if target != Path::new(&path) {
+        info!("partition: {path} resolved to {}", target.display());
+    }
+    let system = std::fs::canonicalize("/system").unwrap_or_else(|_| "/system".into());
+    if target.starts_with(&system) {
+        return None;
+    }
+    Some(target.display().to_string())
+}
+
+// the module lowerdirs of /system and other partitions, the top layer comes first
+#[derive(Default)]
+struct ModuleLayout {
+    system: Vec<String>,
+    partitions: HashMap<String, Vec<String>>,
+    // $MODULE/system of the modules which only ship system/etc/hosts
+    hosts: Vec<String>,
+    // the partitions which a module keeps in system/<partition>, see get_legacy_partition_dir
+    legacy: BTreeSet<String>,
+}
+
+impl ModuleLayout {
+    fn new(module_dir: &str, partitions: &[String]) -> Result<Self> {
+        let (system, partitions) = collect_lowerdirs(module_dir, partitions)?;
+        let legacy = get_legacy_partitions(module_dir, &partitions);
+        Ok(Self {
+            system,
+            partitions,
+            hosts: collect_hosts(module_dir)?,
+            legacy,
+        })
+    }
 
-        for part in &partition {
+    // only the lowerdirs of module `id`, the others still count for the dependencies
+    fn retain_module(&mut self, module_dir: &str, id: &str) {
+        let is_of_module = |lowerdir: &String| {
+            get_module_of_lowerdir(module_dir, lowerdir).and_then(Path::file_name)
+                == Some(id.as_ref())
+        };
+        self.system.retain(is_of_module);
+        self.hosts.retain(is_of_module);
+        for lowerdirs in self.partitions.values_mut() {
+            lowerdirs.retain(is_of_module);
+        }
+        self.legacy = get_legacy_partitions(module_dir, &self.partitions);
+    }
+}
+
+// the modules of module_dir which are mounted, the top layer first, and the others with the
+// reason they are skipped
+fn get_mounted_modules(module_dir: &str) -> Result<(Vec<PathBuf>, Vec<(PathBuf, String)>)> {
+    let dir = std::fs::read_dir(module_dir);
+    let Ok(dir) = dir else {
+        bail!("open {} failed", module_dir);
+    };
+
+    // higher priority modules come first in lowerdir so they win on conflict,
+    // the module dir name is the tie breaker to make it stable across boots
+    let mut modules = dir
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .map(|path| (crate::module::get_module_priority(&path), path))
+        .collect::<Vec<_>>();
+    modules.sort_by(|(pa, a), (pb, b)| pb.cmp(pa).then_with(|| a.cmp(b)));
+
+    // a dependency is applied first, so it is a lower layer than the modules which need it
+    let bottom_first = modules
+        .into_iter()
+        .rev()
+        .map(|(_, module)| module)
+        .collect();
+    let (bottom_first, mut skipped) = crate::module::order_by_dependencies(bottom_first);
+
+    let blocklist = crate::module::get_blocklist();
+    let mut mounted = Vec::new();
+    for module in bottom_first.into_iter().rev() {
+        // the blocklist wins over the module's own disable flag
+        if crate::module::is_blocked(&blocklist, &module) {
+            skipped.push((module, "blocked".to_string()));
+        } else if let Some(reason) = crate::module::get_incompatible_reason(&module) {
+            skipped.push((module, format!("incompatible: {reason}")));
+        } else if crate::module::is_disabled(&module) {
+            skipped.push((module, "disabled".to_string()));
+        } else {
+            mounted.push(module);
+        }
+    }
+    Ok((mounted, skipped))
+}
+
+// the lowerdirs of /system and of each of partitions in module_dir, the top layer comes first.
+// only the module dir is read, nothing is mounted and the partitions of the device are not
+// checked, so that it works on any dir of fake modules
+fn collect_lowerdirs(
+    module_dir: &str,
+    partitions: &[String],
+) -> Result<(Vec<String>, HashMap<String, Vec<String>>)> {
+    let mut system = Vec::new();
+    let mut lowerdirs = partitions
+        .iter()
+        .map(|part| (part.clone(), Vec::new()))
+        .collect::<HashMap<_, _>>();
+    for module in get_mounted_modules(module_dir)?.0 {
+        let module_system = module.join("system");
+        // the hosts only modules are merged instead, no need to overlay the whole /system/etc
+        if module_system.exists() && !hosts::is_hosts_only(&module_system) {
+            system.push(format!("{}", module_system.display()));
+        }
+
+        for part in partitions {
             // if /partition is a mountpoint, we would move it to $MODPATH/$partition when install
             // otherwise it must be a symlink and we don't need to overlay!
-            let part_path = Path::new(&module).join(part);
+            let mut part_path = module.join(part);
             if !part_path.exists() {
-                continue;
+                let Some(legacy) = get_legacy_partition_dir(&module, part) else {
+                    continue;
+                };
+                part_path = legacy;
             }
-            if let Some(v) = partition_lowerdir.get_mut(*part) {
+            if let Some(v) = lowerdirs.get_mut(part) {
                 v.push(format!("{}", part_path.display()));
             }
         }
     }
+    Ok((system, lowerdirs))
+}
+
+// $MODULE/system of the mounted modules which only ship system/etc/hosts
+fn collect_hosts(module_dir: &str) -> Result<Vec<String>> {
+    Ok(get_mounted_modules(module_dir)?
+        .0
+        .into_iter()
+        .map(|module| module.join("system"))
+        .filter(|system| hosts::is_hosts_only(system))
+        .map(|system| format!("{}", system.display()))
+        .collect())
+}
+
+// the partitions which have a lowerdir $MODULE/system/<partition>
+fn get_legacy_partitions(
+    module_dir: &str,
+    lowerdirs: &HashMap<String, Vec<String>>,
+) -> BTreeSet<String> {
+    lowerdirs
+        .iter()
+        .filter(|(_, dirs)| {
+            dirs.iter().any(|dir| {
+                Path::new(dir)
+                    .strip_prefix(module_dir)
+                    .map_or(false, |relative| relative.components().count() == 3)
+            })
+        })
+        .map(|(part, _)| part.clone())
+        .collect()
+}
+
+// log what get_mounted_modules collected into the report, the mounts add to it later
+fn report_modules(module_dir: &str, report: &mut MountReport) -> Result<()> {
+    let (mounted, skipped) = get_mounted_modules(module_dir)?;
+    for (module, reason) in skipped {
+        info!("module: {} is skipped: {reason}", module.display());
+        let id = module.file_name().unwrap_or_default().to_string_lossy();
+        report.module(&id).skipped.push(reason);
+    }
+    for module in mounted {
+        info!(
+            "module: {} priority: {}",
+            module.display(),
+            crate::module::get_module_priority(&module)
+        );
+        report.module(&module.file_name().unwrap_or_default().to_string_lossy());
+    }
+    Ok(())
+}
+
+// $MODULE of the lowerdir $MODULE/<partition>, or $MODULE/system/<partition> of the legacy layout,
+// the module is the first component below module_dir. None if lowerdir is not in module_dir
+fn get_module_of_lowerdir<'a>(module_dir: &str, lowerdir: &'a str) -> Option<&'a Path> {
+    let lowerdir = Path::new(lowerdir);
+    let depth = lowerdir.strip_prefix(module_dir).ok()?.components().count();
+    lowerdir.ancestors().nth(depth.checked_sub(1)?)
+}
+
+// a module installed by another tool or edited by hand may keep /partition in system/partition,
+// as in the old Magisk layout. it is a partition of its own if /system/partition links to
+// /partition on this device, which is what installer.sh checks on install
+fn get_legacy_partition_dir(module: &Path, partition: &str) -> Option<PathBuf> {
+    let legacy = module.join("system").join(partition);
+    if module.join(partition).exists() || legacy.is_symlink() || !legacy.is_dir() {
+        return None;
+    }
+    let link = format!("/system/{partition}");
+    if !Path::new(&link).is_symlink() {
+        return None;
+    }
+    let target = std::fs::canonicalize(&link).ok()?;
+    let real = std::fs::canonicalize(format!("/{partition}")).ok()?;
+    (target == real).then_some(legacy)
+}
+
+// the system/partition dirs of the modules would hide the /system/partition symlink in the
+// /system overlay, a top layer of the same symlinks keeps them. the module image is not touched,
+// the dirs are mounted on /partition by collect_lowerdirs
+#[cfg(unix)]
+fn ensure_legacy_links(partitions: &BTreeSet<String>) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    ensure_dir_exists(defs::LEGACY_LINKS_DIR)?;
+    // the root of the top layer is the root of the /system overlay
+    std::fs::set_permissions(
+        defs::LEGACY_LINKS_DIR,
+        std::fs::Permissions::from_mode(0o755),
+    )?;
+    restorecon::lsetsyscon(defs::LEGACY_LINKS_DIR)?;
+    for partition in partitions {
+        let link = Path::new(defs::LEGACY_LINKS_DIR).join(partition);
+        let target = PathBuf::from(format!("/{partition}"));
+        if std::fs::read_link(&link).ok().as_ref() == Some(&target) {
+            continue;
+        }
+        if link.symlink_metadata().is_ok() {
+            std::fs::remove_file(&link)?;
+        }
+        std::os::unix::fs::symlink(&target, &link)?;
+        restorecon::lsetsyscon(&link)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn ensure_legacy_links(_partitions: &BTreeSet<String>) -> Result<()> {
+    unimplemented!()
+}
+
+/// $MODULE/system of the live modules whose hosts is merged, without the removed ones
+pub fn get_hosts_module_systems() -> Result<Vec<String>> {
+    let module_dir = defs::MODULE_DIR;
+    Ok(collect_hosts(module_dir)?
+        .into_iter()
+        .filter(|system| {
+            get_module_of_lowerdir(module_dir, system)
+                .map_or(true, |module| !module.join(defs::REMOVE_FILE_NAME).exists())
+        })
+        .collect())
+}
+
+pub fn mount_systemlessly(module_dir: &str, dry_run: bool) -> Result<()> {
+    mount_modules(module_dir, None, dry_run)
+}
+
+fn mount_modules(module_dir: &str, only: Option<&str>, dry_run: bool) -> Result<()> {
+    // construct overlay mount params
+    let mut report = MountReport::new(module_dir);
+    let partitions = get_module_partitions(module_dir);
+    info!("partitions of modules: {partitions:?}");
+    report_modules(module_dir, &mut report)?;
+    let mut layout = ModuleLayout::new(module_dir, &partitions)?;
+    if let Some(id) = only {
+        layout.retain_module(module_dir, id);
+    }
+    let allowed = get_allowed_partitions();
+
+    if !layout.legacy.is_empty() {
+        if dry_run {
+            println!("- Would keep the /system symlinks of: {:?}", layout.legacy);
+        } else {
+            match ensure_legacy_links(&layout.legacy) {
+                Ok(()) => layout.system.insert(0, defs::LEGACY_LINKS_DIR.to_string()),
+                Err(e) => warn!("create the links of {:?} failed: {e}", layout.legacy),
+            }
+        }
+    }
+
+    // the stock overlays which the module overlays would cover are umounted and remounted on top
+    let stock_overlay = if dry_run {
+        None
+    } else {
+        let stock_overlay =
+            mount::StockOverlay::new_for(&get_module_mount_points(&layout, &allowed));
+        stock_overlay.umount_all();
+        Some(stock_overlay)
+    };
 
     // mount /system first
-    if let Err(e) = mount_partition("system", &mut system_lowerdir) {
-        warn!("mount system failed: {e}");
+    // a loadable overlayfs is only listed after its first mount, the real mount decides
+    if !mount::is_overlayfs_listed() {
+        info!("overlay is not in /proc/filesystems, try to mount it anyway");
     }
+    let mut overlay = true;
+
+    mount_allowed_partition(
+        "system",
+        layout.system,
+        &allowed,
+        &mut report,
+        &mut overlay,
+        dry_run,
+    );
 
     // mount other partitions
-    for (k, mut v) in partition_lowerdir {
-        if let Err(e) = mount_partition(&k, &mut v) {
-            warn!("mount {k} failed: {e}");
+    for (k, v) in layout.partitions {
+        mount_allowed_partition(&k, v, &allowed, &mut report, &mut overlay, dry_run);
+    }
+
+    // hosts is merged after /system is mounted, so that is on top of the modded one
+    if dry_run {
+        println!(
+            "- Would merge hosts from: [{}] and {}",
+            layout.hosts.join(", "),
+            defs::HOSTS_FRAGMENT_PATH
+        );
+    } else {
+        match hosts::mount_merged_hosts(&layout.hosts) {
+            Ok(()) => report.applied(&layout.hosts, "hosts"),
+            Err(e) => {
+                warn!("mount merged hosts failed: {e}");
+                report.skipped(&layout.hosts, "hosts", &format!("mount failed: {e}"));
+            }
+        }
+    }
+
+    if let Some(stock_overlay) = stock_overlay {
+        stock_overlay.mount_all();
+    }
+
+    // the report of the boot is kept, a single module mount prints its own
+    if let Some(id) = only {
+        let module = report.module(id);
+        if module.applied.is_empty() && module.skipped.is_empty() {
+            println!("- {id} has nothing to mount");
+        }
+        for partition in &module.applied {
+            println!("- Mounted: /{partition}");
+        }
+        for reason in &module.skipped {
+            println!("- Skipped: {reason}");
+        }
+    } else if !dry_run {
+        if let Err(e) = report.save(defs::MOUNT_REPORT_PATH) {
+            warn!("save mount report failed: {e}");
         }
     }
 
     Ok(())
 }
 
-pub fn on_post_data_fs() -> Result<()> {
-    crate::ksu::report_post_fs_data();
+/// umount every module mount and mount only module <id>, so that it can be tested alone.
+/// a reboot mounts all modules again
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn mount_only(id: &str) -> Result<()> {
+    ensure!(
+        Path::new(defs::MODULE_DIR)
+            .join(id)
+            .join("module.prop")
+            .exists(),
+        crate::error::KsudError::ModuleNotFound(id.to_string())
+    );
+    detach_modules();
+    mount_modules(defs::MODULE_DIR, Some(id), false)?;
+    println!("- Only {id} is mounted, reboot to mount all modules again");
+    Ok(())
+}
 
-    if utils::has_magisk() {
-        warn!("Magisk detected, skip post-fs-data!");
-        return Ok(());
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn mount_only(_id: &str) -> Result<()> {
+    unimplemented!()
+}
+
+// the mount points which the module overlays of `layout` go on
+fn get_module_mount_points(layout: &ModuleLayout, allowed: &[String]) -> Vec<String> {
+    let mut mount_points = Vec::new();
+    let is_allowed = |partition: &str| allowed.iter().any(|p| p == partition);
+    // the merged hosts is mounted even if system is not allowed
+    if (!layout.system.is_empty() && is_allowed("system")) || !layout.hosts.is_empty() {
+        mount_points.push("/system".to_string());
+    }
+    for (partition, lowerdir) in &layout.partitions {
+        if lowerdir.is_empty() || !is_allowed(partition) {
+            continue;
+        }
+        // it is not mounted if it is linked into /system
+        if let Some(target) = resolve_partition(partition) {
+            mount_points.push(format!("/{partition}"));
+            mount_points.push(target);
+        }
+    }
+    mount_points.sort();
+    mount_points.dedup();
+    mount_points
+}
+
+// overlay_partitions of the config, all of them if it isn't set
+fn get_allowed_partitions() -> Vec<String> {
+    crate::config::get()
+        .overlay_partitions
+        .clone()
+        .unwrap_or_else(|| {
+            let mut partitions = get_partitions();
+            partitions.push("system".to_string());
+            partitions
+        })
+}
+
+fn mount_allowed_partition(
+    partition: &str,
+    lowerdir: Vec<String>,
+    allowed: &[String],
+    report: &mut MountReport,
+    overlay: &mut bool,
+    dry_run: bool,
+) {
+    if !lowerdir.is_empty() && !allowed.iter().any(|p| p == partition) {
+        warn!(
+            "partition: {partition} is not in overlay_partitions of {}, skip it",
+            defs::CONFIG_PATH
+        );
+        report.skipped(&lowerdir, partition, "disabled by config");
+        return;
     }
+    mount_partition_with_report(partition, lowerdir, report, overlay, dry_run);
+}
 
-    utils::umask(0);
+fn mount_partition_with_report(
+    partition: &str,
+    lowerdir: Vec<String>,
+    report: &mut MountReport,
+    overlay: &mut bool,
+    dry_run: bool,
+) {
+    // no module has files for it
+    if lowerdir.is_empty() {
+        return;
+    }
 
-    let module_update_img = defs::MODULE_UPDATE_IMG;
-    let module_img = defs::MODULE_IMG;
-    let module_dir = defs::MODULE_DIR;
-    let module_update_flag = Path::new(defs::WORKING_DIR).join(defs::UPDATE_FILE_NAME);
+    if !is_partition_present(partition) {
+        info!("partition: /{partition} is not a dir on this device, skip it");
+        report.skipped(&lowerdir, partition, "partition is not on this device");
+        return;
+    }
+
+    if resolve_partition(partition).is_none() {
+        warn!("partition: {partition} is linked into /system");
+        report.skipped(&lowerdir, partition, "partition is linked into /system");
+        return;
+    }
+
+    let module_dir = report.module_dir.clone();
+    let result = profile::time(&format!("mount /{partition}"), || {
+        mount_partition(&module_dir, partition, &lowerdir, overlay, dry_run)
+    });
+    match result {
+        Ok(()) => report.applied(&lowerdir, partition),
+        Err(e) => {
+            warn!("mount {partition} failed: {e}");
+            report.skipped(&lowerdir, partition, &format!("mount failed: {e}"));
+        }
+    }
+}
+
+/// print the overlay layout which `mount_systemlessly` would do for `module_dir` as json
+pub fn dump_mount_layout(module_dir: Option<&str>) -> Result<()> {
+    let module_dir = module_dir.unwrap_or(defs::MODULE_DIR);
+    let layout = ModuleLayout::new(module_dir, &get_partitions())?;
+
+    // the stock partition is always the lowest layer and is not listed here
+    let mut partitions: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let mut symlinks: Vec<String> = Vec::new();
+    for (k, v) in layout.partitions {
+        if v.is_empty() {
+            continue;
+        }
+        if resolve_partition(&k).is_none() {
+            symlinks.push(k);
+            continue;
+        }
+        partitions.insert(k, v);
+    }
+    if !layout.system.is_empty() {
+        partitions.insert("system".to_string(), layout.system);
+    }
+    symlinks.sort();
+
+    let layout = serde_json::json!({
+        "module_dir": module_dir,
+        "partitions": partitions,
+        "hosts": layout.hosts,
+        "skipped_symlinks": symlinks,
+    });
+    println!("{}", serde_json::to_string_pretty(&layout)?);
+    Ok(())
+}
+
+/// print the paths which more than one enabled module provides, the module which wins the
+/// overlay comes first
+pub fn print_conflicts(module_dir: &str) -> Result<()> {
+    let layout = ModuleLayout::new(module_dir, &get_module_partitions(module_dir))?;
+
+    // the top layer comes first in lowerdirs, so do the providers of each path
+    let mut providers: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let partitions =
+        std::iter::once(("system".to_string(), layout.system)).chain(layout.partitions.into_iter());
+    for (partition, lowerdirs) in partitions {
+        for lowerdir in lowerdirs {
+            let id = get_module_of_lowerdir(module_dir, &lowerdir)
+                .and_then(Path::file_name)
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string();
+            for entry in WalkDir::new(&lowerdir)
+                .parallelism(Serial)
+                .into_iter()
+                .flatten()
+            {
+                // dirs are merged by overlayfs
+                if entry.file_type().is_dir() {
+                    continue;
+                }
+                if let Ok(relative) = entry.path().strip_prefix(&lowerdir) {
+                    providers
+                        .entry(format!("/{partition}/{}", relative.display()))
+                        .or_default()
+                        .push(id.clone());
+                }
+            }
+        }
+    }
+
+    let mut conflicts = 0;
+    for (path, ids) in providers.iter().filter(|(_, ids)| ids.len() > 1) {
+        conflicts += 1;
+        println!("{path}: {} (wins), {}", ids[0], ids[1..].join(", "));
+    }
+    if conflicts == 0 {
+        println!("- No conflict");
+    }
+    Ok(())
+}
+
+// files a module provides, relative to the module dir, e.g. system/bin/foo
+fn collect_module_files(module: &Path) -> Vec<(String, PathBuf)> {
+    let mut files = Vec::new();
+    for part in std::iter::once("system".to_string()).chain(get_partitions()) {
+        let part_path = module.join(part);
+        if !part_path.is_dir() {
+            continue;
+        }
+        for entry in WalkDir::new(&part_path)
+            .parallelism(Serial)
+            .into_iter()
+            .flatten()
+        {
+            if entry.file_type().is_dir() {
+                continue;
+            }
+            let path = entry.path();
+            if let Ok(relative) = path.strip_prefix(module) {
+                files.push((relative.to_string_lossy().to_string(), path.clone()));
+            }
+        }
+    }
+    files
+}
+
+/// mount module <id> on a throwaway dir and report the problems, the live system is untouched
+pub fn mount_test(id: &str) -> Result<()> {
+    let module = Path::new(defs::MODULE_DIR).join(id);
+    ensure!(module.is_dir(), "module: {id} not found");
+
+    let files = collect_module_files(&module);
+
+    // the same path provided by the other modules which the boot mounts
+    let mut providers: HashMap<String, Vec<String>> = HashMap::new();
+    for other in get_mounted_modules(defs::MODULE_DIR)?.0 {
+        let other_id = other.file_name().unwrap_or_default().to_string_lossy();
+        if other_id == id {
+            continue;
+        }
+        for (relative, _) in collect_module_files(&other) {
+            providers
+                .entry(relative)
+                .or_default()
+                .push(other_id.to_string());
+        }
+    }
+
+    let mut problems = 0;
+    for (relative, path) in &files {
+        if let Some(others) = providers.get(relative) {
+            problems += 1;
+            println!(
+                "- Conflict: /{relative} is also provided by {}",
+                others.join(", ")
+            );
+        }
+        match restorecon::getcon(path) {
+            Ok(con) if con == restorecon::ADB_CON => {
+                problems += 1;
+                println!("- SELinux: /{relative} has context {con}, it is not restored");
+            }
+            Ok(_) => {}
+            Err(e) => {
+                problems += 1;
+                println!("- SELinux: /{relative}: {e}");
+            }
+        }
+    }
+
+    // mount the module on a throwaway root, the cli runs in a private mount namespace
+    let test_root = Path::new(defs::MOUNT_TEST_DIR);
+    ensure_clean_dir(defs::MOUNT_TEST_DIR)?;
+    for part in std::iter::once("system".to_string()).chain(get_partitions()) {
+        let part_path = module.join(&part);
+        if !part_path.is_dir() {
+            continue;
+        }
+        let lowest = if part == "system" {
+            Some("/system".to_string())
+        } else {
+            resolve_partition(&part)
+        };
+        let Some(lowest) = lowest else {
+            continue;
+        };
+        let target = test_root.join(&part);
+        std::fs::create_dir_all(&target)?;
+        let layers = vec![part_path.display().to_string()];
+        match mount::mount_overlay_layers_at(&layers, &lowest, &target.display().to_string()) {
+            Ok(_) => println!("- Mount: /{part} ok"),
+            Err(e) => {
+                problems += 1;
+                println!("- Mount: /{part} failed: {e}");
+            }
+        }
+    }
+    mount::umount_dir_recursive(defs::MOUNT_TEST_DIR)?;
+    std::fs::remove_dir_all(test_root)?;
 
-    // modules.img is the default image
-    let mut target_update_img = &module_img;
+    println!("- {} files checked, {problems} problems found", files.len());
+    Ok(())
+}
 
-    // we should clean the module mount point if it exists
-    ensure_clean_dir(module_dir)?;
+pub fn on_post_data_fs(dry_run: bool) -> Result<()> {
+    logger::set_phase("post-fs-data");
+    if !dry_run {
+        profile::start_watchdog();
+    }
+    let result = profile::time("post-fs-data", || post_fs_data(dry_run));
+    if !dry_run {
+        profile::stop_watchdog();
+        if let Err(e) = profile::save() {
+            warn!("save boot timing failed: {e}");
+        }
+    }
+    result
+}
 
-    assets::ensure_binaries().with_context(|| "Failed to extract bin assets")?;
+// a killed post-fs-data leaves its mounts behind, take them down so that the next run starts
+// from scratch and ends with the same mounts. true if that run mounted modules_update.img
+fn umount_interrupted_run(module_dir: &str, module_update_img: &str) -> bool {
+    let update_mounted = mount::is_image_mounted(module_update_img);
+    if !mount::is_mounted(module_dir)
+        && !update_mounted
+        && get_module_overlay_mounts(module_dir).is_empty()
+    {
+        return false;
+    }
+    warn!("the mounts of an interrupted post-fs-data are found, umount them");
+    detach_modules_of(module_dir);
+    if let Err(e) = mount::umount_dir_recursive(module_dir) {
+        warn!("umount {module_dir} failed: {e}");
+    }
+    update_mounted
+}
+
+// the image to mount in this boot, modules.img unless the update flag is set for an update image
+// which this ksud can mount. the flag is consumed and the images which won't be used are dropped
+fn choose_module_image<'a>(
+    module_img: &'a str,
+    module_update_img: &'a str,
+    module_update_flag: &Path,
+    resumed_update: bool,
+    dry_run: bool,
+) -> Result<&'a str> {
+    let mut target_update_img = module_img;
+
+    // an older ksud may not mount the image of a newer one correctly, e.g. after a downgrade
+    let update_format = crate::module::get_image_format(module_update_img);
+    let newer_update = update_format.map_or(true, |format| format > defs::IMG_FORMAT_VERSION);
 
     if Path::new(module_update_img).exists() {
-        if module_update_flag.exists() {
+        if (module_update_flag.exists() || resumed_update) && newer_update {
+            warn!(
+                "module image: {module_update_img} is of format {}, newer than {}, fallback to {module_img}",
+                update_format.map_or_else(|| "unknown".to_string(), |f| f.to_string()),
+                defs::IMG_FORMAT_VERSION
+            );
+            if dry_run {
+                println!("- {module_update_img} of a newer format would be moved aside");
+            } else {
+                // kept for a newer ksud, but not promoted on boot-completed
+                move_image(
+                    module_update_img,
+                    &format!("{module_update_img}{}", defs::NEWER_IMG_SUFFIX),
+                );
+                let _ = std::fs::remove_file(module_update_flag);
+                let _ = std::fs::remove_file(defs::MODULE_STAGE_FLAG_PATH);
+                let _ = std::fs::remove_file(defs::MODULE_STAGED_UNINSTALL_PATH);
+            }
+        } else if module_update_flag.exists() || resumed_update {
             // if modules_update.img exists, and the the flag indicate this is an update
             // this make sure that if the update failed, we will fallback to the old image
             // if we boot succeed, we will rename the modules_update.img to modules.img #on_boot_complete
-            target_update_img = &module_update_img;
+            target_update_img = module_update_img;
             // And we should delete the flag immediately
-            std::fs::remove_file(module_update_flag)?;
+            if !dry_run && module_update_flag.exists() {
+                std::fs::remove_file(module_update_flag)?;
+            }
+        } else if dry_run {
+            println!("- {module_update_img} without update flag would be removed");
         } else {
             // if modules_update.img exists, but the flag not exist, we should delete it
             std::fs::remove_file(module_update_img)?;
+            let _ = std::fs::remove_file(crate::module::get_checksum_path(module_update_img));
+            let _ = std::fs::remove_file(crate::module::get_format_path(module_update_img));
+            // it may be the staged changes, a reboot discards them
+            let _ = std::fs::remove_file(defs::MODULE_STAGE_FLAG_PATH);
+            let _ = std::fs::remove_file(defs::MODULE_STAGED_UNINSTALL_PATH);
+        }
+    } else if module_update_flag.exists() {
+        // the update image is gone, e.g. removed by hand, so boot the current image instead and
+        // drop the flag, or it would pick up a half built update image in a later boot
+        warn!("update flag without {module_update_img}, fallback to {module_img}");
+        if dry_run {
+            println!("- Orphaned update flag would be removed");
+        } else {
+            std::fs::remove_file(module_update_flag)?;
+        }
+    }
+
+    Ok(target_update_img)
+}
+
+fn post_fs_data(dry_run: bool) -> Result<()> {
+    if dry_run {
+        println!("- Dry run, nothing will be changed");
+    } else {
+        crate::ksu::report_post_fs_data();
+        crate::config::migrate();
+    }
+
+    let kernel_version = crate::ksu::get_version();
+    if !crate::ksu::is_kernel_compatible(kernel_version) {
+        warn!(
+            "kernel KernelSU version {kernel_version} is older than the minimal supported {}",
+            defs::MIN_KSU_KERNEL_VERSION
+        );
+    }
+
+    if magisk_takes_over("post-fs-data")? {
+        return Ok(());
+    }
+
+    utils::umask(utils::get_script_umask());
+
+    let module_update_img = defs::MODULE_UPDATE_IMG;
+    let module_img = utils::get_module_img();
+    let module_img = module_img.as_str();
+    info!("module image: {module_img}");
+    let module_dir = defs::MODULE_DIR;
+    let module_update_flag = Path::new(defs::WORKING_DIR).join(defs::UPDATE_FILE_NAME);
+
+    // the update flag is already consumed by an interrupted run of this boot
+    let mut resumed_update = false;
+
+    if !dry_run {
+        // the reports are only valid for the current boot
+        for report in [
+            defs::MOUNT_REPORT_PATH,
+            defs::SEPOLICY_REPORT_PATH,
+            defs::PENDING_PARTITIONS_PATH,
+        ] {
+            if Path::new(report).exists() {
+                let _ = std::fs::remove_file(report);
+            }
         }
+
+        resumed_update = umount_interrupted_run(module_dir, module_update_img);
+
+        // we should clean the module mount point if it exists
+        ensure_clean_dir(module_dir)?;
+
+        profile::time("extract assets", ensure_binaries_if_space)
+            .with_context(|| "Failed to extract bin assets")?;
     }
 
+    let target_update_img = choose_module_image(
+        module_img,
+        module_update_img,
+        &module_update_flag,
+        resumed_update,
+        dry_run,
+    )?;
+
     // If there isn't any image exist, do nothing for module!
     if !Path::new(target_update_img).exists() {
+        if dry_run {
+            println!("- No module image found");
+        }
         return Ok(());
     }
 
+    if dry_run {
+        println!("- Module image: {target_update_img} would be mounted to {module_dir}");
+        if crate::utils::is_safe_mode() {
+            println!("- Safe mode, all modules would be disabled");
+            return Ok(());
+        }
+        // the module dir holds the image of this boot if any, the chosen one is looked at
+        // read-only, so that the dry run changes nothing
+        let dir = defs::DRY_RUN_DIR;
+        ensure_clean_dir(dir)?;
+        mount::mount_image_readonly(target_update_img, dir)?;
+        let result = dry_run_post_fs_data(dir);
+        mount::umount_dir(dir)?;
+        let _ = std::fs::remove_dir(dir);
+        return result;
+    }
+
+    let image_type = match mount::get_image_type(target_update_img) {
+        Ok(image_type) => image_type,
+        Err(e) => {
+            warn!("module image: {target_update_img} is corrupt: {e}");
+            // a read error may be gone on the next boot
+            if matches!(
+                e.downcast_ref::<crate::error::KsudError>(),
+                Some(crate::error::KsudError::UnknownImage(_))
+            ) {
+                move_bad_image(target_update_img);
+            }
+            return Ok(());
+        }
+    };
+    info!("module image: {target_update_img} type: {image_type:?}");
+
+    // a finalized image is checked before it is mounted, a read error may be gone on the next boot
+    if let Err(e) = profile::time("verify image", || {
+        crate::module::verify_image_checksum(target_update_img)
+    }) {
+        warn!("module image: {target_update_img} is corrupt: {e}");
+        if matches!(
+            e.downcast_ref::<crate::error::KsudError>(),
+            Some(crate::error::KsudError::ChecksumMismatch(..))
+        ) {
+            move_bad_image(target_update_img);
+        }
+        return Ok(());
+    }
+
+    if image_type == mount::ImageType::Ext4 {
+        // a corrupt image may wedge every boot, move it aside and boot without modules
+        if let Err(e) = crate::module::preen_image(target_update_img) {
+            warn!("module image: {target_update_img} is corrupt: {e}");
+            move_bad_image(target_update_img);
+            return Ok(());
+        }
+
+        if Path::new(defs::SHRINK_IMG_FLAG_PATH).exists() {
+            if let Err(e) = crate::module::shrink_image(target_update_img) {
+                warn!("shrink module image failed: {e}");
+            }
+        }
+    }
+
     // we should always mount the module.img to module dir
     // becuase we may need to operate the module dir in safe mode
     info!("mount module image: {target_update_img} to {module_dir}");
-    mount::AutoMountExt4::try_new(target_update_img, module_dir, false)
-        .with_context(|| "mount module image failed".to_string())?;
+    let result = profile::time("mount image", || match image_type {
+        mount::ImageType::Ext4 => {
+            mount::AutoMountExt4::try_new(target_update_img, module_dir, false).map(|_| ())
+        }
+        mount::ImageType::Erofs => {
+            mount::AutoMountErofs::try_new(target_update_img, module_dir, false).map(|_| ())
+        }
+    });
+    if let Err(e) = result {
+        // it may work on the next boot, the image is kept
+        warn!("mount module image: {target_update_img} failed: {e}, no modules will be loaded!");
+        return Ok(());
+    }
+    if image_type == mount::ImageType::Ext4 {
+        crate::module::drop_image_checksum(target_update_img);
+        if !crate::module::ensure_image_space(target_update_img, module_dir) {
+            return Ok(());
+        }
+    }
+
+    // modules which bootloop the device are disabled after a few tries, so the user doesn't
+    // have to catch the safe mode window
+    match utils::record_boot_attempt() {
+        Ok(failures) if failures >= defs::MAX_BOOT_FAILURES => {
+            warn!("boot failed {failures} times in a row, disable all modules!");
+            if let Err(e) = crate::module::disable_all_modules() {
+                warn!("disable all modules failed: {}", e);
+            }
+            let _ = utils::reset_boot_count();
+            return Ok(());
+        }
+        Ok(_) => {}
+        Err(e) => warn!("record boot attempt failed: {e}"),
+    }
 
     // check safe mode first.
     if crate::utils::is_safe_mode() {
         warn!("safe mode, skip post-fs-data scripts and disable all modules!");
+        // the only scripts which run in safe mode, to clean up what broke the boot
+        if let Err(e) = profile::time("safemode scripts", crate::module::exec_safe_mode_scripts) {
+            warn!("exec safemode scripts failed: {}", e);
+        }
         if let Err(e) = crate::module::disable_all_modules() {
             warn!("disable all modules failed: {}", e);
         }
         return Ok(());
     }
 
+    crate::module::rotate_module_logs();
+
     // Then exec common post-fs-data scripts
-    if let Err(e) = crate::module::exec_common_scripts("post-fs-data.d", true) {
+    if let Err(e) = profile::time("post-fs-data.d scripts", || {
+        crate::module::exec_common_scripts("post-fs-data.d", true)
+    }) {
         warn!("exec common post-fs-data scripts failed: {}", e);
     }
 
     // load sepolicy.rule
-    if crate::module::load_sepolicy_rule().is_err() {
+    if profile::time("load sepolicy", crate::module::load_sepolicy_rule).is_err() {
         warn!("load sepolicy.rule failed");
     }
 
     // exec modules post-fs-data scripts
-    // TODO: Add timeout
-    if let Err(e) = crate::module::exec_post_fs_data() {
+    if let Err(e) = profile::time("post-fs-data.sh scripts", crate::module::exec_post_fs_data) {
         warn!("exec post-fs-data scripts failed: {}", e);
     }
 
@@ -184,26 +1308,305 @@ pub fn on_post_data_fs() -> Result<()> {
         warn!("load system.prop failed: {}", e);
     }
 
+    // not a module script, it is the same for every module set
+    if let Err(e) = profile::time("pre-mount script", crate::module::exec_pre_mount_script) {
+        warn!("pre-mount: {} failed: {e}", defs::PRE_MOUNT_SCRIPT_PATH);
+    }
+
     // Finally, we should do systemless mount
-    // But we should umount all stock overlayfs and remount them after module mounted
-    let stock_overlay = mount::StockOverlay::new();
-    stock_overlay.umount_all();
+    if Path::new(defs::ISOLATE_MOUNT_FLAG_PATH).exists() {
+        // only the processes entered by `ksud mount enter-ns` can see the modules
+        info!("mount isolation enabled, skip global systemless mount");
+    } else {
+        profile::time("systemless mount", || do_systemless_mount(module_dir));
+        save_pending_partitions(module_dir);
+    }
+
+    // the modules can touch the modded filesystem now
+    if let Err(e) = profile::time("post-mount scripts", crate::module::exec_post_mount) {
+        warn!("exec post-mount scripts failed: {}", e);
+    }
 
-    // mount moduke systemlessly by overlay
-    if let Err(e) = mount_systemlessly(module_dir) {
+    Ok(())
+}
+
+fn do_systemless_mount(module_dir: &str) {
+    // mount moduke systemlessly by overlay, the stock overlays it covers are remounted after it
+    if let Err(e) = mount_systemlessly(module_dir, false) {
         warn!("do systemless mount failed: {}", e);
     }
+}
 
-    stock_overlay.mount_all();
+/// apply the module overlays in the mount namespace of `pid`, or revert them if `clean`.
+/// the global namespace is refused, it is shared by processes which don't ask for it.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn enter_ns(pid: i32, clean: bool) -> Result<()> {
+    ensure!(
+        !utils::is_global_mnt_ns(pid)?,
+        "process {pid} is in the global mount namespace"
+    );
+    utils::switch_mnt_ns(pid)?;
 
+    if clean {
+        info!("process {pid}: umount modules");
+        detach_module_overlays(defs::MODULE_DIR);
+        return Ok(());
+    }
+
+    ensure!(
+        get_module_overlay_mounts(defs::MODULE_DIR).is_empty(),
+        "process {pid} already sees the modules"
+    );
+    info!("process {pid}: do systemless mount");
+    do_systemless_mount(defs::MODULE_DIR);
     Ok(())
 }
 
-pub fn on_services() -> Result<()> {
-    utils::umask(0);
+// the partitions of the manifest which some enabled module has files for but which are not on
+// the device yet, they may be mounted after post-fs-data
+fn save_pending_partitions(module_dir: &str) {
+    let manifest = Path::new(module_dir).join(defs::PARTITION_MANIFEST_NAME);
+    let candidates = std::fs::read_to_string(manifest).map_or_else(
+        |_| KNOWN_PARTITIONS.iter().map(ToString::to_string).collect(),
+        |content| {
+            content
+                .lines()
+                .map(str::trim)
+                .filter(|p| !p.is_empty())
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+        },
+    );
+    let modules = std::fs::read_dir(module_dir)
+        .map(|dir| dir.flatten().map(|e| e.path()).collect::<Vec<_>>())
+        .unwrap_or_default();
+    let allowed = get_allowed_partitions();
+    let pending = candidates
+        .into_iter()
+        .filter(|p| !is_partition_present(p) && allowed.contains(p))
+        .filter(|p| {
+            modules
+                .iter()
+                .any(|m| m.join(p).is_dir() && !crate::module::is_disabled(m))
+        })
+        .collect::<Vec<_>>();
+    if pending.is_empty() {
+        let _ = std::fs::remove_file(defs::PENDING_PARTITIONS_PATH);
+        return;
+    }
+    info!("partitions pending for the modules: {pending:?}");
+    let content = pending.iter().map(|p| format!("{p}\n")).collect::<String>();
+    if let Err(e) = std::fs::write(defs::PENDING_PARTITIONS_PATH, content) {
+        warn!("write {} failed: {e}", defs::PENDING_PARTITIONS_PATH);
+    }
+}
 
-    if utils::has_magisk() {
-        warn!("Magisk detected, skip services!");
+/// overlay the pending partitions which are on the device now, the others stay pending
+pub fn retry_pending_mounts() -> Result<()> {
+    let Ok(content) = std::fs::read_to_string(defs::PENDING_PARTITIONS_PATH) else {
+        println!("- No pending partitions");
+        return Ok(());
+    };
+    let (present, pending): (Vec<String>, Vec<String>) = content
+        .lines()
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(ToString::to_string)
+        .partition(|p| is_partition_present(p));
+
+    if !present.is_empty() {
+        let mut report = MountReport::new(defs::MODULE_DIR);
+        let layout = ModuleLayout::new(defs::MODULE_DIR, &present)?;
+        let allowed = get_allowed_partitions();
+        let stock_overlay =
+            mount::StockOverlay::new_for(&get_module_mount_points(&layout, &allowed));
+        stock_overlay.umount_all();
+        let mut overlay = true;
+        for (partition, lowerdir) in layout.partitions {
+            println!("- Mount pending partition: /{partition}");
+            mount_allowed_partition(
+                &partition,
+                lowerdir,
+                &allowed,
+                &mut report,
+                &mut overlay,
+                false,
+            );
+        }
+        stock_overlay.mount_all();
+    }
+
+    if pending.is_empty() {
+        let _ = std::fs::remove_file(defs::PENDING_PARTITIONS_PATH);
+    } else {
+        println!("- Still pending: {}", pending.join(", "));
+        let content = pending.iter().map(|p| format!("{p}\n")).collect::<String>();
+        std::fs::write(defs::PENDING_PARTITIONS_PATH, content)
+            .with_context(|| format!("Failed to write {}", defs::PENDING_PARTITIONS_PATH))?;
+    }
+    Ok(())
+}
+
+// overlay mount points which have module layers
+fn get_module_overlay_mounts(module_dir: &str) -> Vec<String> {
+    mount::get_overlay_mounts()
+        .into_iter()
+        .filter(|(_, lowerdirs)| lowerdirs.iter().any(|d| d.starts_with(module_dir)))
+        .map(|(mnt, _)| mnt)
+        .collect()
+}
+
+/// umount the module overlays, bind mounts and the merged hosts in the current mount namespace,
+/// the module image itself stays mounted
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn detach_modules() {
+    detach_modules_of(defs::MODULE_DIR);
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn detach_modules_of(module_dir: &str) {
+    detach_module_overlays(module_dir);
+
+    for mnt in mount::get_bind_mounts_of(module_dir) {
+        info!("umount {mnt}");
+        if let Err(e) = mount::detach_dir(&mnt) {
+            warn!("umount {mnt} failed: {e}");
+        }
+    }
+
+    hosts::umount_merged_hosts();
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn detach_modules() {
+    unimplemented!()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn detach_modules_of(_module_dir: &str) {
+    unimplemented!()
+}
+
+// deepest first, chained overlays on the same mount point need a few rounds
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn detach_module_overlays(module_dir: &str) {
+    const MAX_ROUNDS: usize = 8;
+    for _ in 0..MAX_ROUNDS {
+        let mut module_mounts = get_module_overlay_mounts(module_dir);
+        if module_mounts.is_empty() {
+            return;
+        }
+        module_mounts.sort_by_key(|mnt| std::cmp::Reverse(mnt.len()));
+        for mnt in module_mounts {
+            info!("umount {mnt}");
+            if let Err(e) = mount::detach_dir(&mnt) {
+                warn!("umount {mnt} failed: {e}");
+            }
+        }
+    }
+    warn!("module overlays are still mounted");
+}
+
+/// tear down everything `on_post_data_fs` mounted in the global namespace: the module
+/// overlays, the magic mounted files, the merged hosts and the module image
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn umount_all() -> Result<()> {
+    detach_modules();
+    mount::umount_dir_recursive(defs::MODULE_DIR)?;
+    println!("- All modules are unmounted");
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn umount_all() -> Result<()> {
+    unimplemented!()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn enter_ns(_pid: i32, _clean: bool) -> Result<()> {
+    unimplemented!()
+}
+
+// print what post-fs-data would do with the modules in `module_dir`
+fn dry_run_post_fs_data(module_dir: &str) -> Result<()> {
+    for script in crate::module::get_common_scripts("post-fs-data.d")? {
+        println!("- Would exec common script: {}", script.display());
+    }
+    for rule in crate::module::get_module_files_in(module_dir, "sepolicy.rule")? {
+        println!("- Would load sepolicy: {}", rule.display());
+    }
+    for dir in crate::module::get_module_files_in(module_dir, "sepolicy.d")? {
+        for rule in crate::module::get_sepolicy_d_rules(&dir) {
+            println!("- Would load sepolicy: {}", rule.display());
+        }
+    }
+    for script in crate::module::get_module_files_in(module_dir, "post-fs-data.sh")? {
+        println!("- Would exec module script: {}", script.display());
+    }
+    for prop in crate::module::get_module_files_in(module_dir, "system.prop")? {
+        println!("- Would load system prop: {}", prop.display());
+    }
+    if Path::new(defs::PRE_MOUNT_SCRIPT_PATH).exists() {
+        println!(
+            "- Would exec pre-mount script: {}",
+            defs::PRE_MOUNT_SCRIPT_PATH
+        );
+    }
+    mount_systemlessly(module_dir, true)?;
+    for script in crate::module::get_module_files_in(module_dir, "post-mount.sh")? {
+        println!("- Would exec module script: {}", script.display());
+    }
+    Ok(())
+}
+
+// return true if KernelSU should leave everything to Magisk.
+// in coexistence mode, we continue in a private mount namespace so that we don't fight with Magisk.
+fn magisk_takes_over(stage: &str) -> Result<bool> {
+    if !utils::has_magisk() {
+        return Ok(false);
+    }
+    if !Path::new(defs::MAGISK_COEXIST_FLAG_PATH).exists() {
+        warn!("Magisk detected, skip {stage}!");
+        return Ok(true);
+    }
+    warn!("Magisk detected, run {stage} in a private mount namespace");
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    utils::unshare_mnt_ns()?;
+    Ok(false)
+}
+
+// rename the image to <img>.bad, the manager can find it and offer recovery
+fn move_bad_image(img: &str) {
+    let bad_img = format!("{img}{}", defs::BAD_IMG_SUFFIX);
+    warn!("move {img} to {bad_img}, no modules will be loaded!");
+    move_image(img, &bad_img);
+}
+
+// rename the image with its sidecars
+fn move_image(img: &str, dst: &str) {
+    if let Err(e) = std::fs::rename(img, dst) {
+        warn!("rename {img} to {dst} failed: {e}");
+    }
+    let _ = std::fs::rename(
+        crate::module::get_checksum_path(img),
+        crate::module::get_checksum_path(dst),
+    );
+    let _ = std::fs::rename(
+        crate::module::get_format_path(img),
+        crate::module::get_format_path(dst),
+    );
+}
+
+pub fn on_services(jobs: Option<usize>) -> Result<()> {
+    logger::set_phase("services");
+    utils::umask(utils::get_script_umask());
+
+    // the manager asks it for the status, also in safe mode
+    if let Err(e) = start_daemon() {
+        warn!("{e}");
+    }
+
+    if magisk_takes_over("services")? {
         return Ok(());
     }
 
@@ -212,43 +1615,174 @@ pub fn on_services() -> Result<()> {
         return Ok(());
     }
 
-    if let Err(e) = crate::module::exec_common_scripts("service.d", false) {
-        warn!("Failed to exec common service scripts: {}", e);
-    }
-    if let Err(e) = crate::module::exec_services() {
+    let jobs = jobs.unwrap_or_else(|| {
+        std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+    });
+    // the workers and the scripts inherit it
+    utils::set_low_priority();
+    if let Err(e) = crate::module::exec_services(jobs) {
         warn!("Failed to exec service scripts: {}", e);
     }
 
     Ok(())
 }
 
+// this is a update and we successfully booted, replace the image with the update one. false if
+// the copy doesn't match, then the update is kept for the next boot
+fn promote_update_image(module_update_img: &Path, module_img: &Path) -> Result<bool> {
+    if std::fs::rename(module_update_img, module_img).is_ok() {
+        return Ok(true);
+    }
+    warn!("Failed to rename images, copy it now.",);
+    std::fs::copy(module_update_img, module_img).with_context(|| "Failed to copy images")?;
+    // the copy may be truncated by a power loss, then the update image is the only good
+    // one, keep it and mark the update again so that the next boot retries the promotion
+    let src = defs::MODULE_UPDATE_IMG;
+    let dst = module_img.display().to_string();
+    match crate::module::is_same_image(src, &dst) {
+        Ok(true) => {
+            std::fs::remove_file(module_update_img).with_context(|| "Failed to remove image!")?;
+            Ok(true)
+        }
+        result => {
+            if let Err(e) = result {
+                log::error!("verify {dst} failed: {e}");
+            }
+            log::error!("{dst} doesn't match {src}, keep {src} for the next boot");
+            if let Err(e) = crate::module::mark_update() {
+                log::error!("mark the update failed: {e}");
+            }
+            Ok(false)
+        }
+    }
+}
+
 pub fn on_boot_completed() -> Result<()> {
+    logger::set_phase("boot-completed");
     crate::ksu::report_boot_complete();
     info!("on_boot_completed triggered!");
+    if let Err(e) = utils::reset_boot_count() {
+        warn!("reset boot count failed: {e}");
+    }
     let module_update_img = Path::new(defs::MODULE_UPDATE_IMG);
-    let module_img = Path::new(defs::MODULE_IMG);
-    if module_update_img.exists() {
-        // this is a update and we successfully booted
-        if std::fs::rename(module_update_img, module_img).is_err() {
-            warn!("Failed to rename images, copy it now.",);
-            std::fs::copy(module_update_img, module_img)
-                .with_context(|| "Failed to copy images")?;
-            std::fs::remove_file(module_update_img).with_context(|| "Failed to remove image!")?;
+    let module_img = utils::get_module_img();
+    let module_img = Path::new(&module_img);
+    // a failed promotion must not skip the rest of the boot
+    let promoted = module_update_img.exists()
+        && promote_update_image(module_update_img, module_img).unwrap_or_else(|e| {
+            log::error!("promote {} failed: {e:#}", defs::MODULE_UPDATE_IMG);
+            false
+        });
+    if promoted {
+        // the checksum of a read-only image goes with it. an ext4 image is mounted read-write,
+        // it has none until it is finalized again unmounted
+        let img = module_img.display().to_string();
+        let update_checksum = crate::module::get_checksum_path(defs::MODULE_UPDATE_IMG);
+        let checksum = crate::module::get_checksum_path(&img);
+        if matches!(mount::get_image_type(&img), Ok(mount::ImageType::Ext4)) {
+            let _ = std::fs::remove_file(&update_checksum);
+            let _ = std::fs::remove_file(&checksum);
+        } else if std::fs::rename(&update_checksum, &checksum).is_err() {
+            let _ = std::fs::remove_file(&checksum);
         }
+        let update_format = crate::module::get_format_path(defs::MODULE_UPDATE_IMG);
+        let format = crate::module::get_format_path(&module_img.display().to_string());
+        if std::fs::rename(&update_format, &format).is_err() {
+            let _ = std::fs::remove_file(&format);
+        }
+    }
+
+    if magisk_takes_over("boot-completed scripts")? {
+        return Ok(());
+    }
+
+    // the partitions mounted after post-fs-data are there by now
+    if Path::new(defs::PENDING_PARTITIONS_PATH).exists() {
+        if let Err(e) = retry_pending_mounts() {
+            warn!("retry pending partitions failed: {e:#}");
+        }
+    }
+
+    // the modules can't be changed behind the back of ksud from now on
+    if Path::new(defs::READONLY_MODULES_FLAG_PATH).exists() {
+        match mount::remount(defs::MODULE_DIR, true) {
+            Ok(()) => info!("{} is read-only now", defs::MODULE_DIR),
+            Err(e) => warn!("make modules read-only failed: {e:#}"),
+        }
+    }
+
+    if crate::utils::is_safe_mode() {
+        warn!("safe mode, skip module boot-completed scripts");
+        return Ok(());
     }
+
+    if let Err(e) = crate::module::exec_boot_completed() {
+        warn!("exec boot-completed scripts failed: {}", e);
+    }
+
     Ok(())
 }
 
+// `ksud daemon` keeps running after the services, a second one exits as the socket is taken
+fn start_daemon() -> Result<()> {
+    let mut command = std::process::Command::new(defs::DAEMON_PATH);
+    command.arg("daemon");
+    crate::module::spawn_detached(&mut command).with_context(|| "Failed to start ksud daemon")
+}
+
 pub fn daemon() -> Result<()> {
+    logger::set_phase("daemon");
+    crate::daemon::run()
+}
+
+// a write which fails half way on a full /data is worse than the binaries of an older version,
+// they are only extracted if they are missing then
+fn ensure_binaries_if_space() -> Result<()> {
+    let free = utils::get_free_space(defs::ADB_DIR).unwrap_or(u64::MAX);
+    if free < defs::LOW_SPACE_THRESHOLD {
+        warn!(
+            "{} has only {} free, the boot may fail",
+            defs::ADB_DIR,
+            humansize::format_size(free, humansize::DECIMAL)
+        );
+        if assets::binaries_exist() {
+            warn!("low space, skip extracting the binaries");
+            return Ok(());
+        }
+    }
+    assets::ensure_binaries()
+}
+
+fn get_file_hash(path: &str) -> Result<Vec<u8>> {
+    use sha2::{Digest, Sha256};
+    let mut file = std::fs::File::open(path).with_context(|| format!("Failed to open {path}"))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
+}
+
+// copied under a temp name and renamed into place, the old binary may be running and can't be
+// written (ETXTBSY), and a copy which doesn't match leaves the commands running an old one
+fn install_daemon() -> Result<()> {
+    let expected = get_file_hash("/proc/self/exe")?;
+    let tmp = format!("{}.tmp", defs::DAEMON_PATH);
+    std::fs::copy("/proc/self/exe", &tmp)?;
+    std::fs::rename(&tmp, defs::DAEMON_PATH)?;
+    ensure!(
+        get_file_hash(defs::DAEMON_PATH)? == expected,
+        "{} doesn't match the running ksud",
+        defs::DAEMON_PATH
+    );
     Ok(())
 }
 
 pub fn install() -> Result<()> {
     ensure_dir_exists(defs::ADB_DIR)?;
-    std::fs::copy("/proc/self/exe", defs::DAEMON_PATH)?;
+    install_daemon()?;
+    restorecon::set_binary_con(defs::DAEMON_PATH)?;
 
     // install binary assets
-    assets::ensure_binaries().with_context(|| "Failed to extract assets")?;
+    ensure_binaries_if_space().with_context(|| "Failed to extract assets")?;
 
     #[cfg(target_os = "android")]
     link_ksud_to_bin()?;
@@ -261,8 +1795,162 @@ fn link_ksud_to_bin() -> Result<()> {
     use std::path::PathBuf;
     let ksu_bin = PathBuf::from(defs::DAEMON_PATH);
     let ksu_bin_link = PathBuf::from(defs::DAEMON_LINK_PATH);
-    if ksu_bin.exists() && !ksu_bin_link.exists() {
+    // a link of an older version may point somewhere else, or be a stale copy of ksud
+    if ksu_bin_link.symlink_metadata().is_ok()
+        && std::fs::read_link(&ksu_bin_link).ok().as_ref() != Some(&ksu_bin)
+    {
+        warn!(
+            "{} doesn't link to {}, link it again",
+            ksu_bin_link.display(),
+            ksu_bin.display()
+        );
+        std::fs::remove_file(&ksu_bin_link)?;
+    }
+    if ksu_bin.exists() && ksu_bin_link.symlink_metadata().is_err() {
         std::os::unix::fs::symlink(&ksu_bin, &ksu_bin_link)?;
     }
+    if ksu_bin_link.is_symlink() {
+        restorecon::set_binary_con(&ksu_bin_link)?;
+    }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_file(path: &Path, content: &str) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn collect_lowerdirs_of_module_dir() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        // high wins over low by priority, the others only have one kind of files
+        write_file(&dir.join("high/module.prop"), "id=high\npriority=1\n");
+        write_file(&dir.join("high/system/bin/high"), "");
+        write_file(&dir.join("low/module.prop"), "id=low\n");
+        write_file(&dir.join("low/system/bin/low"), "");
+        write_file(&dir.join("low/vendor/lib/low.so"), "");
+        write_file(&dir.join("hosts/module.prop"), "id=hosts\n");
+        write_file(&dir.join("hosts/system/etc/hosts"), "127.0.0.1 localhost\n");
+        write_file(&dir.join("off/module.prop"), "id=off\n");
+        write_file(&dir.join("off/system/bin/off"), "");
+        write_file(&dir.join("off").join(defs::DISABLE_FILE_NAME), "");
+
+        let module_dir = dir.display().to_string();
+        let lowerdir = |module: &str, partition: &str| format!("{module_dir}/{module}/{partition}");
+        let partitions = vec!["vendor".to_string(), "odm".to_string()];
+        let (system, lowerdirs) = collect_lowerdirs(&module_dir, &partitions).unwrap();
+
+        assert_eq!(
+            system,
+            vec![lowerdir("high", "system"), lowerdir("low", "system")]
+        );
+        assert_eq!(lowerdirs["vendor"], vec![lowerdir("low", "vendor")]);
+        assert!(lowerdirs["odm"].is_empty());
+        assert_eq!(
+            collect_hosts(&module_dir).unwrap(),
+            vec![lowerdir("hosts", "system")]
+        );
+
+        let mut report = MountReport::new(&module_dir);
+        report_modules(&module_dir, &mut report).unwrap();
+        assert_eq!(report.module("off").skipped, vec!["disabled".to_string()]);
+
+        // only the lowerdirs of one module are kept
+        let mut layout = ModuleLayout::new(&module_dir, &partitions).unwrap();
+        layout.retain_module(&module_dir, "low");
+        assert_eq!(layout.system, vec![lowerdir("low", "system")]);
+        assert!(layout.hosts.is_empty());
+    }
+
+    #[test]
+    fn module_of_lowerdir_is_the_first_dir_below_module_dir() {
+        let module = |lowerdir: &str| {
+            get_module_of_lowerdir(defs::MODULE_DIR, lowerdir).map(|m| m.display().to_string())
+        };
+        let foo = Some(format!("{}foo", defs::MODULE_DIR));
+        assert_eq!(module(&format!("{}foo/vendor", defs::MODULE_DIR)), foo);
+        assert_eq!(
+            module(&format!("{}foo/system/vendor", defs::MODULE_DIR)),
+            foo
+        );
+        // a module may be named system too
+        assert_eq!(
+            module(&format!("{}system/vendor", defs::MODULE_DIR)),
+            Some(format!("{}system", defs::MODULE_DIR))
+        );
+        assert_eq!(module(defs::LEGACY_LINKS_DIR), None);
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[ignore = "needs root"]
+    fn interrupted_run_twice_ends_with_the_same_mounts() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path();
+        let module_dir = base.join("modules").display().to_string();
+        let module_update_img = base.join("modules_update.img").display().to_string();
+        let target = base.join("system").display().to_string();
+        std::fs::create_dir_all(&module_dir).unwrap();
+        std::fs::create_dir_all(&target).unwrap();
+
+        // what post-fs-data mounts: the image on the module dir and the overlay of a module
+        let run = || {
+            umount_interrupted_run(&module_dir, &module_update_img);
+            sys_mount::Mount::builder()
+                .fstype(sys_mount::FilesystemType::from("tmpfs"))
+                .mount("tmpfs", &module_dir)
+                .unwrap();
+            write_file(&Path::new(&module_dir).join("module/system/file"), "");
+            mount::mount_overlay(&format!("{module_dir}/module/system:{target}"), &target).unwrap();
+        };
+        let mounts = || {
+            let mut mounts = procfs::process::Process::myself()
+                .unwrap()
+                .mountinfo()
+                .unwrap()
+                .into_iter()
+                .filter(|m| m.mount_point.starts_with(&base))
+                .map(|m| (m.mount_point, m.fs_type))
+                .collect::<Vec<_>>();
+            mounts.sort();
+            mounts
+        };
+
+        run();
+        let once = mounts();
+        assert_eq!(once.len(), 2);
+        // the second run finds the mounts of the first one as if it was killed
+        run();
+        assert_eq!(mounts(), once);
+
+        umount_interrupted_run(&module_dir, &module_update_img);
+        assert!(mounts().is_empty());
+    }
+
+    #[test]
+    fn orphaned_update_flag_falls_back_to_module_img() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        write_file(&dir.join("modules.img"), "");
+        let flag = dir.join(defs::UPDATE_FILE_NAME);
+        write_file(&flag, "");
+        let module_img = dir.join("modules.img").display().to_string();
+        let module_update_img = dir.join("modules_update.img").display().to_string();
+
+        // a dry run keeps the flag
+        let target =
+            choose_module_image(&module_img, &module_update_img, &flag, false, true).unwrap();
+        assert_eq!(target, module_img);
+        assert!(flag.exists());
+
+        let target =
+            choose_module_image(&module_img, &module_update_img, &flag, false, false).unwrap();
+        assert_eq!(target, module_img);
+        assert!(!flag.exists());
+    }
+}
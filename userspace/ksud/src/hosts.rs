@@ -0,0 +1,139 @@
+use anyhow::{Context, Result};
+use std::{collections::HashSet, path::Path};
+
+use crate::{defs, mount, restorecon, utils};
+
+const SYSTEM_HOSTS: &str = "/system/etc/hosts";
+
+/// if the module system dir only contains etc/hosts
+pub fn is_hosts_only<P: AsRef<Path>>(module_system: P) -> bool {
+    fn only_entry(dir: &Path) -> Option<String> {
+        let mut entries = std::fs::read_dir(dir).ok()?.flatten();
+        let entry = entries.next()?;
+        if entries.next().is_some() {
+            return None;
+        }
+        entry.file_name().to_str().map(ToString::to_string)
+    }
+
+    let module_system = module_system.as_ref();
+    only_entry(module_system).as_deref() == Some("etc")
+        && only_entry(&module_system.join("etc")).as_deref() == Some("hosts")
+        && module_system.join("etc/hosts").is_file()
+}
+
+// a hosts entry without the extra spaces, so that the same entry is only added once
+fn normalize(line: &str) -> String {
+    line.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+// stock hosts + hosts of the modules + the managed fragment, duplicated entries are removed
+fn merge_hosts(stock: &str, module_systems: &[String]) -> String {
+    let mut sources = vec![stock.to_string()];
+    sources.extend(module_systems.iter().map(|dir| format!("{dir}/etc/hosts")));
+    sources.push(defs::HOSTS_FRAGMENT_PATH.to_string());
+
+    let mut seen = HashSet::new();
+    let mut merged = String::new();
+    for (i, source) in sources.iter().enumerate() {
+        let Ok(content) = std::fs::read_to_string(source) else {
+            continue;
+        };
+        for line in content.lines() {
+            let entry = normalize(line);
+            if entry.is_empty() {
+                continue;
+            }
+            // only keep the comments of the stock hosts
+            if entry.starts_with('#') && i != 0 {
+                continue;
+            }
+            if entry.starts_with('#') || seen.insert(entry.clone()) {
+                merged.push_str(&entry);
+                merged.push('\n');
+            }
+        }
+    }
+    merged
+}
+
+/// bind mount the merged hosts over /system/etc/hosts, do nothing if there is nothing to merge
+pub fn mount_merged_hosts(module_systems: &[String]) -> Result<()> {
+    if module_systems.is_empty() && !Path::new(defs::HOSTS_FRAGMENT_PATH).exists() {
+        return Ok(());
+    }
+
+    if let Err(e) = std::fs::copy(SYSTEM_HOSTS, defs::HOSTS_STOCK_PATH) {
+        log::warn!("copy {SYSTEM_HOSTS} failed: {e}");
+    }
+    let merged = merge_hosts(SYSTEM_HOSTS, module_systems);
+    std::fs::write(defs::HOSTS_MERGED_PATH, merged)
+        .with_context(|| format!("Failed to write {}", defs::HOSTS_MERGED_PATH))?;
+    restorecon::setsyscon(defs::HOSTS_MERGED_PATH)?;
+    log::info!("bind mount {} to {SYSTEM_HOSTS}", defs::HOSTS_MERGED_PATH);
+    mount::bind_mount(defs::HOSTS_MERGED_PATH, SYSTEM_HOSTS)
+}
+
+/// merge the hosts again into the mounted merged hosts, e.g. after a module is removed. it is
+/// written in place, so the bind mount shows it at once
+pub fn regenerate_merged_hosts(module_systems: &[String]) -> Result<()> {
+    if !Path::new(defs::HOSTS_MERGED_PATH).exists() || !Path::new(defs::HOSTS_STOCK_PATH).exists() {
+        return Ok(());
+    }
+    let merged = merge_hosts(defs::HOSTS_STOCK_PATH, module_systems);
+    std::fs::write(defs::HOSTS_MERGED_PATH, merged)
+        .with_context(|| format!("Failed to write {}", defs::HOSTS_MERGED_PATH))
+}
+
+/// umount the merged hosts if it is mounted
+pub fn umount_merged_hosts() {
+    if !Path::new(defs::HOSTS_MERGED_PATH).exists() {
+        return;
+    }
+    if let Err(e) = mount::umount_dir(SYSTEM_HOSTS) {
+        log::info!("umount {SYSTEM_HOSTS}: {e}");
+    }
+}
+
+fn read_fragment() -> Vec<String> {
+    std::fs::read_to_string(defs::HOSTS_FRAGMENT_PATH)
+        .map(|content| content.lines().map(ToString::to_string).collect())
+        .unwrap_or_default()
+}
+
+fn write_fragment(lines: &[String]) -> Result<()> {
+    utils::ensure_dir_exists(defs::WORKING_DIR)?;
+    let mut content = lines.join("\n");
+    content.push('\n');
+    std::fs::write(defs::HOSTS_FRAGMENT_PATH, content)
+        .with_context(|| format!("Failed to write {}", defs::HOSTS_FRAGMENT_PATH))
+}
+
+pub fn add_host(ip: &str, host: &str) -> Result<()> {
+    let entry = format!("{ip} {host}");
+    let mut lines = read_fragment();
+    if lines.iter().any(|line| normalize(line) == entry) {
+        println!("- {entry} already exists");
+        return Ok(());
+    }
+    lines.push(entry);
+    write_fragment(&lines)?;
+    println!("- Reboot to take effect");
+    Ok(())
+}
+
+pub fn remove_host(host: &str) -> Result<()> {
+    let lines = read_fragment();
+    let remain = lines
+        .iter()
+        .filter(|line| !line.split_whitespace().skip(1).any(|h| h == host))
+        .cloned()
+        .collect::<Vec<_>>();
+    if remain.len() == lines.len() {
+        println!("- {host} not found");
+        return Ok(());
+    }
+    write_fragment(&remain)?;
+    println!("- Reboot to take effect");
+    Ok(())
+}
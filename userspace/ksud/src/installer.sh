@@ -222,6 +222,10 @@ mount_partitions() {
 }
 
 api_level_arch_detect() {
+  # ksud exports them before it runs the installer
+  if [ -n "$API" ] && [ -n "$ABI" ] && [ -n "$ARCH" ] && [ -n "$ABI32" ] && [ -n "$IS64BIT" ]; then
+    return
+  fi
   API=$(grep_get_prop ro.build.version.sdk)
   ABI=$(grep_get_prop ro.product.cpu.abi)
   if [ "$ABI" = "x86" ]; then
@@ -21,7 +21,7 @@ const CMD_GRANT_ROOT: u64 = 0;
 const CMD_GET_VERSION: u64 = 2;
 // const CMD_ALLOW_SU: u64 = 3;
 // const CMD_DENY_SU: u64 = 4;
-// const CMD_GET_ALLOW_LIST: u64 = 5;
+const CMD_GET_ALLOW_LIST: u64 = 5;
 // const CMD_GET_DENY_LIST: u64 = 6;
 const CMD_REPORT_EVENT: u64 = 7;
 pub const CMD_SET_SEPOLICY: u64 = 8;
@@ -268,7 +268,44 @@ pub fn get_version() -> i32 {
     result
 }
 
+/// the kernel is new enough for this ksud, 0 means it is not a KernelSU kernel
+pub fn is_kernel_compatible(version: i32) -> bool {
+    version >= defs::MIN_KSU_KERNEL_VERSION
+}
+
+/// the versions of ksud and the kernel, recorded in the report files of ksud
+pub fn get_version_info() -> serde_json::Value {
+    let kernel = get_version();
+    serde_json::json!({
+        "ksud": defs::VERSION_NAME.trim(),
+        "ksud_code": defs::VERSION_CODE.trim(),
+        "kernel": kernel,
+        "kernel_compatible": is_kernel_compatible(kernel),
+    })
+}
+
+pub fn print_version() -> Result<()> {
+    let kernel = get_version();
+    println!(
+        "ksud: {} ({})",
+        defs::VERSION_NAME.trim(),
+        defs::VERSION_CODE.trim()
+    );
+    println!("kernel: {kernel}");
+    if !is_kernel_compatible(kernel) {
+        println!(
+            "- Kernel is older than the minimal supported version {}, please update it",
+            defs::MIN_KSU_KERNEL_VERSION
+        );
+    }
+    Ok(())
+}
+
 fn report_event(event: u64) {
+    // the boot of a sandbox is not the one of the kernel
+    if crate::utils::is_sandboxed() {
+        return;
+    }
     #[cfg(any(target_os = "linux", target_os = "android"))]
     unsafe {
         #[allow(clippy::cast_possible_wrap)]
@@ -296,10 +333,146 @@ pub fn check_kernel_safemode() -> bool {
     result == KERNEL_SU_OPTION as i32
 }
 
+// the size of the array the kernel copies the allow list to
+const MAX_ALLOW_LIST_LEN: usize = 128;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn get_allow_list() -> Result<Vec<u32>> {
+    let mut uids = [0u32; MAX_ALLOW_LIST_LEN];
+    let mut len: u32 = 0;
+    let mut result: u32 = 0;
+    unsafe {
+        #[allow(clippy::cast_possible_wrap)]
+        libc::prctl(
+            KERNEL_SU_OPTION as i32, // supposed to overflow
+            CMD_GET_ALLOW_LIST,
+            uids.as_mut_ptr(),
+            std::ptr::addr_of_mut!(len).cast::<libc::c_void>(),
+            std::ptr::addr_of_mut!(result).cast::<libc::c_void>(),
+        );
+    }
+    ensure!(
+        result == KERNEL_SU_OPTION,
+        "kernel refused to get the allow list"
+    );
+    Ok(uids[..(len as usize).min(MAX_ALLOW_LIST_LEN)].to_vec())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn get_allow_list() -> Result<Vec<u32>> {
+    unimplemented!()
+}
+
+// uid -> packages from packages.list, shared uids have more than one
+fn get_packages_of_uids() -> std::collections::HashMap<u32, Vec<String>> {
+    let mut packages = std::collections::HashMap::<u32, Vec<String>>::new();
+    let content = std::fs::read_to_string("/data/system/packages.list").unwrap_or_default();
+    for line in content.lines() {
+        let mut fields = line.split_whitespace();
+        let (Some(name), Some(uid)) = (fields.next(), fields.next()) else {
+            continue;
+        };
+        if let std::result::Result::Ok(uid) = uid.parse() {
+            packages.entry(uid).or_default().push(name.to_string());
+        }
+    }
+    packages
+}
+
+/// the uid of the installed `package` in packages.list, which is the one of the first user
+pub fn get_package_uid(package: &str) -> Option<u32> {
+    get_packages_of_uids()
+        .into_iter()
+        .find_map(|(uid, packages)| packages.iter().any(|p| p == package).then_some(uid))
+}
+
+/// print the granted uids and their packages as json
+pub fn list_su() -> Result<()> {
+    println!("{}", serde_json::to_string_pretty(&export_su_policy()?)?);
+    Ok(())
+}
+
+/// the granted uids with their packages, the uids may differ on another system
+pub fn export_su_policy() -> Result<serde_json::Value> {
+    let packages = get_packages_of_uids();
+    let list = get_allow_list()?
+        .into_iter()
+        .map(|uid| {
+            serde_json::json!({
+                "uid": uid,
+                "packages": packages.get(&uid).cloned().unwrap_or_default(),
+            })
+        })
+        .collect::<Vec<_>>();
+    Ok(list.into())
+}
+
+/// print the entries of `export_su_policy` which are installed with their current uid, only the
+/// manager can change the allow list so they are granted there again
+pub fn import_su_policy(policy: &serde_json::Value) -> Result<()> {
+    let uids = get_packages_of_uids()
+        .into_iter()
+        .flat_map(|(uid, packages)| packages.into_iter().map(move |p| (p, uid)))
+        .collect::<std::collections::HashMap<_, _>>();
+    let granted = get_allow_list().unwrap_or_default();
+    for entry in policy.as_array().into_iter().flatten() {
+        let packages = entry["packages"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(serde_json::Value::as_str)
+            .collect::<Vec<_>>();
+        // the uid of a package changes when the system is reinstalled
+        let uid = if packages.is_empty() {
+            entry["uid"]
+                .as_u64()
+                .and_then(|uid| u32::try_from(uid).ok())
+        } else {
+            packages.iter().find_map(|p| uids.get(*p).copied())
+        };
+        let Some(uid) = uid else {
+            log::warn!("su: {entry} is not installed, skip it");
+            continue;
+        };
+        if !granted.contains(&uid) {
+            println!("- Grant uid {uid} {} in the manager", packages.join(" "));
+        }
+    }
+    Ok(())
+}
+
+// a restarted ksud must not report an event twice in one boot, the kernel expects each once
+fn report_event_once(event: u64) {
+    let Some(boot_id) = std::fs::read_to_string("/proc/sys/kernel/random/boot_id").ok() else {
+        report_event(event);
+        return;
+    };
+    let boot_id = boot_id.trim();
+    let content = std::fs::read_to_string(defs::REPORTED_EVENTS_PATH).unwrap_or_default();
+    let mut lines = content.lines();
+    // the events of an earlier boot are dropped
+    let mut reported = if lines.next() == Some(boot_id) {
+        lines.map(ToString::to_string).collect::<Vec<_>>()
+    } else {
+        Vec::new()
+    };
+    let event_name = event.to_string();
+    if reported.contains(&event_name) {
+        log::debug!("event {event} is already reported in this boot, skip");
+        return;
+    }
+    report_event(event);
+    reported.push(event_name);
+    let content = format!("{boot_id}\n{}\n", reported.join("\n"));
+    if let Err(e) = std::fs::write(defs::REPORTED_EVENTS_PATH, content) {
+        log::warn!("write {} failed: {e}", defs::REPORTED_EVENTS_PATH);
+    }
+}
+
 pub fn report_post_fs_data() {
-    report_event(EVENT_POST_FS_DATA);
+    report_event_once(EVENT_POST_FS_DATA);
 }
 
 pub fn report_boot_complete() {
-    report_event(EVENT_BOOT_COMPLETED);
+    report_event_once(EVENT_BOOT_COMPLETED);
 }
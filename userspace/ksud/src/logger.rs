@@ -0,0 +1,78 @@
+use std::{
+    io::Write,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// the boot stage ksud is running, attached to each json log line
+static PHASE: Mutex<&'static str> = Mutex::new("");
+
+/// set env KSUD_LOG_FORMAT=json to get json logs without the cli flag
+pub const LOG_FORMAT_ENV: &str = "KSUD_LOG_FORMAT";
+
+pub fn set_phase(phase: &'static str) {
+    if let Ok(mut p) = PHASE.lock() {
+        *p = phase;
+    }
+}
+
+fn get_phase() -> &'static str {
+    PHASE.lock().map(|p| *p).unwrap_or_default()
+}
+
+pub fn json_enabled(flag: bool) -> bool {
+    flag || std::env::var(LOG_FORMAT_ENV).map_or(false, |v| v == "json")
+}
+
+/// info by default, each -v raises it to debug and trace, -q lowers it to warn
+pub fn get_level(verbose: u8, quiet: bool) -> log::LevelFilter {
+    match (quiet, verbose) {
+        (true, _) => log::LevelFilter::Warn,
+        (false, 0) => log::LevelFilter::Info,
+        (false, 1) => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    }
+}
+
+pub fn init(json: bool, level: log::LevelFilter) {
+    if json {
+        init_json(level);
+        return;
+    }
+
+    #[cfg(target_os = "android")]
+    android_logger::init_once(
+        android_logger::Config::default()
+            .with_max_level(level) // limit log level
+            .with_tag("KernelSU"), // logs will show under mytag tag
+    );
+
+    // RUST_LOG still overrides it
+    #[cfg(not(target_os = "android"))]
+    let _ = env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .try_init();
+}
+
+// one json object per line to stderr
+fn init_json(level: log::LevelFilter) {
+    let _ = env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .format(|buf, record| {
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis())
+                .unwrap_or_default();
+            let line = serde_json::json!({
+                "timestamp": timestamp as u64,
+                "level": record.level().as_str(),
+                "module": record.module_path().unwrap_or_default(),
+                "phase": get_phase(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{line}")
+        })
+        .try_init();
+}
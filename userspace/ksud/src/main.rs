@@ -1,14 +1,24 @@
 mod apk_sign;
 mod assets;
+mod backup;
 mod cli;
+mod config;
+mod daemon;
 mod debug;
 mod defs;
+mod diag;
+mod error;
 mod event;
+mod hosts;
 mod ksu;
+mod logger;
 mod module;
+mod module_sign;
 mod mount;
+mod profile;
 mod restorecon;
 mod sepolicy;
+mod update_check;
 mod utils;
 
 fn main() -> anyhow::Result<()> {
@@ -1,7 +1,9 @@
 #[allow(clippy::wildcard_imports)]
 use crate::utils::*;
 use crate::{
-    assets, defs, mount,
+    assets, defs,
+    error::KsudError,
+    mount,
     restorecon::{restore_syscon, setsyscon},
     sepolicy,
 };
@@ -12,18 +14,51 @@ use is_executable::is_executable;
 use java_properties::PropertiesIter;
 use log::{info, warn};
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     env::var as env_var,
-    fs::{remove_dir_all, set_permissions, File, Permissions},
-    io::Cursor,
+    fs::{remove_dir_all, set_permissions, File, OpenOptions, Permissions},
+    io::{Cursor, Read, Write},
     path::{Path, PathBuf},
     process::{Command, Stdio},
     str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 use zip_extensions::zip_extract_file_to_memory;
 
 #[cfg(unix)]
-use std::os::unix::{prelude::PermissionsExt, process::CommandExt};
+use std::os::unix::{
+    io::{AsRawFd, FromRawFd},
+    prelude::PermissionsExt,
+    process::CommandExt,
+};
+
+// grow the image during install if the free space is less than this
+const IMG_LOW_SPACE_THRESHOLD: u64 = 16 * 1024 * 1024;
+// free space left in the image after shrinking
+const IMG_SHRINK_SLACK: u64 = 32 * 1024 * 1024;
+// the modules are not mounted if the image has less free space than this on boot
+const IMG_MIN_FREE_SPACE: u64 = 1024 * 1024;
+// a dir with this file replaces the stock dir instead of being merged with it
+const MODULE_REPLACE_MARKER: &str = ".replace";
+// stock paths a module replaces or deletes, one per line, e.g. /system/app/Foo
+const MODULE_REPLACE_FILE: &str = "replace";
+
+// runtime data of a module which `ksud module update` keeps
+const MODULE_DATA_DIR: &str = "data";
+// more paths to keep, one relative path per line
+const MODULE_KEEP_FILE: &str = "keep";
+
+// `ksud module install -` reads the zip from stdin into this file, up to this size. a zip which
+// must be signed is copied here too
+const ZIP_COPY_PATH: &str = concatcp!(defs::WORKING_DIR, ".module_copy.zip");
+const STDIN_ZIP_MAX_SIZE: u64 = 512 * 1024 * 1024;
+
+// older versions kept the runonce markers in the module dir
+const LEGACY_RUNONCE_DIR: &str = ".runonce";
+
+// erofs compresses well, assume the contents are at most this many times larger when unpacked
+const EROFS_UNPACK_RATIO: u64 = 4;
 
 const INSTALLER_CONTENT: &str = include_str!("./installer.sh");
 const INSTALL_MODULE_SCRIPT: &str = concatcp!(
@@ -35,12 +70,40 @@ const INSTALL_MODULE_SCRIPT: &str = concatcp!(
     "\n"
 );
 
+// the installer works in it, the same as TMPDIR of Magisk
+const INSTALL_TMP_DIR: &str = "/dev/tmp";
+
+// ARCH, ABI, ABI32, IS64BIT and API of the Magisk installer, so that customize.sh finds them
+// the same way under KernelSU
+fn get_device_env() -> Vec<(&'static str, String)> {
+    let abi = getprop("ro.product.cpu.abi").unwrap_or_default();
+    let (arch, abi, abi32, is64bit) = match abi.trim() {
+        "x86" => ("x86", "x86", "x86", false),
+        "arm64-v8a" => ("arm64", "arm64-v8a", "armeabi-v7a", true),
+        "x86_64" => ("x64", "x86_64", "x86", true),
+        _ => ("arm", "armeabi-v7a", "armeabi-v7a", false),
+    };
+    let mut env = vec![
+        ("ARCH", arch.to_string()),
+        ("ABI", abi.to_string()),
+        ("ABI32", abi32.to_string()),
+        ("IS64BIT", is64bit.to_string()),
+    ];
+    // without it the installer reads build.prop itself
+    if let Some(api) = getprop("ro.build.version.sdk") {
+        env.push(("API", api.trim().to_string()));
+    }
+    env
+}
+
 fn exec_install_script(module_file: &str) -> Result<()> {
     let realpath = std::fs::canonicalize(module_file)
         .with_context(|| format!("realpath: {module_file} failed"))?;
 
     let result = Command::new(assets::BUSYBOX_PATH)
         .args(["sh", "-c", INSTALL_MODULE_SCRIPT])
+        .envs(get_device_env())
+        .env("TMPDIR", INSTALL_TMP_DIR)
         .env("ASH_STANDALONE", "1")
         .env(
             "PATH",
@@ -69,25 +132,236 @@ fn exec_install_script(module_file: &str) -> Result<()> {
 fn ensure_boot_completed() -> Result<()> {
     // ensure getprop sys.boot_completed == 1
     if getprop("sys.boot_completed").as_deref() != Some("1") {
-        bail!("Android is Booting!");
+        bail!(KsudError::Booting);
+    }
+    Ok(())
+}
+
+pub fn mark_update() -> Result<()> {
+    set_flag(concatcp!(defs::WORKING_DIR, defs::UPDATE_FILE_NAME), true)
+}
+
+// a `staged` change goes into modules_update.img but the update flag is not set and the live
+// modules are not touched until stage-commit
+fn mark_changed(staged: bool) -> Result<()> {
+    if staged {
+        println!("- Staged, run `ksud module stage-commit` to apply it on reboot");
+        return set_flag(defs::MODULE_STAGE_FLAG_PATH, true);
+    }
+    mark_update()
+}
+
+// staged changes live in modules_update.img, a normal change would apply them with its own,
+// and a pending update would apply the staged ones without a commit
+fn ensure_stage_consistent(staged: bool) -> Result<()> {
+    if staged {
+        ensure!(
+            !Path::new(defs::WORKING_DIR)
+                .join(defs::UPDATE_FILE_NAME)
+                .exists(),
+            "an update is pending, reboot before staging changes"
+        );
+    } else {
+        ensure!(
+            !Path::new(defs::MODULE_STAGE_FLAG_PATH).exists(),
+            "changes are staged, run `ksud module stage-commit` or `stage-abort` first"
+        );
+    }
+    Ok(())
+}
+
+/// install the module zip staged, it accumulates in modules_update.img until stage-commit
+pub fn stage_install(zip: &str) -> Result<()> {
+    let progress = InstallProgress::new(false);
+    let result = _install_module(zip, None, false, true, &progress);
+    if let Ok((id, _)) = &result {
+        // installed again, it is not gone on stage-commit anymore
+        let mut ids = read_id_list(defs::MODULE_STAGED_UNINSTALL_PATH);
+        if ids.remove(id) {
+            write_id_list(defs::MODULE_STAGED_UNINSTALL_PATH, &ids)?;
+        }
+    }
+    handle_install_result(result, &progress)
+}
+
+/// remove the module from the staged image, its uninstall.sh runs on stage-commit because
+/// stage-abort can't undo it
+pub fn stage_uninstall(id: &str) -> Result<()> {
+    _uninstall_module(id, false, true)?;
+    let mut ids = read_id_list(defs::MODULE_STAGED_UNINSTALL_PATH);
+    ids.insert(id.to_string());
+    write_id_list(defs::MODULE_STAGED_UNINSTALL_PATH, &ids)
+}
+
+/// enable or disable the module in the staged image
+pub fn stage_enable(id: &str, enable: bool) -> Result<()> {
+    _update_module(
+        defs::MODULE_UPDATE_TMP_DIR,
+        id,
+        false,
+        true,
+        |mid, update_dir| _enable_module(update_dir, mid, enable, true),
+    )
+}
+
+fn ensure_staged() -> Result<()> {
+    ensure!(
+        Path::new(defs::MODULE_STAGE_FLAG_PATH).exists()
+            && Path::new(defs::MODULE_UPDATE_IMG).exists(),
+        "no staged changes"
+    );
+    Ok(())
+}
+
+/// make a copy of the module image `img` the pending update, it is mounted on the next boot
+pub fn restore_image(img: &str) -> Result<()> {
+    ensure_stage_consistent(false)?;
+    mount::get_image_type(img)?;
+    std::fs::copy(img, defs::MODULE_UPDATE_TMP_IMG)
+        .with_context(|| format!("Failed to copy {img}"))?;
+    std::fs::rename(defs::MODULE_UPDATE_TMP_IMG, defs::MODULE_UPDATE_IMG)?;
+    save_image_checksum(defs::MODULE_UPDATE_IMG)?;
+    // the copy is of the format of the ksud which built it
+    let format = get_format_path(defs::MODULE_UPDATE_IMG);
+    if std::fs::copy(get_format_path(img), &format).is_err() {
+        let _ = std::fs::remove_file(&format);
+    }
+    mark_update()
+}
+
+/// print the mount layout and the conflicts of the staged image
+pub fn stage_preview() -> Result<()> {
+    ensure_staged()?;
+    let dir = defs::MODULE_UPDATE_TMP_DIR;
+    ensure_clean_dir(dir)?;
+    // we are in a private mount namespace, it is gone when we exit
+    mount::mount_image_readonly(defs::MODULE_UPDATE_IMG, dir)?;
+    crate::event::dump_mount_layout(Some(dir))?;
+    crate::event::print_conflicts(dir)
+}
+
+/// apply the staged changes on the next boot
+pub fn stage_commit() -> Result<()> {
+    ensure_staged()?;
+    verify_image_checksum(defs::MODULE_UPDATE_IMG)?;
+    // the staged image doesn't have the uninstalled modules anymore, run the live scripts
+    for id in read_id_list(defs::MODULE_STAGED_UNINSTALL_PATH) {
+        let uninstall_script = Path::new(defs::MODULE_DIR).join(&id).join("uninstall.sh");
+        if uninstall_script.exists() {
+            if let Err(e) = exec_script(&uninstall_script, true) {
+                warn!("exec {} failed: {e:#}", uninstall_script.display());
+            }
+        }
+        if let Err(e) = mark_module_state(&id, defs::REMOVE_FILE_NAME, true) {
+            warn!("mark {id} removed failed: {e:#}");
+        }
+        let _ = remove_dir_all(Path::new(defs::MODULE_RUNONCE_DIR).join(&id));
+        let _ = remove_dir_all(Path::new(defs::MODULE_LOG_DIR).join(&id));
+        drop_live_module(&id);
     }
+    mark_update()?;
+    std::fs::remove_file(defs::MODULE_STAGE_FLAG_PATH)?;
+    let _ = std::fs::remove_file(defs::MODULE_STAGED_UNINSTALL_PATH);
+    println!("- Staged changes will be applied on reboot");
     Ok(())
 }
 
-fn mark_update() -> Result<()> {
-    ensure_file_exists(concatcp!(defs::WORKING_DIR, defs::UPDATE_FILE_NAME))
+/// discard the staged changes
+pub fn stage_abort() -> Result<()> {
+    ensure_staged()?;
+    std::fs::remove_file(defs::MODULE_UPDATE_IMG)?;
+    let _ = std::fs::remove_file(get_checksum_path(defs::MODULE_UPDATE_IMG));
+    let _ = std::fs::remove_file(get_format_path(defs::MODULE_UPDATE_IMG));
+    std::fs::remove_file(defs::MODULE_STAGE_FLAG_PATH)?;
+    let _ = std::fs::remove_file(defs::MODULE_STAGED_UNINSTALL_PATH);
+    println!("- Staged changes are discarded");
+    Ok(())
 }
 
 fn mark_module_state(module: &str, flag_file: &str, create_or_delete: bool) -> Result<()> {
     let module_state_file = Path::new(defs::MODULE_DIR).join(module).join(flag_file);
-    if create_or_delete {
-        ensure_file_exists(module_state_file)
-    } else {
-        if module_state_file.exists() {
-            std::fs::remove_file(module_state_file)?;
+    // read-only after the boot with READONLY_MODULES_FLAG_PATH, or mounted so by the cli
+    mount::with_writable(defs::MODULE_DIR, || {
+        set_flag(module_state_file, create_or_delete)
+    })?;
+    // the live image is changed
+    drop_image_checksum(&get_module_img());
+    Ok(())
+}
+
+/// read module.prop of the module at `module_path` into a map
+pub fn read_module_prop<P: AsRef<Path>>(module_path: P) -> Result<HashMap<String, String>> {
+    let module_prop = module_path.as_ref().join("module.prop");
+    let content = std::fs::read(&module_prop)
+        .with_context(|| format!("Failed to read file: {}", module_prop.display()))?;
+    let mut module_prop_map: HashMap<String, String> = HashMap::new();
+    PropertiesIter::new_with_encoding(Cursor::new(content), encoding::all::UTF_8)
+        .read_into(|k, v| {
+            module_prop_map.insert(k, v);
+        })
+        .with_context(|| format!("Failed to parse module.prop: {}", module_prop.display()))?;
+    Ok(module_prop_map)
+}
+
+fn get_priority_overrides() -> BTreeMap<String, i32> {
+    std::fs::read_to_string(defs::MODULE_PRIORITY_PATH)
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let (id, priority) = line.split_once(char::is_whitespace)?;
+            Some((id.to_string(), priority.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// the mount priority set by `ksud module reorder`, otherwise the one declared by `priority` in
+/// module.prop, 0 if absent or invalid
+pub fn get_module_priority<P: AsRef<Path>>(module_path: P) -> i32 {
+    let module_path = module_path.as_ref();
+    let id = module_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy();
+    if let Some(priority) = get_priority_overrides().get(id.as_ref()) {
+        return *priority;
+    }
+    read_module_prop(module_path)
+        .ok()
+        .and_then(|prop| prop.get("priority").and_then(|p| p.trim().parse().ok()))
+        .unwrap_or(0)
+}
+
+/// set the mount priority of module <id> over its own one, or drop the override if None
+pub fn reorder_module(id: &str, priority: Option<i32>) -> Result<()> {
+    let mut overrides = get_priority_overrides();
+    match priority {
+        Some(priority) => {
+            ensure!(
+                Path::new(defs::MODULE_DIR)
+                    .join(id)
+                    .join("module.prop")
+                    .exists(),
+                KsudError::ModuleNotFound(id.to_string())
+            );
+            overrides.insert(id.to_string(), priority);
+        }
+        None => {
+            if overrides.remove(id).is_none() {
+                println!("- {id} has no priority override");
+                return Ok(());
+            }
         }
-        Ok(())
     }
+    let content = overrides
+        .iter()
+        .map(|(id, priority)| format!("{id} {priority}\n"))
+        .collect::<String>();
+    std::fs::write(defs::MODULE_PRIORITY_PATH, content)
+        .with_context(|| format!("Failed to write {}", defs::MODULE_PRIORITY_PATH))?;
+    println!("- Reboot to take effect");
+    Ok(())
 }
 
 fn get_minimal_image_size(img: &str) -> Result<u64> {
@@ -109,7 +383,7 @@ fn get_minimal_image_size(img: &str) -> Result<u64> {
     Ok(result)
 }
 
-fn check_image(img: &str) -> Result<()> {
+pub fn check_image(img: &str) -> Result<()> {
     let result = Command::new("e2fsck")
         .args(["-yf", img])
         .stdout(Stdio::null())
@@ -123,78 +397,622 @@ fn check_image(img: &str) -> Result<()> {
     // https://man7.org/linux/man-pages/man8/e2fsck.8.html
     ensure!(
         code == Some(0) || code == Some(1),
-        "Failed to check image, e2fsck exit code: {}",
-        code.unwrap_or(-1)
+        KsudError::ImageCorrupt(img.to_string(), code.unwrap_or(-1))
     );
     Ok(())
 }
 
+/// check the image before it is mounted on boot, e2fsck -p only fixes what is safe without
+/// asking. fail only if it leaves errors, exit code 4 and above, a missing or killed e2fsck
+/// is no reason to give up the image
+pub fn preen_image(img: &str) -> Result<()> {
+    let result = Command::new("e2fsck")
+        .args(["-p", img])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status();
+    let code = match result {
+        Ok(status) => status.code(),
+        Err(e) => {
+            warn!("exec e2fsck {img} failed: {e}, mount it unchecked");
+            return Ok(());
+        }
+    };
+    match code {
+        // 1: errors corrected, 2: the same and a reboot is advised, which is for a mounted fs
+        Some(0..=3) => Ok(()),
+        Some(code) => bail!(KsudError::ImageCorrupt(img.to_string(), code)),
+        None => {
+            warn!("e2fsck {img} is killed, mount it unchecked");
+            Ok(())
+        }
+    }
+}
+
 fn grow_image_size(img: &str, extra_size: u64) -> Result<()> {
     let minimal_size = get_minimal_image_size(img)?; // the minimal size is in KB
     let target_size = minimal_size * 1024 + extra_size;
 
-    // check image
-    check_image(img)?;
-
     println!(
         "- Target image size: {}",
         humansize::format_size(target_size, humansize::DECIMAL)
     );
-    let target_size = target_size / 1024 + 1024;
 
-    let result = Command::new("resize2fs")
-        .args([img, &format!("{target_size}K")])
+    mount::resize_ext4(img, target_size)
+}
+
+/// grow the ext4 image mounted at dir if it is almost full, the modules may write into their
+/// dirs at runtime. a full image wedges the modules which do, so if it can't be grown it is
+/// umounted and false is returned, the boot goes on without modules
+pub fn ensure_image_space(img: &str, dir: &str) -> bool {
+    let Ok(free) = get_free_space(dir) else {
+        return true;
+    };
+    if free >= IMG_LOW_SPACE_THRESHOLD {
+        return true;
+    }
+    warn!("module image: {img} has only {free} bytes free, grow it");
+    let grown = mount::umount_dir(dir).and_then(|_| {
+        let current_size = std::fs::metadata(img)?.len();
+        mount::resize_ext4(img, current_size + IMG_SHRINK_SLACK)
+    });
+    if let Err(e) = grown {
+        warn!("grow module image: {img} failed: {e}");
+    }
+    if !mount::is_mounted(dir.trim_end_matches('/')) {
+        if let Err(e) = mount::AutoMountExt4::try_new(img, dir, false) {
+            warn!("mount module image: {img} failed: {e}");
+            return false;
+        }
+    }
+
+    let free = get_free_space(dir).unwrap_or(0);
+    if free < IMG_MIN_FREE_SPACE {
+        log::error!("module image: {img} is full, boot without modules");
+        let _ = mount::umount_dir(dir);
+        return false;
+    }
+    true
+}
+
+/// shrink the image to fit its contents with some free space, the image must not be mounted
+pub fn shrink_image(img: &str) -> Result<()> {
+    let minimal_size = get_minimal_image_size(img)?; // the minimal size is in KB
+    let target_size = minimal_size * 1024 + IMG_SHRINK_SLACK;
+    let current_size = std::fs::metadata(img)?.len();
+    // don't bother if we can't save much
+    if current_size <= target_size + IMG_SHRINK_SLACK {
+        info!("image: {img} size: {current_size}, no need to shrink");
+        return Ok(());
+    }
+
+    info!("shrink image: {img} from {current_size} to {target_size}");
+    mount::resize_ext4(img, target_size)?;
+    // resize2fs doesn't truncate the image file when shrinking
+    let new_size = get_ext4_size(img)?;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(img)?
+        .set_len(new_size)?;
+    Ok(())
+}
+
+// shrink an unmounted image to its contents, a mounted one can't be shrunk, its free blocks are
+// given back by discarding them instead
+fn trim_image(img: &str) -> Result<()> {
+    if mount::get_image_type(img)? != mount::ImageType::Ext4 {
+        println!("- {img}: not ext4, skip");
+        return Ok(());
+    }
+    let before = std::fs::metadata(img)?.len();
+    if let Some(mnt) = mount::get_image_mount_point(img) {
+        let trimmed = mount::fstrim(&mnt)?;
+        // the discarded blocks read as zeros, the content differs from the checksum
+        drop_image_checksum(img);
+        println!(
+            "- {img}: mounted on {mnt}, {} discarded",
+            humansize::format_size(trimmed, humansize::DECIMAL)
+        );
+        return Ok(());
+    }
+    ensure!(
+        !mount::is_image_mounted(img),
+        "{img} is mounted in another mount namespace only, it can't be trimmed here"
+    );
+    // never trim a damaged image, and keep the checksum of a finalized one valid
+    let has_checksum = verify_image_checksum(img)?;
+    shrink_image(img)?;
+    if has_checksum {
+        save_image_checksum(img)?;
+    }
+    let after = std::fs::metadata(img)?.len();
+    println!(
+        "- {img}: {} -> {}",
+        humansize::format_size(before, humansize::DECIMAL),
+        humansize::format_size(after, humansize::DECIMAL)
+    );
+    Ok(())
+}
+
+/// shrink the ext4 module images to their contents and some free space, or discard the free
+/// blocks of the mounted ones. a failed image doesn't stop the others
+pub fn trim_images() -> Result<()> {
+    let mut failed = false;
+    let module_img = get_module_img();
+    for img in [module_img.as_str(), defs::MODULE_UPDATE_IMG] {
+        if !Path::new(img).exists() {
+            continue;
+        }
+        if let Err(e) = trim_image(img) {
+            failed = true;
+            warn!("trim {img} failed: {e:#}");
+            println!("- {img}: {e:#}");
+        }
+    }
+    ensure!(!failed, "module image trim failed");
+    Ok(())
+}
+
+pub fn get_checksum_path(img: &str) -> String {
+    format!("{img}{}", defs::IMG_CHECKSUM_SUFFIX)
+}
+
+fn get_image_checksum(img: &str) -> Result<String> {
+    use sha2::{Digest, Sha256};
+    let mut file = File::open(img).with_context(|| format!("Failed to open {img}"))?;
+    let mut hasher = Sha256::new();
+    // streamed, the image is not read into the memory at once
+    std::io::copy(&mut file, &mut hasher).with_context(|| format!("Failed to hash {img}"))?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+/// if dst has the same size and sha256 as src, after it is synced to the disk
+pub fn is_same_image(src: &str, dst: &str) -> Result<bool> {
+    File::open(dst)?.sync_all()?;
+    if std::fs::metadata(src)?.len() != std::fs::metadata(dst)?.len() {
+        return Ok(false);
+    }
+    Ok(get_image_checksum(src)? == get_image_checksum(dst)?)
+}
+
+/// write the sha256 of the finalized image next to it
+pub fn save_image_checksum(img: &str) -> Result<()> {
+    let checksum = get_image_checksum(img)?;
+    let path = get_checksum_path(img);
+    std::fs::write(&path, format!("{checksum}\n"))
+        .with_context(|| format!("Failed to write {path}"))
+}
+
+pub fn get_format_path(img: &str) -> String {
+    format!("{img}{}", defs::IMG_FORMAT_SUFFIX)
+}
+
+/// stamp the image with the format of this ksud
+pub fn save_image_format(img: &str) -> Result<()> {
+    let path = get_format_path(img);
+    std::fs::write(&path, format!("{}\n", defs::IMG_FORMAT_VERSION))
+        .with_context(|| format!("Failed to write {path}"))
+}
+
+/// the format of the image from its sidecar, 0 without it, None if the sidecar can't be read
+pub fn get_image_format(img: &str) -> Option<u32> {
+    match std::fs::read_to_string(get_format_path(img)) {
+        Ok(content) => content.trim().parse().ok(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Some(0),
+        Err(_) => None,
+    }
+}
+
+/// check the image against its sha256 sidecar, return false if there is no sidecar
+pub fn verify_image_checksum(img: &str) -> Result<bool> {
+    let path = get_checksum_path(img);
+    let Ok(expected) = std::fs::read_to_string(&path) else {
+        return Ok(false);
+    };
+    let actual = get_image_checksum(img)?;
+    ensure!(
+        expected.trim() == actual,
+        KsudError::ChecksumMismatch(img.to_string(), expected.trim().to_string(), actual)
+    );
+    Ok(true)
+}
+
+// the image is mounted read-write, its checksum will not be valid anymore
+pub fn drop_image_checksum(img: &str) {
+    let path = get_checksum_path(img);
+    if Path::new(&path).exists() {
+        info!("{img} is writable, remove {path}");
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// verify the module images which have a checksum
+pub fn verify_images() -> Result<()> {
+    let mut failed = false;
+    let module_img = get_module_img();
+    for img in [module_img.as_str(), defs::MODULE_UPDATE_IMG] {
+        if !Path::new(img).exists() {
+            continue;
+        }
+        match verify_image_checksum(img) {
+            Ok(true) => println!("- {img}: ok"),
+            Ok(false) => println!("- {img}: no checksum, it has been mounted read-write"),
+            Err(e) => {
+                failed = true;
+                println!("- {img}: {e}");
+            }
+        }
+    }
+    ensure!(!failed, "module image verification failed");
+    Ok(())
+}
+
+fn create_image(img: &str, size: u64) -> Result<()> {
+    File::create(img)
+        .context("Failed to create ext4 image file")?
+        .set_len(size)
+        .context("Failed to extend ext4 image")?;
+
+    // format the img to ext4 filesystem
+    let result = Command::new("mkfs.ext4")
+        .arg(img)
         .stdout(Stdio::null())
+        .output()?;
+    ensure!(
+        result.status.success(),
+        "Failed to format ext4 image: {}",
+        String::from_utf8(result.stderr).unwrap()
+    );
+
+    check_image(img)
+}
+
+// copy the module image to the tmp image, which is always ext4 so that it can be modified.
+// an erofs image is unpacked with `extra_size` free space, return if it is erofs.
+fn copy_to_tmp_image(img: &Path, tmp_img: &str, extra_size: u64) -> Result<bool> {
+    let img_str = img.to_string_lossy();
+    if mount::get_image_type(&img_str)? != mount::ImageType::Erofs {
+        std::fs::copy(img, tmp_img)
+            .with_context(|| format!("Failed to copy {} to {tmp_img}", img.display()))?;
+        return Ok(false);
+    }
+
+    info!("unpack erofs image: {} to {tmp_img}", img.display());
+    let size = std::fs::metadata(img)?.len() * EROFS_UNPACK_RATIO + extra_size;
+    create_image(tmp_img, size + IMG_SHRINK_SLACK)?;
+
+    ensure_clean_dir(defs::EROFS_STAGING_DIR)?;
+    ensure_clean_dir(defs::MODULE_UPDATE_TMP_DIR)?;
+    let _src = mount::AutoMountErofs::try_new(&img_str, defs::EROFS_STAGING_DIR, true)?;
+    let _dst = mount::AutoMountExt4::try_new(tmp_img, defs::MODULE_UPDATE_TMP_DIR, true)?;
+    let result = Command::new("cp")
+        .arg("-a")
+        .arg(format!("{}.", defs::EROFS_STAGING_DIR))
+        .arg(defs::MODULE_UPDATE_TMP_DIR)
         .status()
-        .with_context(|| format!("Failed to exec resize2fs {img}"))?;
-    ensure!(result.success(), "Failed to resize2fs: {}", result);
+        .context("Failed to exec cp")?;
+    ensure!(result.success(), "Failed to unpack erofs image: {}", result);
+    Ok(true)
+}
 
-    check_image(img)?;
+/// replace the ext4 image `img` with an erofs image of the same contents
+fn rebuild_erofs(img: &str) -> Result<()> {
+    let erofs_img = format!("{img}.erofs");
+    ensure_clean_dir(defs::EROFS_STAGING_DIR)?;
+    {
+        let _mount = mount::AutoMountExt4::try_new(img, defs::EROFS_STAGING_DIR, true)?;
+        let result = Command::new("mkfs.erofs")
+            .args(["-zlz4hc", &erofs_img, defs::EROFS_STAGING_DIR])
+            .stdout(Stdio::null())
+            .output()
+            .context("Failed to exec mkfs.erofs")?;
+        ensure!(
+            result.status.success(),
+            "Failed to build erofs image: {}",
+            String::from_utf8_lossy(&result.stderr)
+        );
+    }
+    info!("rebuilt {img} as erofs");
+    std::fs::rename(&erofs_img, img)
+        .with_context(|| format!("Failed to rename {erofs_img} to {img}"))
+}
+
+// block count * block size of the ext4 image
+fn get_ext4_size(img: &str) -> Result<u64> {
+    let output = Command::new("dumpe2fs")
+        .args(["-h", img])
+        .stderr(Stdio::null())
+        .output()
+        .with_context(|| format!("Failed to exec dumpe2fs {img}"))?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    let field = |name: &str| -> Result<u64> {
+        let regex = regex::Regex::new(&format!(r"(?m)^{name}:\s+(\d+)"))?;
+        let result = regex
+            .captures(&output)
+            .ok_or(anyhow::anyhow!("{name} not found in dumpe2fs output"))?;
+        Ok(u64::from_str(&result[1])?)
+    };
+    Ok(field("Block count")? * field("Block size")?)
+}
+
+/// the module ids in the blocklist
+pub fn get_blocklist() -> HashSet<String> {
+    read_id_list(defs::MODULE_BLOCKLIST_PATH)
+}
+
+// one module id per line, # starts a comment
+fn read_id_list(path: &str) -> HashSet<String> {
+    std::fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(ToString::to_string)
+        .collect()
+}
+
+/// if the module at path has the disable flag, or is disabled by safe mode
+pub fn is_disabled(path: &Path) -> bool {
+    path.join(defs::DISABLE_FILE_NAME).exists()
+        || path.file_name().map_or(false, |id| {
+            read_id_list(defs::MODULE_SAFE_MODE_DISABLED_PATH)
+                .contains(id.to_string_lossy().as_ref())
+        })
+}
+
+/// if the module at path is in the blocklist
+pub fn is_blocked(blocklist: &HashSet<String>, path: &Path) -> bool {
+    path.file_name().map_or(false, |id| {
+        blocklist.contains(id.to_string_lossy().as_ref())
+    })
+}
+
+/// why the module can't run on this Android version, per `minApi` and `maxApi` in module.prop
+pub fn get_incompatible_reason(module: &Path) -> Option<String> {
+    let api = getprop("ro.build.version.sdk")?
+        .trim()
+        .parse::<u32>()
+        .ok()?;
+    let prop = read_module_prop(module).ok()?;
+    let get = |key: &str| prop.get(key).and_then(|v| v.trim().parse::<u32>().ok());
+    if let Some(min) = get("minApi").filter(|min| api < *min) {
+        return Some(format!("requires API {min} or higher, the device is {api}"));
+    }
+    if let Some(max) = get("maxApi").filter(|max| api > *max) {
+        return Some(format!("requires API {max} or lower, the device is {api}"));
+    }
+    None
+}
+
+fn write_blocklist(blocklist: &HashSet<String>) -> Result<()> {
+    write_id_list(defs::MODULE_BLOCKLIST_PATH, blocklist)
+}
+
+fn write_id_list(path: &str, ids: &HashSet<String>) -> Result<()> {
+    let mut ids = ids.iter().cloned().collect::<Vec<_>>();
+    ids.sort();
+    let content = ids.iter().map(|id| format!("{id}\n")).collect::<String>();
+    std::fs::write(path, content).with_context(|| format!("Failed to write {path}"))
+}
+
+/// keep module <id> enabled in safe mode. `confirm` must be set, as a module which breaks the
+/// boot and is pinned makes safe mode useless
+pub fn pin_module(id: &str, confirm: bool) -> Result<()> {
+    ensure!(
+        Path::new(defs::MODULE_DIR)
+            .join(id)
+            .join("module.prop")
+            .exists(),
+        KsudError::ModuleNotFound(id.to_string())
+    );
+    ensure!(
+        confirm,
+        "safe mode will not disable a pinned {id}, if it breaks the boot safe mode can't recover \
+         it. pass --confirm to pin it anyway"
+    );
+    let mut pinlist = read_id_list(defs::MODULE_PINLIST_PATH);
+    if !pinlist.insert(id.to_string()) {
+        println!("- {id} is already pinned");
+        return Ok(());
+    }
+    write_id_list(defs::MODULE_PINLIST_PATH, &pinlist)?;
+    warn!("{id} is pinned, safe mode will not disable it");
+    println!("- {id} is pinned, safe mode will not disable it");
+    Ok(())
+}
+
+pub fn unpin_module(id: &str) -> Result<()> {
+    let mut pinlist = read_id_list(defs::MODULE_PINLIST_PATH);
+    if !pinlist.remove(id) {
+        println!("- {id} is not pinned");
+        return Ok(());
+    }
+    write_id_list(defs::MODULE_PINLIST_PATH, &pinlist)?;
+    println!("- {id} is not pinned anymore");
+    Ok(())
+}
+
+pub fn block_module(id: &str) -> Result<()> {
+    let mut blocklist = get_blocklist();
+    if !blocklist.insert(id.to_string()) {
+        println!("- {id} is already blocked");
+        return Ok(());
+    }
+    write_blocklist(&blocklist)?;
+    println!("- Reboot to take effect");
+    Ok(())
+}
 
+pub fn unblock_module(id: &str) -> Result<()> {
+    let mut blocklist = get_blocklist();
+    if !blocklist.remove(id) {
+        println!("- {id} is not blocked");
+        return Ok(());
+    }
+    write_blocklist(&blocklist)?;
+    println!("- Reboot to take effect");
     Ok(())
 }
 
+/// the *.rule files in a sepolicy.d dir, sorted
+pub fn get_sepolicy_d_rules(dir: &Path) -> Vec<PathBuf> {
+    let Ok(dir) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut rules = dir
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && path.extension().map_or(false, |ext| ext == "rule"))
+        .collect::<Vec<_>>();
+    rules.sort();
+    rules
+}
+
+// sepolicy.rule, then sepolicy.d/*.rule in order
+fn get_sepolicy_rule_files(module: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let rule_file = module.join("sepolicy.rule");
+    if rule_file.exists() {
+        files.push(rule_file);
+    }
+    files.extend(get_sepolicy_d_rules(&module.join("sepolicy.d")));
+    files
+}
+
 pub fn load_sepolicy_rule() -> Result<()> {
     let modules_dir = Path::new(defs::MODULE_DIR);
     let dir = std::fs::read_dir(modules_dir)?;
+    let blocklist = get_blocklist();
+    // module id -> the rules it loaded, for `ksud sepolicy status`
+    let mut report = serde_json::Map::new();
     for entry in dir.flatten() {
         let path = entry.path();
-        let disabled = path.join(defs::DISABLE_FILE_NAME);
-        if disabled.exists() {
+        if is_disabled(&path) {
             info!("{} is disabled, skip", path.display());
             continue;
         }
-
-        let rule_file = path.join("sepolicy.rule");
-        if !rule_file.exists() {
+        if is_blocked(&blocklist, &path) {
+            info!("{} is blocked, skip", path.display());
+            continue;
+        }
+        if let Some(reason) = get_incompatible_reason(&path) {
+            info!("{} is incompatible: {reason}, skip", path.display());
             continue;
         }
-        info!("load policy: {}", &rule_file.display());
 
-        if sepolicy::apply_file(&rule_file).is_err() {
-            warn!("Failed to load sepolicy.rule for {}", &rule_file.display());
+        let mut loaded = Vec::new();
+        for rule_file in get_sepolicy_rule_files(&path) {
+            info!("load policy: {}", &rule_file.display());
+            let file = rule_file.display().to_string();
+            match sepolicy::apply_file_lenient(&rule_file) {
+                Ok(rules) => {
+                    let failed = rules.iter().filter(|(_, error)| error.is_some()).count();
+                    if failed > 0 {
+                        warn!("{failed} rules of {file} failed");
+                    }
+                    loaded.extend(rules.into_iter().map(|(rule, error)| {
+                        serde_json::json!({ "file": file, "rule": rule, "error": error })
+                    }));
+                }
+                Err(e) => {
+                    warn!("Failed to load {file}: {e}");
+                    loaded.push(serde_json::json!({
+                        "file": file,
+                        "rule": null,
+                        "error": e.to_string(),
+                    }));
+                }
+            }
+        }
+        if !loaded.is_empty() {
+            let id = path.file_name().unwrap_or_default().to_string_lossy();
+            report.insert(id.to_string(), loaded.into());
         }
     }
 
+    let report = serde_json::to_string_pretty(&serde_json::Value::Object(report))?;
+    if let Err(e) = std::fs::write(defs::SEPOLICY_REPORT_PATH, report) {
+        warn!("write {} failed: {e}", defs::SEPOLICY_REPORT_PATH);
+    }
     Ok(())
 }
 
-fn exec_script<T: AsRef<Path>>(path: T, wait: bool) -> Result<()> {
-    info!("exec {}", path.as_ref().display());
+// the rules of the module are not loaded on the next boot
+fn drop_sepolicy_report(id: &str) -> Result<()> {
+    let Ok(content) = std::fs::read_to_string(defs::SEPOLICY_REPORT_PATH) else {
+        return Ok(());
+    };
+    let mut report: serde_json::Map<String, serde_json::Value> = serde_json::from_str(&content)?;
+    if report.remove(id).is_some() {
+        println!("- sepolicy rules of {id} are unloaded on reboot");
+        std::fs::write(
+            defs::SEPOLICY_REPORT_PATH,
+            serde_json::to_string_pretty(&report)?,
+        )?;
+    }
+    Ok(())
+}
+
+/// print the sepolicy rules each module loaded in this boot, the failed ones are marked
+pub fn print_sepolicy_status() -> Result<()> {
+    let content = std::fs::read_to_string(defs::SEPOLICY_REPORT_PATH)
+        .with_context(|| "No sepolicy report, module rules are not loaded in this boot")?;
+    let report: serde_json::Value = serde_json::from_str(&content)?;
+    let Some(modules) = report.as_object() else {
+        bail!("invalid sepolicy report: {}", defs::SEPOLICY_REPORT_PATH);
+    };
+    if modules.is_empty() {
+        println!("- No module sepolicy rules loaded");
+    }
+    for (id, rules) in modules {
+        let rules = rules.as_array().cloned().unwrap_or_default();
+        let failed = rules.iter().filter(|r| !r["error"].is_null()).count();
+        println!("{id}: {} rules, {failed} failed", rules.len());
+        for rule in &rules {
+            let text = rule["rule"]
+                .as_str()
+                .map_or_else(|| rule["file"].to_string(), ToString::to_string);
+            match rule["error"].as_str() {
+                Some(error) => println!("  FAILED {text}: {error}"),
+                None => println!("  ok     {text}"),
+            }
+        }
+    }
+    Ok(())
+}
+
+// `umask` in module.prop, in octal, e.g. umask=0 for the modules which rely on it
+fn get_module_umask(module_dir: &Path) -> Option<u32> {
+    let prop = read_module_prop(module_dir).ok()?;
+    u32::from_str_radix(prop.get("umask")?.trim(), 8).ok()
+}
 
-    let mut command = &mut Command::new(assets::BUSYBOX_PATH);
+// the environment of module scripts:
+// KSU=true, KSU_VER, KSU_VER_CODE, KSU_KERNEL_VER_CODE, BOOTMODE=true, KSU_UMASK
+// which is the umask the script runs with, and MODDIR which is the module's own dir
+// and KSU_RUNONCE_MARKER if the script belongs to a module
+fn script_command<T: AsRef<Path>>(path: T) -> Command {
+    let mask = get_module_dir_of(path.as_ref())
+        .and_then(get_module_umask)
+        .unwrap_or_else(get_script_umask);
+    let mut command = Command::new(assets::BUSYBOX_PATH);
     #[cfg(unix)]
     {
-        command = command.process_group(0);
-        command = unsafe {
-            command.pre_exec(|| {
+        command.process_group(0);
+        unsafe {
+            command.pre_exec(move || {
                 // ignore the error?
                 switch_cgroups();
+                umask(mask);
                 Ok(())
-            })
-        };
+            });
+        }
     }
-    command = command
+    command
         .current_dir(path.as_ref().parent().unwrap())
         .arg("sh")
         .arg(path.as_ref())
@@ -210,115 +1028,1274 @@ fn exec_script<T: AsRef<Path>>(path: T, wait: bool) -> Result<()> {
                 env_var("PATH").unwrap(),
                 defs::BINARY_DIR.trim_end_matches('/')
             ),
-        );
+        )
+        .env("KSU_UMASK", format!("{mask:03o}"))
+        .env("BOOTMODE", "true");
+    if let Some(module_dir) = get_module_dir_of(path.as_ref()) {
+        command.env("MODDIR", module_dir);
+        if let Some(name) = path.as_ref().file_name() {
+            let markers = get_runonce_dir(module_dir);
+            // so that a plain `touch $KSU_RUNONCE_MARKER` works
+            if is_runonce(module_dir, &name.to_string_lossy()) {
+                let _ = std::fs::create_dir_all(&markers);
+            }
+            command.env("KSU_RUNONCE_MARKER", markers.join(name));
+        }
+        // the output of module scripts goes to the module's log
+        if let Some(mut log) = open_module_log(module_dir) {
+            let _ = writeln!(
+                log,
+                "[{}] exec {}",
+                timestamp_secs(),
+                path.as_ref().display()
+            );
+            if let Ok(stderr) = log.try_clone() {
+                command.stdout(log).stderr(stderr);
+            }
+        }
+    }
+    command
+}
 
-    let result = if wait {
-        command.status().map(|_| ())
+fn timestamp_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn get_module_log_dir(module: &Path) -> PathBuf {
+    Path::new(defs::MODULE_LOG_DIR).join(module.file_name().unwrap_or_default())
+}
+
+// last_run.log for the current boot, last_run.log.<index>.gz for the previous ones
+fn module_log_path(module_dir: &Path, index: usize) -> PathBuf {
+    let dir = get_module_log_dir(module_dir);
+    if index == 0 {
+        dir.join(defs::MODULE_LOG_FILE_NAME)
     } else {
-        command.spawn().map(|_| ())
-    };
-    result.map_err(|err| anyhow!("Failed to exec {}: {}", path.as_ref().display(), err))
+        dir.join(format!("{}.{index}.gz", defs::MODULE_LOG_FILE_NAME))
+    }
 }
 
-/// execute every modules' post-fs-data.sh
-pub fn exec_post_fs_data() -> Result<()> {
-    let modules_dir = Path::new(defs::MODULE_DIR);
-    let dir = std::fs::read_dir(modules_dir)?;
+// the log of a boot, decompressed
+fn read_module_log(path: &Path) -> Option<Vec<u8>> {
+    let content = std::fs::read(path).ok()?;
+    if path.extension().map_or(true, |ext| ext != "gz") {
+        return Some(content);
+    }
+    let mut decoded = Vec::new();
+    flate2::read::GzDecoder::new(content.as_slice())
+        .read_to_end(&mut decoded)
+        .ok()?;
+    Some(decoded)
+}
+
+fn write_gzip(path: &Path, content: &[u8]) -> Result<()> {
+    let mut encoder =
+        flate2::write::GzEncoder::new(File::create(path)?, flate2::Compression::best());
+    encoder.write_all(content)?;
+    encoder.finish()?;
+    Ok(())
+}
+
+fn open_module_log(module_dir: &Path) -> Option<File> {
+    let path = module_log_path(module_dir, 0);
+    let result = std::fs::create_dir_all(get_module_log_dir(module_dir))
+        .and_then(|()| OpenOptions::new().create(true).append(true).open(&path));
+    match result {
+        Ok(log) => Some(log),
+        Err(e) => {
+            warn!("open {} failed: {e}", path.display());
+            None
+        }
+    }
+}
+
+// record how the script exited in the module's log
+fn log_script_exit(path: &Path, status: &str) {
+    info!("{} exited: {}", path.display(), status);
+    if let Some(mut log) = get_module_dir_of(path).and_then(open_module_log) {
+        let _ = writeln!(log, "[{}] {}: {}", timestamp_secs(), path.display(), status);
+    }
+}
+
+/// keep the logs of the last boots, it should be called once every boot before the scripts run
+pub fn rotate_module_logs() {
+    let Ok(dir) = std::fs::read_dir(defs::MODULE_DIR) else {
+        return;
+    };
     for entry in dir.flatten() {
-        let path = entry.path();
-        let disabled = path.join(defs::DISABLE_FILE_NAME);
-        if disabled.exists() {
+        let module_dir = entry.path();
+        remove_legacy_module_logs(&module_dir);
+        let current = module_log_path(&module_dir, 0);
+        if !current.exists() {
+            continue;
+        }
+        for i in (1..defs::MODULE_LOG_HISTORY - 1).rev() {
+            let _ = std::fs::rename(
+                module_log_path(&module_dir, i),
+                module_log_path(&module_dir, i + 1),
+            );
+        }
+        // only the tail of a huge log is kept, so that it fits in the limit even uncompressed
+        if let Ok(content) = std::fs::read(&current) {
+            #[allow(clippy::cast_possible_truncation)]
+            let max = defs::MODULE_LOG_MAX_SIZE as usize;
+            let tail = &content[content.len().saturating_sub(max)..];
+            if let Err(e) = write_gzip(&module_log_path(&module_dir, 1), tail) {
+                warn!("compress log of {} failed: {e}", module_dir.display());
+            }
+        }
+        let _ = std::fs::remove_file(current);
+        evict_module_logs(&module_dir);
+    }
+}
+
+// older versions kept the logs in the module dir, plain or gzipped
+fn remove_legacy_module_logs(module_dir: &Path) {
+    let _ = std::fs::remove_file(module_dir.join(defs::MODULE_LOG_FILE_NAME));
+    for i in 1..defs::MODULE_LOG_HISTORY {
+        for name in [
+            format!("{}.{i}", defs::MODULE_LOG_FILE_NAME),
+            format!("{}.{i}.gz", defs::MODULE_LOG_FILE_NAME),
+        ] {
+            let _ = std::fs::remove_file(module_dir.join(name));
+        }
+    }
+}
+
+// remove the oldest logs until the compressed ones fit in MODULE_LOG_MAX_SIZE
+fn evict_module_logs(module_dir: &Path) {
+    let size = |i| std::fs::metadata(module_log_path(module_dir, i)).map_or(0, |m| m.len());
+    let mut total = (1..defs::MODULE_LOG_HISTORY).map(size).sum::<u64>();
+    for i in (2..defs::MODULE_LOG_HISTORY).rev() {
+        if total <= defs::MODULE_LOG_MAX_SIZE {
+            break;
+        }
+        total -= size(i);
+        let _ = std::fs::remove_file(module_log_path(module_dir, i));
+    }
+}
+
+/// the log of the current boot of each module which has one, by module id
+pub fn get_last_run_logs() -> Vec<(String, Vec<u8>)> {
+    let Ok(dir) = std::fs::read_dir(defs::MODULE_DIR) else {
+        return Vec::new();
+    };
+    let mut logs = dir
+        .flatten()
+        .filter_map(|entry| {
+            let log = read_module_log(&module_log_path(&entry.path(), 0))?;
+            Some((entry.file_name().to_string_lossy().to_string(), log))
+        })
+        .collect::<Vec<_>>();
+    logs.sort();
+    logs
+}
+
+pub fn print_module_logs(id: &str) -> Result<()> {
+    let module_dir = Path::new(defs::MODULE_DIR).join(id);
+    ensure!(
+        module_dir.exists(),
+        KsudError::ModuleNotFound(id.to_string())
+    );
+    // the oldest first
+    for i in (0..defs::MODULE_LOG_HISTORY).rev() {
+        let log = module_log_path(&module_dir, i);
+        let Some(content) = read_module_log(&log) else {
+            continue;
+        };
+        println!("==> {} <==", log.display());
+        print!("{}", String::from_utf8_lossy(&content));
+    }
+    Ok(())
+}
+
+// $MODULE_DIR/<id>/xxx.sh -> $MODULE_DIR/<id>, the same for $MODULE_UPDATE_TMP_DIR
+fn get_module_dir_of(script: &Path) -> Option<&Path> {
+    let module_dir = script.parent()?;
+    let parent = module_dir.parent()?;
+    (parent == Path::new(defs::MODULE_DIR) || parent == Path::new(defs::MODULE_UPDATE_TMP_DIR))
+        .then_some(module_dir)
+}
+
+fn exec_script<T: AsRef<Path>>(path: T, wait: bool) -> Result<()> {
+    info!("exec {}", path.as_ref().display());
+
+    let mut command = script_command(&path);
+    let result = if wait {
+        command
+            .status()
+            .map(|status| log_script_exit(path.as_ref(), &status.to_string()))
+    } else {
+        spawn_detached(&mut command)
+    };
+    result.map_err(|err| anyhow!("Failed to exec {}: {}", path.as_ref().display(), err))
+}
+
+// fork once more before exec and let the middle process exit at once, the script is adopted by
+// init which reaps it, so it never becomes a zombie of ksud
+pub fn spawn_detached(command: &mut Command) -> std::io::Result<()> {
+    #[cfg(unix)]
+    unsafe {
+        command.pre_exec(|| match libc::fork() {
+            -1 => Err(std::io::Error::last_os_error()),
+            0 => Ok(()),
+            _ => libc::_exit(0),
+        });
+    }
+    command.spawn()?.wait().map(|_| ())
+}
+
+// spawn the script and wait for it at most `timeout`, the whole process group is killed if it expires
+fn exec_script_with_timeout<T: AsRef<Path>>(path: T, timeout: Duration) -> Result<()> {
+    info!(
+        "exec {} with timeout {:?}",
+        path.as_ref().display(),
+        timeout
+    );
+
+    let mut child = script_command(&path)
+        .spawn()
+        .map_err(|err| anyhow!("Failed to exec {}: {}", path.as_ref().display(), err))?;
+
+    let start = Instant::now();
+    loop {
+        if let Some(status) = child.try_wait()? {
+            log_script_exit(path.as_ref(), &status.to_string());
+            return Ok(());
+        }
+        if start.elapsed() >= timeout {
+            // the script is the leader of its own process group, kill them all
+            kill_process_group(child.id());
+            let _ = child.wait();
+            log_script_exit(
+                path.as_ref(),
+                &format!("killed after {}s timeout", timeout.as_secs()),
+            );
+            bail!(
+                "{} timed out after {}s, killed",
+                path.as_ref().display(),
+                timeout.as_secs()
+            );
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+fn get_module_dependencies(module_prop: &HashMap<String, String>) -> Vec<String> {
+    module_prop
+        .get("dependencies")
+        .map(|deps| {
+            deps.split(',')
+                .map(str::trim)
+                .filter(|dep| !dep.is_empty())
+                .map(ToString::to_string)
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// order `modules` so that the dependencies of a module come before it, otherwise the order is kept.
+/// a module is dropped with the reason if it sets `requireDependencies=true` and a dependency
+/// is missing or disabled. disabled modules are kept in place, the caller skips them.
+pub fn order_by_dependencies(modules: Vec<PathBuf>) -> (Vec<PathBuf>, Vec<(PathBuf, String)>) {
+    let id_of = |path: &Path| {
+        path.file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string()
+    };
+    let mut deps: HashMap<String, Vec<String>> = HashMap::new();
+    let mut required: HashMap<String, bool> = HashMap::new();
+    let mut enabled: HashMap<String, bool> = HashMap::new();
+    for module in &modules {
+        let id = id_of(module);
+        let prop = read_module_prop(module).unwrap_or_default();
+        required.insert(
+            id.clone(),
+            prop.get("requireDependencies")
+                .map_or(false, |v| v.trim() == "true"),
+        );
+        deps.insert(id.clone(), get_module_dependencies(&prop));
+        enabled.insert(id, !is_disabled(&module));
+    }
+
+    // dropping a module may break the modules which require it, repeat until nothing changes
+    let mut dropped: Vec<(PathBuf, String)> = Vec::new();
+    loop {
+        let mut changed = false;
+        for module in &modules {
+            let id = id_of(module);
+            if !enabled[&id] {
+                continue;
+            }
+            let missing = deps[&id]
+                .iter()
+                .filter(|dep| !enabled.get(*dep).copied().unwrap_or(false))
+                .cloned()
+                .collect::<Vec<_>>();
+            if missing.is_empty() {
+                continue;
+            }
+            if required[&id] {
+                warn!(
+                    "module: {id} requires missing or disabled: {}, skip it",
+                    missing.join(", ")
+                );
+                enabled.insert(id, false);
+                dropped.push((
+                    module.clone(),
+                    format!("missing dependencies: {}", missing.join(", ")),
+                ));
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    for module in &modules {
+        let id = id_of(module);
+        let missing = deps[&id]
+            .iter()
+            .filter(|dep| !enabled.get(*dep).copied().unwrap_or(false))
+            .cloned()
+            .collect::<Vec<_>>();
+        if enabled[&id] && !missing.is_empty() {
+            warn!(
+                "module: {id} depends on missing or disabled: {}",
+                missing.join(", ")
+            );
+        }
+    }
+
+    // depth first, a module is pushed after its dependencies. a cycle is broken where it is found
+    fn visit(
+        id: &str,
+        paths: &HashMap<String, PathBuf>,
+        deps: &HashMap<String, Vec<String>>,
+        visiting: &mut Vec<String>,
+        done: &mut Vec<String>,
+        ordered: &mut Vec<PathBuf>,
+    ) {
+        if done.iter().any(|d| d == id) {
+            return;
+        }
+        if visiting.iter().any(|v| v == id) {
+            warn!("dependency cycle: {} -> {id}", visiting.join(" -> "));
+            return;
+        }
+        let Some(path) = paths.get(id) else {
+            return;
+        };
+        visiting.push(id.to_string());
+        for dep in deps.get(id).into_iter().flatten() {
+            visit(dep, paths, deps, visiting, done, ordered);
+        }
+        visiting.pop();
+        done.push(id.to_string());
+        ordered.push(path.clone());
+    }
+
+    let dropped_ids = dropped.iter().map(|(m, _)| id_of(m)).collect::<Vec<_>>();
+    let paths = modules
+        .iter()
+        .map(|m| (id_of(m), m.clone()))
+        .filter(|(id, _)| !dropped_ids.contains(id))
+        .collect::<HashMap<_, _>>();
+    let mut ordered = Vec::new();
+    let mut visiting = Vec::new();
+    let mut done = Vec::new();
+    for module in &modules {
+        visit(
+            &id_of(module),
+            &paths,
+            &deps,
+            &mut visiting,
+            &mut done,
+            &mut ordered,
+        );
+    }
+    (ordered, dropped)
+}
+
+fn is_runonce(module: &Path, name: &str) -> bool {
+    read_module_prop(module)
+        .ok()
+        .and_then(|prop| {
+            prop.get("runonce")
+                .map(|scripts| scripts.split(',').any(|s| s.trim() == name))
+        })
+        .unwrap_or(false)
+}
+
+fn get_runonce_dir(module: &Path) -> PathBuf {
+    Path::new(defs::MODULE_RUNONCE_DIR).join(module.file_name().unwrap_or_default())
+}
+
+// the script <name> is declared as runonce and its marker exists
+fn is_runonce_done(module: &Path, name: &str) -> bool {
+    is_runonce(module, name) && get_runonce_dir(module).join(name).exists()
+}
+
+/// let the runonce scripts of module <id> run again in the next boot
+pub fn reset_runonce(id: &str) -> Result<()> {
+    let module_dir = Path::new(defs::MODULE_DIR).join(id);
+    ensure!(
+        module_dir.exists(),
+        KsudError::ModuleNotFound(id.to_string())
+    );
+    let markers = get_runonce_dir(&module_dir);
+    if markers.exists() {
+        remove_dir_all(&markers)
+            .with_context(|| format!("Failed to remove {}", markers.display()))?;
+    }
+    Ok(())
+}
+
+/// the file <name> of every enabled module, the ones of the dependencies come first
+pub fn get_module_files(name: &str) -> Result<Vec<PathBuf>> {
+    get_module_files_in(defs::MODULE_DIR, name)
+}
+
+/// the same as `get_module_files` for the modules in `modules_dir`
+pub fn get_module_files_in(modules_dir: &str, name: &str) -> Result<Vec<PathBuf>> {
+    let modules_dir = Path::new(modules_dir);
+    let mut modules = std::fs::read_dir(modules_dir)?
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir())
+        .collect::<Vec<_>>();
+    modules.sort();
+    let (modules, _) = order_by_dependencies(modules);
+
+    let blocklist = get_blocklist();
+    let mut scripts = Vec::new();
+    for path in modules {
+        if is_disabled(&path) {
             warn!("{} is disabled, skip", path.display());
             continue;
         }
+        if is_blocked(&blocklist, &path) {
+            warn!("{} is blocked, skip", path.display());
+            continue;
+        }
+        if let Some(reason) = get_incompatible_reason(&path) {
+            warn!("{} is incompatible: {reason}, skip", path.display());
+            continue;
+        }
+
+        let script = path.join(name);
+        if !script.exists() {
+            continue;
+        }
+        if is_runonce_done(&path, name) {
+            info!("{} has run once, skip", script.display());
+            continue;
+        }
+        scripts.push(script);
+    }
+    Ok(scripts)
+}
+
+/// the executable scripts in /data/adb/<dir>
+pub fn get_common_scripts(dir: &str) -> Result<Vec<PathBuf>> {
+    let script_dir = Path::new(defs::ADB_DIR).join(dir);
+    if !script_dir.exists() {
+        info!("{} not exists, skip", script_dir.display());
+        return Ok(Vec::new());
+    }
+
+    let dir = std::fs::read_dir(&script_dir)?;
+    let mut scripts = Vec::new();
+    for entry in dir.flatten() {
+        let path = entry.path();
+
+        if !is_executable(&path) {
+            warn!("{} is not executable, skip", path.display());
+            continue;
+        }
+
+        scripts.push(path);
+    }
+    Ok(scripts)
+}
+
+/// execute every modules' post-fs-data.sh
+pub fn exec_post_fs_data() -> Result<()> {
+    let timeout = Duration::from_secs(crate::config::get().post_fs_data_timeout);
+    for post_fs_data in get_module_files("post-fs-data.sh")? {
+        // a slow or broken module should not block the others
+        if let Err(e) = exec_script_with_timeout(&post_fs_data, timeout) {
+            warn!("post-fs-data failed: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// execute safemode.sh of ksu and every module, before the modules are disabled in safe mode
+pub fn exec_safe_mode_scripts() -> Result<()> {
+    let timeout = Duration::from_secs(crate::config::get().post_fs_data_timeout);
+    let mut scripts = Vec::new();
+    if Path::new(defs::SAFE_MODE_SCRIPT_PATH).exists() {
+        scripts.push(PathBuf::from(defs::SAFE_MODE_SCRIPT_PATH));
+    }
+    scripts.extend(get_module_files(defs::SAFE_MODE_SCRIPT_NAME)?);
+    for script in scripts {
+        // a hanging script must not keep the device in the boot
+        if let Err(e) = exec_script_with_timeout(&script, timeout) {
+            warn!("safemode script failed: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// execute PRE_MOUNT_SCRIPT_PATH of ksu, before the modules are mounted
+pub fn exec_pre_mount_script() -> Result<()> {
+    if !Path::new(defs::PRE_MOUNT_SCRIPT_PATH).exists() {
+        return Ok(());
+    }
+    info!("pre-mount: exec {}", defs::PRE_MOUNT_SCRIPT_PATH);
+    let timeout = Duration::from_secs(crate::config::get().post_fs_data_timeout);
+    exec_script_with_timeout(defs::PRE_MOUNT_SCRIPT_PATH, timeout)
+}
+
+/// execute every modules' post-mount.sh, after the modules are mounted
+pub fn exec_post_mount() -> Result<()> {
+    let timeout = Duration::from_secs(crate::config::get().post_fs_data_timeout);
+    for post_mount in get_module_files("post-mount.sh")? {
+        if let Err(e) = exec_script_with_timeout(&post_mount, timeout) {
+            warn!("post-mount failed: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// execute every modules' boot-completed.sh without waiting for them
+pub fn exec_boot_completed() -> Result<()> {
+    for boot_completed in get_module_files("boot-completed.sh")? {
+        if let Err(e) = exec_script(&boot_completed, false) {
+            warn!("{}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// execute the scripts in /data/adb/<dir>, they are killed after the timeout if `wait`,
+/// or detached otherwise
+pub fn exec_common_scripts(dir: &str, wait: bool) -> Result<()> {
+    let timeout = Duration::from_secs(crate::config::get().common_script_timeout);
+    for path in get_common_scripts(dir)? {
+        let result = if wait {
+            exec_script_with_timeout(&path, timeout)
+        } else {
+            exec_script(&path, false)
+        };
+        // a hanging or broken script should not block the others
+        if let Err(e) = result {
+            warn!("{}", e);
+        }
+    }
+
+    Ok(())
+}
+
+// spawn the scripts detached with `jobs` workers, only the launch is parallel and waited for,
+// the scripts keep running after it as before
+fn exec_scripts_concurrently(scripts: Vec<PathBuf>, jobs: usize) {
+    info!("spawn {} scripts with {jobs} workers", scripts.len());
+    let queue = Mutex::new(scripts.into_iter());
+    std::thread::scope(|s| {
+        for _ in 0..jobs.max(1) {
+            s.spawn(|| loop {
+                let Some(script) = queue.lock().unwrap().next() else {
+                    break;
+                };
+                if let Err(e) = exec_script(&script, false) {
+                    warn!("{}", e);
+                }
+            });
+        }
+    });
+}
+
+/// execute common service scripts and every modules' service.sh
+pub fn exec_services(jobs: usize) -> Result<()> {
+    let mut scripts = get_common_scripts("service.d").unwrap_or_else(|e| {
+        warn!("Failed to get common service scripts: {}", e);
+        Vec::new()
+    });
+    scripts.extend(get_module_files("service.sh")?);
+
+    exec_scripts_concurrently(scripts, jobs);
+
+    Ok(())
+}
+
+// the `key=value` assignments of system.prop
+fn read_prop_assignments(system_prop: &Path) -> Vec<(String, String)> {
+    let vars = get_template_vars();
+    std::fs::read_to_string(system_prop)
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.starts_with('#') && !line.starts_with('!') && !line.starts_with('+'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| {
+            let value = expand_template_vars(value.trim(), &vars, system_prop);
+            (key.trim().to_string(), value)
+        })
+        .collect()
+}
+
+/// the device facts a module may use as `${VAR}` in system.prop and the description of
+/// module.prop
+fn get_template_vars() -> HashMap<&'static str, String> {
+    let mut vars = HashMap::new();
+    // the same names as ARCH in the install script
+    let arch = match std::env::consts::ARCH {
+        "aarch64" => "arm64",
+        "arm" => "arm",
+        "x86_64" => "x64",
+        "x86" => "x86",
+        other => other,
+    };
+    vars.insert("ARCH", arch.to_string());
+    if let Some(api) = getprop("ro.build.version.sdk") {
+        vars.insert("API", api.trim().to_string());
+    }
+    if let Some(density) = getprop("ro.sf.lcd_density") {
+        vars.insert("DENSITY", density.trim().to_string());
+    }
+    vars.insert("KSU_VER", defs::VERSION_NAME.to_string());
+    vars.insert("KSU_VER_CODE", defs::VERSION_CODE.to_string());
+    vars.insert("KSU_KERNEL_VER_CODE", crate::ksu::get_version().to_string());
+    vars
+}
+
+// replace `${VAR}` with the value of VAR, an unknown one is kept as it is
+fn expand_template_vars(text: &str, vars: &HashMap<&'static str, String>, origin: &Path) -> String {
+    if !text.contains("${") {
+        return text.to_string();
+    }
+    let Ok(re) = regex::Regex::new(r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}") else {
+        return text.to_string();
+    };
+    re.replace_all(text, |caps: &regex::Captures| {
+        let name = &caps[1];
+        vars.get(name).cloned().unwrap_or_else(|| {
+            warn!("{}: unknown variable ${{{name}}}", origin.display());
+            caps[0].to_string()
+        })
+    })
+    .into_owned()
+}
+
+// the props each system.prop must not set, because a module with a different value wins them
+fn resolve_prop_conflicts(system_props: &[PathBuf]) -> HashMap<PathBuf, HashSet<String>> {
+    let strict = Path::new(defs::STRICT_PROPS_FLAG_PATH).exists();
+    // key -> (system.prop, value) in the order they are applied
+    let mut setters: BTreeMap<String, Vec<(&PathBuf, String)>> = BTreeMap::new();
+    for system_prop in system_props {
+        for (key, value) in read_prop_assignments(system_prop) {
+            setters.entry(key).or_default().push((system_prop, value));
+        }
+    }
+
+    let id_of = |system_prop: &Path| {
+        system_prop
+            .parent()
+            .and_then(Path::file_name)
+            .unwrap_or_default()
+            .to_string_lossy()
+            .to_string()
+    };
+    let mut skipped: HashMap<PathBuf, HashSet<String>> = HashMap::new();
+    for (key, setters) in setters {
+        if setters.iter().all(|(_, value)| *value == setters[0].1) {
+            continue;
+        }
+        let ids = setters
+            .iter()
+            .map(|(prop, value)| format!("{}={value}", id_of(prop)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let authorities = setters
+            .iter()
+            .filter(|(prop, _)| {
+                let module = prop.parent().unwrap_or_else(|| Path::new(""));
+                read_module_prop(module)
+                    .ok()
+                    .and_then(|p| p.get("authoritativeProps").cloned())
+                    .map_or(false, |keys| keys.split(',').any(|k| k.trim() == key))
+            })
+            .collect::<Vec<_>>();
+        let winner = match authorities.as_slice() {
+            [(prop, _)] => Some(*prop),
+            _ if strict => None,
+            // the last one applied wins
+            _ => setters.last().map(|(prop, _)| *prop),
+        };
+        match winner {
+            Some(winner) => warn!("prop: {key} is set by {ids}, {} wins", id_of(winner)),
+            None => warn!("prop: {key} is set by {ids} and none is authoritative, skip it"),
+        }
+        for (prop, _) in &setters {
+            if Some(*prop) != winner {
+                skipped
+                    .entry((*prop).clone())
+                    .or_default()
+                    .insert(key.clone());
+            }
+        }
+    }
+    skipped
+}
+
+/// apply system.prop of every enabled module, in the reverse order of the mount so that the
+/// module on top wins a prop which several modules set
+pub fn load_system_prop() -> Result<()> {
+    let mut system_props = get_module_files("system.prop")?;
+    system_props.sort_by(|a, b| {
+        let priority = |prop: &Path| get_module_priority(prop.parent().unwrap_or(prop));
+        priority(a).cmp(&priority(b)).then_with(|| b.cmp(a))
+    });
+    let skipped = resolve_prop_conflicts(&system_props);
+    // the temp copy older versions applied and left behind
+    let _ = std::fs::remove_file(Path::new(defs::WORKING_DIR).join(".system.prop"));
+
+    for system_prop in &system_props {
+        info!("load {}", system_prop.display());
+        let skip = skipped.get(system_prop).cloned().unwrap_or_default();
+        apply_system_prop(system_prop, &skip)?;
+    }
+
+    Ok(())
+}
+
+fn resetprop(args: &[&str]) -> Result<()> {
+    let status = Command::new(assets::RESETPROP_PATH)
+        .args(args)
+        .status()
+        .with_context(|| format!("Failed to exec resetprop {}", args.join(" ")))?;
+    ensure!(status.success(), "resetprop {} failed", args.join(" "));
+    Ok(())
+}
+
+// besides `key=value`, system.prop may have `!delete <prop>` and `+append <prop> <value>`, which
+// appends to a comma separated list. a malformed directive is logged and skipped
+fn apply_system_prop(system_prop: &Path, skip: &HashSet<String>) -> Result<()> {
+    let content = std::fs::read_to_string(system_prop)
+        .with_context(|| format!("Failed to read {}", system_prop.display()))?;
+    let vars = get_template_vars();
+    let mut props = Vec::new();
+    let mut directives = Vec::new();
+    let mut expanded = false;
+    for (number, line) in content.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.starts_with('!') || trimmed.starts_with('+') {
+            directives.push((number + 1, trimmed));
+        } else if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        } else if trimmed
+            .split_once('=')
+            .map_or(true, |(key, _)| !skip.contains(key.trim()))
+        {
+            let line_expanded = expand_template_vars(line, &vars, system_prop);
+            expanded |= line_expanded != line;
+            props.push((number + 1, line_expanded));
+        }
+    }
+
+    if directives.is_empty() && skip.is_empty() && !expanded {
+        // resetprop -n --file system.prop
+        Command::new(assets::RESETPROP_PATH)
+            .arg("-n")
+            .arg("--file")
+            .arg(system_prop)
+            .status()
+            .with_context(|| format!("Failed to exec {}", system_prop.display()))?;
+    } else {
+        // the assignments only, expanded and without the ones another module wins, the value is
+        // everything after the first `=`
+        for (number, line) in props {
+            let result = match line.split_once('=') {
+                Some((key, value)) => resetprop(&["-n", key.trim(), value.trim()]),
+                None => Err(anyhow!("malformed assignment")),
+            };
+            if let Err(e) = result {
+                warn!("{}:{number}: {line}: {e}", system_prop.display());
+            }
+        }
+    }
+
+    for (number, directive) in directives {
+        let (command, args) = directive
+            .split_once(char::is_whitespace)
+            .unwrap_or((directive, ""));
+        let args = args.trim();
+        let result = match command {
+            "!delete" if !args.is_empty() && !args.contains(char::is_whitespace) => {
+                resetprop(&["-d", args])
+            }
+            // the value is the rest of the line, it may have spaces
+            "+append" => match args.split_once(char::is_whitespace) {
+                Some((prop, value)) => {
+                    let current = getprop(prop).unwrap_or_default();
+                    let value = expand_template_vars(value.trim(), &vars, system_prop);
+                    let value = if current.is_empty() {
+                        value
+                    } else {
+                        format!("{current},{value}")
+                    };
+                    resetprop(&["-n", prop, value.as_str()])
+                }
+                None => Err(anyhow!("malformed directive")),
+            },
+            _ => Err(anyhow!("malformed directive")),
+        };
+        if let Err(e) = result {
+            warn!("{}:{number}: {directive}: {e}", system_prop.display());
+        }
+    }
+    Ok(())
+}
+
+// module.prop must contain a valid id, version and versionCode
+fn validate_module_prop(module_prop: &HashMap<String, String>) -> Result<&str> {
+    let Some(module_id) = module_prop.get("id") else {
+        bail!("module id not found in module.prop!");
+    };
+    // the same rule as Magisk, the id is used as the dir name
+    let id_regex = regex::Regex::new(r"^[a-zA-Z][a-zA-Z0-9._-]+$")?;
+    ensure!(
+        id_regex.is_match(module_id),
+        "invalid module id: {module_id}"
+    );
+
+    ensure!(
+        module_prop.contains_key("version"),
+        "module version not found in module.prop!"
+    );
+    let Some(version_code) = module_prop.get("versionCode") else {
+        bail!("module versionCode not found in module.prop!");
+    };
+    ensure!(
+        version_code.trim().parse::<i64>().is_ok(),
+        "invalid module versionCode: {version_code}"
+    );
+
+    Ok(module_id)
+}
+
+// paths of module <dir> kept across `ksud module update`, data/ and the ones listed in its keep file
+fn get_keep_paths(module_dir: &Path) -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from(MODULE_DATA_DIR)];
+    if let Ok(content) = std::fs::read_to_string(module_dir.join(MODULE_KEEP_FILE)) {
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let path = PathBuf::from(line);
+            // don't let a module move files out of its own dir
+            if path
+                .components()
+                .all(|c| matches!(c, std::path::Component::Normal(_)))
+            {
+                paths.push(path);
+            } else {
+                warn!("ignore invalid keep path: {line}");
+            }
+        }
+    }
+    paths
+}
+
+// move the kept paths between the module dir and the stash dir, both are in the same image
+fn move_keep_paths(from: &Path, to: &Path, paths: &[PathBuf]) -> Result<()> {
+    for path in paths {
+        let src = from.join(path);
+        if !src.exists() {
+            continue;
+        }
+        let dst = to.join(path);
+        if dst.is_dir() {
+            remove_dir_all(&dst)?;
+        } else if dst.exists() {
+            std::fs::remove_file(&dst)?;
+        }
+        if let Some(parent) = dst.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::rename(&src, &dst)
+            .with_context(|| format!("Failed to move {} to {}", src.display(), dst.display()))?;
+    }
+    Ok(())
+}
+
+fn get_version_code(module_prop: &HashMap<String, String>) -> i64 {
+    module_prop
+        .get("versionCode")
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(0)
+}
+
+// the first line of a script, busybox sh runs it whatever the shebang asks for
+fn lint_shebang(script: &str, content: &[u8], report: &mut LintReport) {
+    let line = content.split(|b| *b == b'\n').next().unwrap_or_default();
+    if line.ends_with(b"\r") {
+        report
+            .errors
+            .push(format!("{script} has CRLF line endings, sh can't run it"));
+        return;
+    }
+    let Some(interpreter) = line.strip_prefix(b"#!") else {
+        report
+            .warnings
+            .push(format!("{script} has no shebang, it is run by sh"));
+        return;
+    };
+    let interpreter = String::from_utf8_lossy(interpreter);
+    let name = interpreter
+        .split_whitespace()
+        .next()
+        .and_then(|path| Path::new(path).file_name())
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    if !matches!(name.as_str(), "sh" | "ash" | "busybox") {
+        report.warnings.push(format!(
+            "{script} asks for {}, but it is run by sh",
+            interpreter.trim()
+        ));
+    }
+}
+
+#[derive(Default)]
+struct LintReport {
+    errors: Vec<String>,
+    warnings: Vec<String>,
+    // what the install would change beyond adding files
+    risky: Vec<String>,
+}
+
+/// check module <zip> without installing it: its module.prop, scripts, file modes, the partitions
+/// it touches and the risky changes it makes, as json. fails if the module would be rejected
+pub fn lint_module(zip: &str) -> Result<()> {
+    let file = File::open(zip).with_context(|| format!("Failed to open {zip}"))?;
+    let mut archive = zip::ZipArchive::new(file)?;
+    let mut report = LintReport::default();
+    let is_rule = |name: &str| {
+        name == "sepolicy.rule" || (name.starts_with("sepolicy.d/") && name.ends_with(".rule"))
+    };
 
-        let post_fs_data = path.join("post-fs-data.sh");
-        if !post_fs_data.exists() {
+    // only the small files which are checked are read, the rest is only listed
+    let mut entries = Vec::new();
+    let mut contents = HashMap::new();
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let name = entry.name().to_string();
+        // a symlink is always 0777
+        let mode = entry.unix_mode().unwrap_or(0);
+        if mode & 0o170000 != 0o120000 && mode & 0o002 != 0 {
+            report.warnings.push(format!("{name} is world-writable"));
+        }
+        let checked = name == "module.prop"
+            || name == MODULE_REPLACE_FILE
+            || name == "customize.sh"
+            || MODULE_SCRIPTS.contains(&name.as_str())
+            || is_rule(&name);
+        if checked && !entry.is_dir() {
+            let mut content = Vec::new();
+            entry.read_to_end(&mut content)?;
+            contents.insert(name.clone(), content);
+        }
+        entries.push((name, entry.is_dir()));
+    }
+
+    let mut module_prop = HashMap::new();
+    match contents.get("module.prop") {
+        None => report.errors.push("module.prop not found".to_string()),
+        Some(content) => {
+            let parsed =
+                PropertiesIter::new_with_encoding(Cursor::new(content), encoding::all::UTF_8)
+                    .read_into(|k, v| {
+                        module_prop.insert(k, v);
+                    });
+            if let Err(e) = parsed {
+                report.errors.push(format!("invalid module.prop: {e}"));
+            } else if let Err(e) = validate_module_prop(&module_prop) {
+                report.errors.push(e.to_string());
+            } else {
+                // the manager reads it as an int, an update must have a bigger one
+                let version_code = get_version_code(&module_prop);
+                if !(0..=i64::from(i32::MAX)).contains(&version_code) {
+                    report
+                        .errors
+                        .push(format!("versionCode {version_code} is out of range"));
+                }
+            }
+        }
+    }
+    for key in ["name", "author", "description"] {
+        if contents.contains_key("module.prop") && !module_prop.contains_key(key) {
+            report.warnings.push(format!("module.prop has no {key}"));
+        }
+    }
+
+    for script in MODULE_SCRIPTS.iter().chain(["customize.sh"].iter()) {
+        if let Some(content) = contents.get(*script) {
+            lint_shebang(script, content, &mut report);
+        }
+    }
+
+    // what each top level partition dir has, as for an installed module
+    let is_partition = |name: &str| {
+        name == "system"
+            || crate::event::KNOWN_PARTITIONS.contains(&name)
+            || name.starts_with("my_")
+    };
+    let mut partitions = BTreeSet::new();
+    let mut touched = BTreeSet::new();
+    for (name, is_dir) in &entries {
+        let (top, rest) = name.split_once('/').unwrap_or((name.as_str(), ""));
+        if !is_partition(top) || (!is_dir && rest.is_empty()) {
             continue;
         }
+        partitions.insert(top.to_string());
+        if !is_dir {
+            touched.insert(top.to_string());
+        }
+        if Path::new(name)
+            .file_name()
+            .map_or(false, |n| n == MODULE_REPLACE_MARKER)
+        {
+            let dir = Path::new(name).parent().unwrap_or_else(|| Path::new(""));
+            report
+                .risky
+                .push(format!("replace dir: /{}", dir.display()));
+        }
+    }
+    for partition in partitions.difference(&touched) {
+        report.warnings.push(format!(
+            "{partition}/ has no files, nothing is mounted from it"
+        ));
+    }
 
-        exec_script(&post_fs_data, true)?;
+    if let Some(content) = contents.get(MODULE_REPLACE_FILE) {
+        for line in String::from_utf8_lossy(content).lines().map(str::trim) {
+            if !line.is_empty() && !line.starts_with('#') {
+                report.risky.push(format!(
+                    "replace or delete: /{}",
+                    line.trim_start_matches('/')
+                ));
+            }
+        }
+    }
+    let mut rules = contents
+        .iter()
+        .filter(|(name, _)| is_rule(name))
+        .collect::<Vec<_>>();
+    rules.sort();
+    for (name, content) in rules {
+        let count = String::from_utf8_lossy(content)
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .count();
+        report
+            .risky
+            .push(format!("sepolicy rules: {count} in {name}"));
     }
 
+    let result = serde_json::json!({
+        "zip": zip,
+        "id": module_prop.get("id"),
+        "errors": &report.errors,
+        "warnings": &report.warnings,
+        "partitions": touched,
+        "risky": &report.risky,
+    });
+    println!("{}", serde_json::to_string_pretty(&result)?);
+    ensure!(
+        report.errors.is_empty(),
+        "{zip} has {} errors",
+        report.errors.len()
+    );
     Ok(())
 }
 
-pub fn exec_common_scripts(dir: &str, wait: bool) -> Result<()> {
-    let script_dir = Path::new(defs::ADB_DIR).join(dir);
-    if !script_dir.exists() {
-        info!("{} not exists, skip", script_dir.display());
-        return Ok(());
-    }
+// an update keeps the data of the installed module, a downgrade must be asked for
+struct UpdateOptions {
+    downgrade: bool,
+}
 
-    let dir = std::fs::read_dir(&script_dir)?;
-    for entry in dir.flatten() {
-        let path = entry.path();
+// json lines on stdout for the manager to show the progress of an install, e.g.
+// {"phase":"extract","percent":42}. the stdout is only for them while it is alive, the human
+// readable output of ksud and of the install script goes to stderr, `out` is the real stdout
+struct InstallProgress {
+    out: Option<File>,
+}
 
-        if !is_executable(&path) {
-            warn!("{} is not executable, skip", path.display());
-            continue;
+impl InstallProgress {
+    fn new(enabled: bool) -> Self {
+        if !enabled {
+            return Self { out: None };
+        }
+        let _ = std::io::stdout().flush();
+        let fd = unsafe { libc::dup(libc::STDOUT_FILENO) };
+        if fd < 0 {
+            warn!("dup stdout failed: {}", std::io::Error::last_os_error());
+            return Self { out: None };
+        }
+        if unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+            warn!(
+                "redirect stdout to stderr failed: {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        Self {
+            out: Some(unsafe { File::from_raw_fd(fd) }),
         }
+    }
 
-        exec_script(path, wait)?;
+    fn write(&self, value: &serde_json::Value) {
+        if let Some(mut out) = self.out.as_ref() {
+            let _ = writeln!(out, "{value}");
+        }
     }
 
-    Ok(())
+    fn report(&self, phase: &str, percent: u64) {
+        self.write(&serde_json::json!({ "phase": phase, "percent": percent.min(100) }));
+    }
+
+    fn summary(&self, result: &Result<(String, String)>) {
+        let summary = match result {
+            Ok((id, version)) => {
+                serde_json::json!({ "success": true, "id": id, "version": version })
+            }
+            Err(e) => serde_json::json!({ "success": false, "error": format!("{e:#}") }),
+        };
+        self.write(&summary);
+    }
 }
 
-/// execute every modules' service.sh
-pub fn exec_services() -> Result<()> {
-    let modules_dir = Path::new(defs::MODULE_DIR);
-    let dir = std::fs::read_dir(modules_dir)?;
-    for entry in dir.flatten() {
-        let path = entry.path();
-        let disabled = path.join(defs::DISABLE_FILE_NAME);
-        if disabled.exists() {
-            warn!("{} is disabled, skip", path.display());
-            continue;
+impl Drop for InstallProgress {
+    fn drop(&mut self) {
+        if let Some(out) = &self.out {
+            let _ = std::io::stdout().flush();
+            unsafe { libc::dup2(out.as_raw_fd(), libc::STDOUT_FILENO) };
         }
+    }
+}
 
-        let service = path.join("service.sh");
-        if !service.exists() {
-            continue;
+// the same as ZipArchive::extract, and the extracted percent of the uncompressed size is reported
+fn extract_zip(zip: &str, dir: &str, progress: &InstallProgress) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(File::open(zip)?)?;
+    let total = (0..archive.len())
+        .filter_map(|i| archive.by_index(i).ok().map(|f| f.size()))
+        .sum::<u64>()
+        .max(1);
+    let mut extracted = 0;
+    let mut last_percent = 0;
+    progress.report("extract", 0);
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let Some(name) = file.enclosed_name().map(Path::to_path_buf) else {
+            bail!("invalid file name in zip: {}", file.name());
+        };
+        let out = Path::new(dir).join(name);
+        if file.is_dir() {
+            std::fs::create_dir_all(&out)?;
+        } else {
+            if let Some(parent) = out.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            std::io::copy(&mut file, &mut File::create(&out)?)?;
+        }
+        #[cfg(unix)]
+        if let Some(mode) = file.unix_mode() {
+            set_permissions(&out, Permissions::from_mode(mode))?;
         }
 
-        exec_script(&service, false)?;
+        extracted += file.size();
+        let percent = extracted * 100 / total;
+        if percent != last_percent {
+            progress.report("extract", percent);
+            last_percent = percent;
+        }
     }
-
     Ok(())
 }
 
-pub fn load_system_prop() -> Result<()> {
-    let modules_dir = Path::new(defs::MODULE_DIR);
-    let dir = std::fs::read_dir(modules_dir)?;
-    for entry in dir.flatten() {
-        let path = entry.path();
-        let disabled = path.join(defs::DISABLE_FILE_NAME);
-        if disabled.exists() {
-            info!("{} is disabled, skip", path.display());
-            continue;
-        }
+// the module zip copied to ZIP_COPY_PATH, which only root can write, it is removed on drop so
+// that no exit path leaves it behind
+struct ZipCopy {
+    file: File,
+}
 
-        let system_prop = path.join("system.prop");
-        if !system_prop.exists() {
-            continue;
-        }
-        info!("load {} system.prop", path.display());
+impl ZipCopy {
+    fn copy_from(reader: &mut impl Read, limit: u64) -> Result<(Self, u64)> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(ZIP_COPY_PATH)
+            .with_context(|| format!("Failed to create {ZIP_COPY_PATH}"))?;
+        let copy = ZipCopy {
+            file: file.try_clone()?,
+        };
+        let size = std::io::copy(&mut reader.take(limit.saturating_add(1)), &mut file)?;
+        file.sync_all()?;
+        Ok((copy, size))
+    }
 
-        // resetprop -n --file system.prop
-        Command::new(assets::RESETPROP_PATH)
-            .arg("-n")
-            .arg("--file")
-            .arg(&system_prop)
-            .status()
-            .with_context(|| format!("Failed to exec {}", system_prop.display()))?;
+    fn from_stdin() -> Result<Self> {
+        let (copy, size) = Self::copy_from(&mut std::io::stdin().lock(), STDIN_ZIP_MAX_SIZE)
+            .with_context(|| "Failed to read module zip from stdin")?;
+        ensure!(
+            size <= STDIN_ZIP_MAX_SIZE,
+            "module zip from stdin is larger than {STDIN_ZIP_MAX_SIZE} bytes"
+        );
+        ensure!(size > 0, "no module zip from stdin");
+        info!("read {size} bytes of module zip from stdin");
+        Ok(copy)
     }
 
-    Ok(())
+    // the zip is opened once, a later change of the file at zip doesn't reach the copy
+    fn from_path(zip: &str) -> Result<Self> {
+        let mut file = File::open(zip).with_context(|| format!("Failed to open {zip}"))?;
+        let (copy, size) = Self::copy_from(&mut file, u64::MAX)
+            .with_context(|| format!("Failed to copy {zip}"))?;
+        info!("copied {size} bytes of {zip} to {ZIP_COPY_PATH}");
+        Ok(copy)
+    }
+}
+
+impl Drop for ZipCopy {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(ZIP_COPY_PATH);
+    }
 }
 
-fn _install_module(zip: &str) -> Result<()> {
-    ensure_boot_completed()?;
+// `direct` works on the image without the boot mount and without a booted Android, e.g. in recovery
+fn _install_module(
+    zip: &str,
+    update: Option<UpdateOptions>,
+    direct: bool,
+    staged: bool,
+    progress: &InstallProgress,
+) -> Result<(String, String)> {
+    if !direct {
+        ensure_boot_completed()?;
+    }
+    ensure_stage_consistent(staged)?;
+    ensure_module_img_reachable()?;
 
     // print banner
     println!(include_str!("banner"));
@@ -329,6 +2306,23 @@ fn _install_module(zip: &str) -> Result<()> {
     ensure_dir_exists(defs::WORKING_DIR).with_context(|| "Failed to create working dir")?;
     ensure_dir_exists(defs::BINARY_DIR).with_context(|| "Failed to create bin dir")?;
 
+    // `-` is the zip streamed to stdin, e.g. by `adb exec-in`. a zip which must be signed is
+    // copied first, so that the zip which is verified is the one which is installed
+    let zip_copy = if zip == "-" {
+        Some(ZipCopy::from_stdin()?)
+    } else if crate::module_sign::is_required()? {
+        Some(ZipCopy::from_path(zip)?)
+    } else {
+        None
+    };
+    let zip = if zip_copy.is_some() {
+        ZIP_COPY_PATH
+    } else {
+        zip
+    };
+
+    progress.report("verify", 0);
+
     // read the module_id from zip, if faild if will return early.
     let mut buffer: Vec<u8> = Vec::new();
     let entry_path = PathBuf::from_str("module.prop")?;
@@ -343,11 +2337,18 @@ fn _install_module(zip: &str) -> Result<()> {
     )?;
     info!("module prop: {:?}", module_prop);
 
-    let Some(module_id) = module_prop.get("id") else {
-        bail!("module id not found in module.prop!");
+    let module_id = validate_module_prop(&module_prop)?;
+    let signer = match &zip_copy {
+        Some(copy) => crate::module_sign::verify_module_zip(&copy.file)?,
+        None => crate::module_sign::verify_module_zip(&File::open(zip)?)?,
     };
+    if let Some(signer) = signer {
+        println!("- Signed by: {signer}");
+    }
+    progress.report("verify", 100);
 
-    let modules_img = Path::new(defs::MODULE_IMG);
+    let modules_img = get_module_img();
+    let modules_img = Path::new(&modules_img);
     let modules_update_img = Path::new(defs::MODULE_UPDATE_IMG);
     let module_update_tmp_dir = defs::MODULE_UPDATE_TMP_DIR;
 
@@ -380,51 +2381,28 @@ fn _install_module(zip: &str) -> Result<()> {
         humansize::format_size(zip_uncompressed_size, humansize::DECIMAL)
     );
 
+    let mut from_erofs = false;
     if !modules_img_exist && !modules_update_img_exist {
         // if no modules and modules_update, it is brand new installation, we should create a new img
         // create a tmp module img and mount it to modules_update
         info!("Creating brand new module image");
-        File::create(tmp_module_img)
-            .context("Failed to create ext4 image file")?
-            .set_len(grow_size)
-            .context("Failed to extend ext4 image")?;
-
-        // format the img to ext4 filesystem
-        let result = Command::new("mkfs.ext4")
-            .arg(tmp_module_img)
-            .stdout(Stdio::null())
-            .output()?;
-        ensure!(
-            result.status.success(),
-            "Failed to format ext4 image: {}",
-            String::from_utf8(result.stderr).unwrap()
-        );
-
-        check_image(tmp_module_img)?;
+        create_image(tmp_module_img, grow_size)?;
     } else if modules_update_img_exist {
         // modules_update.img exists, we should use it as tmp img
         info!("Using existing modules_update.img as tmp image");
-        std::fs::copy(modules_update_img, tmp_module_img).with_context(|| {
-            format!(
-                "Failed to copy {} to {}",
-                modules_update_img.display(),
-                tmp_module_img
-            )
-        })?;
+        from_erofs = copy_to_tmp_image(modules_update_img, tmp_module_img, grow_size)?;
         // grow size of the tmp image
-        grow_image_size(tmp_module_img, grow_size)?;
+        if !from_erofs {
+            grow_image_size(tmp_module_img, grow_size)?;
+        }
     } else {
         // modules.img exists, we should use it as tmp img
         info!("Using existing modules.img as tmp image");
-        std::fs::copy(modules_img, tmp_module_img).with_context(|| {
-            format!(
-                "Failed to copy {} to {}",
-                modules_img.display(),
-                tmp_module_img
-            )
-        })?;
+        from_erofs = copy_to_tmp_image(modules_img, tmp_module_img, grow_size)?;
         // grow size of the tmp image
-        grow_image_size(tmp_module_img, grow_size)?;
+        if !from_erofs {
+            grow_image_size(tmp_module_img, grow_size)?;
+        }
     }
 
     // ensure modules_update exists
@@ -433,30 +2411,92 @@ fn _install_module(zip: &str) -> Result<()> {
     // mount the modules_update.img to mountpoint
     println!("- Mounting image");
 
-    let _dontdrop = mount::AutoMountExt4::try_new(tmp_module_img, module_update_tmp_dir, true)?;
+    let mut image_mount =
+        mount::AutoMountExt4::try_new(tmp_module_img, module_update_tmp_dir, true)?;
 
     info!("mounted {} to {}", tmp_module_img, module_update_tmp_dir);
 
     setsyscon(module_update_tmp_dir)?;
 
     let module_dir = format!("{module_update_tmp_dir}/{module_id}");
+    let keep_dir = format!("{module_update_tmp_dir}/.{module_id}.keep");
+    let mut keep_paths = Vec::new();
+    if let Some(options) = &update {
+        let installed = Path::new(&module_dir);
+        ensure!(
+            installed.join("module.prop").exists(),
+            "module: {module_id} is not installed"
+        );
+        let installed_version = get_version_code(&read_module_prop(installed)?);
+        let version = get_version_code(&module_prop);
+        ensure!(
+            version >= installed_version || options.downgrade,
+            "versionCode {version} is lower than the installed {installed_version}, use --downgrade to install it"
+        );
+        println!("- Updating {module_id} from {installed_version} to {version}");
+
+        keep_paths = get_keep_paths(installed);
+        ensure_clean_dir(&keep_dir)?;
+        move_keep_paths(installed, Path::new(&keep_dir), &keep_paths)?;
+    }
     ensure_clean_dir(&module_dir)?;
     info!("module dir: {}", module_dir);
 
     // unzip the image and move it to modules_update/<id> dir
-    let file = File::open(zip)?;
-    let mut archive = zip::ZipArchive::new(file)?;
-    archive.extract(&module_dir)?;
+    extract_zip(zip, &module_dir, progress)?;
+
+    if update.is_some() {
+        // the kept files replace the ones in the zip, the new module may keep more
+        keep_paths.extend(get_keep_paths(Path::new(&module_dir)));
+        move_keep_paths(Path::new(&keep_dir), Path::new(&module_dir), &keep_paths)?;
+        remove_dir_all(&keep_dir)?;
+    }
+
+    // the install script may extract or download more files, make sure there is enough space
+    let free_space = get_free_space(module_update_tmp_dir)?;
+    if free_space < IMG_LOW_SPACE_THRESHOLD {
+        println!("- Low space in image, growing it");
+        info!("free space: {free_space}, grow image {tmp_module_img}");
+        image_mount.umount()?;
+        drop(image_mount);
+        let current_size = std::fs::metadata(tmp_module_img)?.len();
+        mount::resize_ext4(tmp_module_img, current_size + grow_size)?;
+        image_mount = mount::AutoMountExt4::try_new(tmp_module_img, module_update_tmp_dir, true)?;
+        setsyscon(module_update_tmp_dir)?;
+    }
 
     // set permission and selinux context for $MOD/system
-    let module_system_dir = PathBuf::from(module_dir).join("system");
+    let module_system_dir = PathBuf::from(&module_dir).join("system");
     if module_system_dir.exists() {
         #[cfg(unix)]
         set_permissions(&module_system_dir, Permissions::from_mode(0o755))?;
         restore_syscon(&module_system_dir)?;
     }
 
+    progress.report("customize", 0);
     exec_install_script(zip)?;
+    progress.report("customize", 100);
+
+    ensure!(
+        Path::new(&module_dir).join("module.prop").exists(),
+        "module.prop not found after install, the install script may be broken"
+    );
+    // the install script may add markers too
+    apply_replace_markers(Path::new(&module_dir))?;
+
+    progress.report("finalize", 0);
+
+    if let Err(e) = crate::event::save_partition_manifest(module_update_tmp_dir) {
+        warn!("save partition manifest failed: {e}");
+    }
+
+    // umount the image before we rename it
+    drop(image_mount);
+
+    if from_erofs || Path::new(defs::EROFS_IMG_FLAG_PATH).exists() {
+        println!("- Building erofs image");
+        rebuild_erofs(tmp_module_img)?;
+    }
 
     info!("rename {tmp_module_img} to {}", defs::MODULE_UPDATE_IMG);
     // all done, rename the tmp image to modules_update.img
@@ -466,21 +2506,60 @@ fn _install_module(zip: &str) -> Result<()> {
             .with_context(|| "Failed to copy image.".to_string())?;
         let _ = std::fs::remove_file(tmp_module_img);
     }
+    save_image_checksum(defs::MODULE_UPDATE_IMG)?;
+    save_image_format(defs::MODULE_UPDATE_IMG)?;
 
-    mark_update()?;
+    mark_changed(staged)?;
+    progress.report("finalize", 100);
 
     info!("Module install successfully!");
 
-    Ok(())
+    let version = module_prop.get("version").cloned().unwrap_or_default();
+    Ok((module_id.to_string(), version))
+}
+
+/// install the module zip, the progress is printed as json lines if `progress`
+pub fn install_module(zip: &str, progress: bool) -> Result<()> {
+    let progress = InstallProgress::new(progress);
+    handle_install_result(
+        _install_module(zip, None, false, false, &progress),
+        &progress,
+    )
 }
 
-pub fn install_module(zip: &str) -> Result<()> {
-    let result = _install_module(zip);
+/// install the module zip into the image without the boot mount, e.g. from recovery, it is
+/// mounted on the next boot like a normal update
+pub fn install_module_direct(zip: &str, progress: bool) -> Result<()> {
+    let progress = InstallProgress::new(progress);
+    handle_install_result(
+        _install_module(zip, None, true, false, &progress),
+        &progress,
+    )
+}
+
+/// install the zip over the installed module with the same id, its data/ and keep paths are preserved
+pub fn upgrade_module(zip: &str, downgrade: bool, progress: bool) -> Result<()> {
+    let progress = InstallProgress::new(progress);
+    let update = Some(UpdateOptions { downgrade });
+    handle_install_result(
+        _install_module(zip, update, false, false, &progress),
+        &progress,
+    )
+}
+
+fn handle_install_result(
+    result: Result<(String, String)>,
+    progress: &InstallProgress,
+) -> Result<()> {
+    progress.summary(&result);
+    let result = result.map(|_| ());
     if let Err(ref e) = result {
         // error happened, do some cleanup!
         let _ = std::fs::remove_file(defs::MODULE_UPDATE_TMP_IMG);
         let _ = mount::umount_dir(defs::MODULE_UPDATE_TMP_DIR);
         println!("- Error: {e}");
+        // for the manager
+        eprintln!("{}", serde_json::json!({ "error": format!("{e:#}") }));
     }
     result
 }
@@ -489,12 +2568,24 @@ fn update_module<F>(update_dir: &str, id: &str, func: F) -> Result<()>
 where
     F: Fn(&str, &str) -> Result<()>,
 {
-    ensure_boot_completed()?;
+    _update_module(update_dir, id, false, false, func)
+}
+
+fn _update_module<F>(update_dir: &str, id: &str, direct: bool, staged: bool, func: F) -> Result<()>
+where
+    F: Fn(&str, &str) -> Result<()>,
+{
+    if !direct {
+        ensure_boot_completed()?;
+    }
+    ensure_stage_consistent(staged)?;
+    ensure_module_img_reachable()?;
 
-    let modules_img = Path::new(defs::MODULE_IMG);
+    let modules_img = get_module_img();
+    let modules_img = Path::new(&modules_img);
     let modules_update_img = Path::new(defs::MODULE_UPDATE_IMG);
     let modules_update_tmp_img = Path::new(defs::MODULE_UPDATE_TMP_IMG);
-    if !modules_update_img.exists() && !modules_img.exists() {
+    let from_erofs = if !modules_update_img.exists() && !modules_img.exists() {
         bail!("Please install module first!");
     } else if modules_update_img.exists() {
         info!(
@@ -502,24 +2593,32 @@ where
             modules_update_img.display(),
             modules_update_tmp_img.display()
         );
-        std::fs::copy(modules_update_img, modules_update_tmp_img)?;
+        copy_to_tmp_image(modules_update_img, defs::MODULE_UPDATE_TMP_IMG, 0)?
     } else {
         info!(
             "copy {} to {}",
             modules_img.display(),
             modules_update_tmp_img.display()
         );
-        std::fs::copy(modules_img, modules_update_tmp_img)?;
-    }
+        copy_to_tmp_image(modules_img, defs::MODULE_UPDATE_TMP_IMG, 0)?
+    };
 
     // ensure modules_update dir exist
     ensure_clean_dir(update_dir)?;
 
     // mount the modules_update img
-    let _dontdrop = mount::AutoMountExt4::try_new(defs::MODULE_UPDATE_TMP_IMG, update_dir, true)?;
+    let image_mount = mount::AutoMountExt4::try_new(defs::MODULE_UPDATE_TMP_IMG, update_dir, true)?;
 
     // call the operation func
     let result = func(id, update_dir);
+    if let Err(e) = crate::event::save_partition_manifest(update_dir) {
+        warn!("save partition manifest failed: {e}");
+    }
+
+    drop(image_mount);
+    if from_erofs || Path::new(defs::EROFS_IMG_FLAG_PATH).exists() {
+        rebuild_erofs(defs::MODULE_UPDATE_TMP_IMG)?;
+    }
 
     if let Err(e) = std::fs::rename(modules_update_tmp_img, defs::MODULE_UPDATE_IMG) {
         warn!("Rename image failed: {e}, try copy it.");
@@ -527,101 +2626,286 @@ where
             .with_context(|| "Failed to copy image.".to_string())?;
         let _ = std::fs::remove_file(modules_update_tmp_img);
     }
+    save_image_checksum(defs::MODULE_UPDATE_IMG)?;
+    save_image_format(defs::MODULE_UPDATE_IMG)?;
 
-    mark_update()?;
+    mark_changed(staged)?;
 
     result
 }
 
-pub fn uninstall_module(id: &str) -> Result<()> {
-    update_module(defs::MODULE_UPDATE_TMP_DIR, id, |mid, update_dir| {
-        let dir = Path::new(update_dir);
-        ensure!(dir.exists(), "No module installed");
-
-        // iterate the modules_update dir, find the module to be removed
-        let dir = std::fs::read_dir(dir)?;
-        for entry in dir.flatten() {
-            let path = entry.path();
-            let module_prop = path.join("module.prop");
-            if !module_prop.exists() {
-                continue;
-            }
-            let content = std::fs::read(module_prop)?;
-            let mut module_id: String = String::new();
-            PropertiesIter::new_with_encoding(Cursor::new(content), encoding::all::UTF_8)
-                .read_into(|k, v| {
-                    if k.eq("id") {
-                        module_id = v;
+/// uninstall module <id>, refuse it if the other modules depend on it unless `force`
+pub fn uninstall_module(id: &str, force: bool) -> Result<()> {
+    let dependents = get_dependents(id);
+    if !dependents.is_empty() {
+        ensure!(
+            force,
+            "{} depend on {id}, use --force to remove it anyway",
+            dependents.join(", ")
+        );
+        warn!("remove {id} which {} depend on", dependents.join(", "));
+    }
+
+    // uninstall.sh runs in the module script environment, then the module is removed from the image
+    _uninstall_module(id, false, false)?;
+    drop_live_module(id);
+    println!("- {id} will be removed after reboot");
+    Ok(())
+}
+
+// the live module is marked removed, drop its hosts and sepolicy rules from what is in effect.
+// the rules already loaded in the kernel can't be unloaded until the reboot
+fn drop_live_module(id: &str) {
+    let hosts = crate::event::get_hosts_module_systems()
+        .and_then(|systems| crate::hosts::regenerate_merged_hosts(&systems));
+    if let Err(e) = hosts {
+        warn!("merge hosts without {id} failed: {e:#}");
+    }
+    if let Err(e) = drop_sepolicy_report(id) {
+        warn!("drop sepolicy rules of {id} failed: {e:#}");
+    }
+}
+
+/// remove the module from the image without the boot mount, e.g. from recovery when the module
+/// breaks the boot, its uninstall.sh is not run because the system is not running
+pub fn uninstall_module_direct(id: &str) -> Result<()> {
+    _uninstall_module(id, true, false)?;
+    println!("- {id} is removed");
+    Ok(())
+}
+
+fn _uninstall_module(id: &str, direct: bool, staged: bool) -> Result<()> {
+    _update_module(
+        defs::MODULE_UPDATE_TMP_DIR,
+        id,
+        direct,
+        staged,
+        |mid, update_dir| {
+            let dir = Path::new(update_dir);
+            ensure!(dir.exists(), "No module installed");
+
+            // iterate the modules_update dir, find the module to be removed
+            let dir = std::fs::read_dir(dir)?;
+            for entry in dir.flatten() {
+                let path = entry.path();
+                let module_prop = path.join("module.prop");
+                if !module_prop.exists() {
+                    continue;
+                }
+                let content = std::fs::read(module_prop)?;
+                let mut module_id: String = String::new();
+                PropertiesIter::new_with_encoding(Cursor::new(content), encoding::all::UTF_8)
+                    .read_into(|k, v| {
+                        if k.eq("id") {
+                            module_id = v;
+                        }
+                    })?;
+                if module_id.eq(mid) {
+                    let uninstall_script = path.join("uninstall.sh");
+                    if direct && uninstall_script.exists() {
+                        println!("- Skip {}", uninstall_script.display());
+                    } else if staged && uninstall_script.exists() {
+                        println!("- {} runs on stage-commit", uninstall_script.display());
+                    } else if uninstall_script.exists() {
+                        exec_script(uninstall_script, true)?;
                     }
-                })?;
-            if module_id.eq(mid) {
-                let uninstall_script = path.join("uninstall.sh");
-                if uninstall_script.exists() {
-                    exec_script(uninstall_script, true)?;
+                    remove_dir_all(path)?;
+                    break;
                 }
-                remove_dir_all(path)?;
-                break;
             }
-        }
 
-        // santity check
-        let target_module_path = format!("{update_dir}/{mid}");
-        let target_module = Path::new(&target_module_path);
-        if target_module.exists() {
-            remove_dir_all(target_module)?;
+            // santity check
+            let target_module_path = format!("{update_dir}/{mid}");
+            let target_module = Path::new(&target_module_path);
+            if target_module.exists() {
+                remove_dir_all(target_module)?;
+            }
+
+            // the update image has it anyway, only the live state may lag behind,
+            // a staged one is marked on stage-commit
+            if !staged {
+                if let Err(e) = mark_module_state(id, defs::REMOVE_FILE_NAME, true) {
+                    warn!("mark {id} removed failed: {e:#}");
+                }
+                let _ = remove_dir_all(Path::new(defs::MODULE_RUNONCE_DIR).join(mid));
+                let _ = remove_dir_all(Path::new(defs::MODULE_LOG_DIR).join(mid));
+            }
+
+            Ok(())
+        },
+    )
+}
+
+/// turn the replace markers of the module into overlayfs markers on install, so that a module
+/// can delete or fully replace a stock path: a dir with a `.replace` file is made opaque and the
+/// file is removed, and each path listed in the `replace` file is made opaque if the module has
+/// it as a dir, or a whiteout if the module doesn't have it. the erofs image keeps them.
+fn apply_replace_markers(module: &Path) -> Result<()> {
+    for entry in jwalk::WalkDir::new(module)
+        .parallelism(jwalk::Parallelism::Serial)
+        .into_iter()
+        .flatten()
+    {
+        if entry.file_name() == MODULE_REPLACE_MARKER {
+            let dir = entry.parent_path();
+            info!("replace dir: {}", dir.display());
+            mount::set_opaque(&*dir)?;
+            std::fs::remove_file(entry.path())?;
         }
+    }
 
-        let _ = mark_module_state(id, defs::REMOVE_FILE_NAME, true);
+    let Ok(content) = std::fs::read_to_string(module.join(MODULE_REPLACE_FILE)) else {
+        return Ok(());
+    };
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let relative = Path::new(line.trim_start_matches('/'));
+        if !relative
+            .components()
+            .all(|c| matches!(c, std::path::Component::Normal(_)))
+        {
+            warn!("ignore invalid replace path: {line}");
+            continue;
+        }
+        let path = module.join(relative);
+        if path.is_dir() {
+            info!("replace dir: {}", path.display());
+            mount::set_opaque(&path)?;
+        } else if std::fs::symlink_metadata(&path).is_err() {
+            info!("delete: {line}");
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            mount::create_whiteout(&path)?;
+        }
+    }
+    Ok(())
+}
 
-        Ok(())
-    })
+// the enabled modules which declare <id> in `dependencies`
+fn get_dependents(id: &str) -> Vec<String> {
+    let Ok(dir) = std::fs::read_dir(defs::MODULE_DIR) else {
+        return Vec::new();
+    };
+    let mut dependents = dir
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_dir() && !is_disabled(path))
+        .filter(|path| {
+            read_module_prop(path)
+                .map(|prop| get_module_dependencies(&prop).iter().any(|dep| dep == id))
+                .unwrap_or(false)
+        })
+        .map(|path| {
+            path.file_name()
+                .unwrap_or_default()
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect::<Vec<_>>();
+    dependents.sort();
+    dependents
 }
 
-fn _enable_module(module_dir: &str, mid: &str, enable: bool) -> Result<()> {
+fn _enable_module(module_dir: &str, mid: &str, enable: bool, staged: bool) -> Result<()> {
     let src_module_path = format!("{module_dir}/{mid}");
     let src_module = Path::new(&src_module_path);
-    ensure!(src_module.exists(), "module: {} not found!", mid);
+    ensure!(
+        src_module.exists(),
+        KsudError::ModuleNotFound(mid.to_string())
+    );
 
     let disable_path = src_module.join(defs::DISABLE_FILE_NAME);
+    set_flag(disable_path, !enable)?;
+
+    // the live modules only change when the staged image is committed
+    if staged {
+        return Ok(());
+    }
+    if let Err(e) = mark_module_state(mid, defs::DISABLE_FILE_NAME, !enable) {
+        warn!("mark {mid} disabled failed: {e:#}");
+    }
     if enable {
-        if disable_path.exists() {
-            std::fs::remove_file(&disable_path).with_context(|| {
-                format!("Failed to remove disable file: {}", &disable_path.display())
-            })?;
+        let mut disabled = read_id_list(defs::MODULE_SAFE_MODE_DISABLED_PATH);
+        if disabled.remove(mid) {
+            write_id_list(defs::MODULE_SAFE_MODE_DISABLED_PATH, &disabled)?;
         }
-    } else {
-        ensure_file_exists(disable_path)?;
     }
 
-    let _ = mark_module_state(mid, defs::DISABLE_FILE_NAME, !enable);
-
     Ok(())
 }
 
+// take the module off the live filesystem, an overlay can only be umounted when the module
+// is the only one in it, return false if a reboot is still required
+fn umount_module_overlays(id: &str) -> bool {
+    let module_prefix = format!("{}{id}/", defs::MODULE_DIR);
+    let mut live = true;
+    for (mnt, lowerdirs) in mount::get_overlay_mounts() {
+        let modules = lowerdirs
+            .iter()
+            .filter(|dir| dir.starts_with(defs::MODULE_DIR))
+            .collect::<Vec<_>>();
+        if !modules.iter().any(|dir| dir.starts_with(&module_prefix)) {
+            continue;
+        }
+        if modules.len() > 1 {
+            println!("- {mnt} is shared with other modules");
+            live = false;
+            continue;
+        }
+        match mount::detach_dir(&mnt) {
+            Ok(()) => println!("- Unmounted {mnt}"),
+            Err(e) => {
+                println!("- Unmount {mnt} failed: {e}");
+                live = false;
+            }
+        }
+    }
+    live
+}
+
 pub fn enable_module(id: &str) -> Result<()> {
     update_module(defs::MODULE_UPDATE_TMP_DIR, id, |mid, update_dir| {
-        _enable_module(update_dir, mid, true)
-    })
+        _enable_module(update_dir, mid, true, false)
+    })?;
+    println!("- Reboot is required to mount the module");
+    Ok(())
 }
 
 pub fn disable_module(id: &str) -> Result<()> {
     update_module(defs::MODULE_UPDATE_TMP_DIR, id, |mid, update_dir| {
-        _enable_module(update_dir, mid, false)
-    })
+        _enable_module(update_dir, mid, false, false)
+    })?;
+    if !umount_module_overlays(id) {
+        println!("- Reboot is required to disable the module completely");
+    }
+    Ok(())
 }
 
 pub fn disable_all_modules() -> Result<()> {
-    // we assume the module dir is already mounted
+    // we assume the module dir is already mounted, it may be read-only so the ids are kept
+    // in the working dir
     let dir = std::fs::read_dir(defs::MODULE_DIR)?;
+    let pinlist = read_id_list(defs::MODULE_PINLIST_PATH);
+    let mut disabled = read_id_list(defs::MODULE_SAFE_MODE_DISABLED_PATH);
     for entry in dir.flatten() {
         let path = entry.path();
-        let disable_flag = path.join(defs::DISABLE_FILE_NAME);
-        if let Err(e) = ensure_file_exists(disable_flag) {
-            warn!("Failed to disable module: {}: {}", path.display(), e);
+        let id = entry.file_name().to_string_lossy().to_string();
+        // it is the reason of the safe mode as likely as any other module, say it loudly
+        if pinlist.contains(&id) {
+            log::error!(
+                "safe mode: {} is pinned and kept enabled, unpin it if it breaks the boot",
+                path.display()
+            );
+            continue;
+        }
+        if path.is_dir() {
+            disabled.insert(id);
         }
     }
-
-    Ok(())
+    write_id_list(defs::MODULE_SAFE_MODE_DISABLED_PATH, &disabled)
 }
 
 fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
@@ -632,6 +2916,7 @@ fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
     };
 
     let mut modules: Vec<HashMap<String, String>> = Vec::new();
+    let vars = get_template_vars();
 
     for entry in dir.flatten() {
         let path = entry.path();
@@ -652,12 +2937,14 @@ fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
                 module_prop_map.insert(k, v);
             });
 
-        // Add enabled, update, remove flags
-        let enabled = !path.join(defs::DISABLE_FILE_NAME).exists();
+        // Add enabled, update, remove flags, an incompatible module is never enabled
+        let incompatible = get_incompatible_reason(&path).is_some();
+        let enabled = !is_disabled(&path) && !incompatible;
         let update = path.join(defs::UPDATE_FILE_NAME).exists();
         let remove = path.join(defs::REMOVE_FILE_NAME).exists();
 
         module_prop_map.insert("enabled".to_owned(), enabled.to_string());
+        module_prop_map.insert("incompatible".to_owned(), incompatible.to_string());
         module_prop_map.insert("update".to_owned(), update.to_string());
         module_prop_map.insert("remove".to_owned(), remove.to_string());
 
@@ -665,14 +2952,279 @@ fn _list_modules(path: &str) -> Vec<HashMap<String, String>> {
             warn!("Failed to parse module.prop: {}", module_prop.display());
             continue;
         }
+        if let Some(description) = module_prop_map.get_mut("description") {
+            *description = expand_template_vars(description, &vars, &module_prop);
+        }
         modules.push(module_prop_map);
     }
 
     modules
 }
 
-pub fn list_modules() -> Result<()> {
-    let modules = _list_modules(defs::MODULE_DIR);
+// the module image is mounted on boot, if it isn't (e.g. in safe mode or before the first boot
+// with modules), mount it read-only in the private namespace of the cli
+fn ensure_module_dir_mounted() {
+    if mount::is_mounted(defs::MODULE_DIR.trim_end_matches('/')) {
+        return;
+    }
+    let module_img = get_module_img();
+    let img = [module_img.as_str(), defs::MODULE_UPDATE_IMG]
+        .into_iter()
+        .find(|img| Path::new(img).exists());
+    let Some(img) = img else {
+        return;
+    };
+    info!("{} is not mounted, mount {img}", defs::MODULE_DIR);
+    if let Err(e) = ensure_dir_exists(defs::MODULE_DIR)
+        .and_then(|_| mount::mount_image_readonly(img, defs::MODULE_DIR))
+    {
+        warn!("mount {img} failed: {e}");
+    }
+}
+
+const MODULE_SCRIPTS: [&str; 6] = [
+    "post-fs-data.sh",
+    "safemode.sh",
+    "post-mount.sh",
+    "service.sh",
+    "boot-completed.sh",
+    "uninstall.sh",
+];
+
+fn get_module_info(path: &Path, partitions: &[String]) -> Result<serde_json::Value> {
+    let prop = read_module_prop(path)?;
+    let get = |key: &str| prop.get(key).cloned().unwrap_or_default();
+    let incompatible = get_incompatible_reason(path);
+    let contributes = std::iter::once("system".to_string())
+        .chain(partitions.iter().cloned())
+        .filter(|p| path.join(p).is_dir())
+        .collect::<Vec<_>>();
+    let scripts = MODULE_SCRIPTS
+        .iter()
+        .map(|script| (script.to_string(), path.join(script).exists().into()))
+        .collect::<serde_json::Map<_, _>>();
+    Ok(serde_json::json!({
+        "id": get("id"),
+        "name": get("name"),
+        "version": get("version"),
+        "versionCode": get_version_code(&prop),
+        "author": get("author"),
+        "description": expand_template_vars(
+            &get("description"),
+            &get_template_vars(),
+            &path.join("module.prop")
+        ),
+        "enabled": !is_disabled(path) && incompatible.is_none(),
+        "incompatible": incompatible,
+        "update": path.join(defs::UPDATE_FILE_NAME).exists(),
+        "remove": path.join(defs::REMOVE_FILE_NAME).exists(),
+        "blocked": is_blocked(&get_blocklist(), path),
+        "partitions": contributes,
+        "scripts": scripts,
+    }))
+}
+
+// the files module <path> puts on <partition>, compared with the stock files
+fn get_module_changes(path: &Path, partition: &str) -> (Vec<serde_json::Value>, u64) {
+    let root = path.join(partition);
+    let mut changes = Vec::new();
+    let mut total_size = 0;
+    for entry in jwalk::WalkDir::new(&root)
+        .parallelism(jwalk::Parallelism::Serial)
+        .skip_hidden(false)
+        .sort(true)
+        .into_iter()
+        .flatten()
+    {
+        if entry.file_type().is_dir() || entry.file_name() == MODULE_REPLACE_MARKER {
+            continue;
+        }
+        let file = entry.path();
+        let Ok(relative) = file.strip_prefix(&root) else {
+            continue;
+        };
+        let Ok(metadata) = std::fs::symlink_metadata(&file) else {
+            continue;
+        };
+        let target = Path::new("/").join(partition).join(relative);
+        let action = if !metadata.is_file() && !metadata.is_symlink() {
+            // a whiteout made from the replace file
+            "delete"
+        } else if std::fs::symlink_metadata(&target).is_ok() {
+            "replace"
+        } else {
+            "add"
+        };
+        if metadata.is_file() {
+            total_size += metadata.len();
+        }
+        changes.push(serde_json::json!({
+            "path": target.display().to_string(),
+            "action": action,
+            "size": metadata.len(),
+        }));
+    }
+    (changes, total_size)
+}
+
+/// print what module <id> changes: its files on each partition against the stock ones, their
+/// size, its sepolicy rules, scripts and the props of its system.prop, as json
+pub fn print_module_info(id: &str) -> Result<()> {
+    ensure_module_dir_mounted();
+    let path = Path::new(defs::MODULE_DIR).join(id);
+    ensure!(
+        path.join("module.prop").exists(),
+        KsudError::ModuleNotFound(id.to_string())
+    );
+    // we are in a private mount namespace, the stock files are seen without the modules
+    crate::event::detach_modules();
+
+    let partitions = crate::event::get_partitions();
+    let mut info = get_module_info(&path, &partitions)?;
+    let mut files = serde_json::Map::new();
+    let mut total_size = 0;
+    for partition in std::iter::once("system".to_string()).chain(partitions) {
+        if !path.join(&partition).is_dir() {
+            continue;
+        }
+        let (changes, size) = get_module_changes(&path, &partition);
+        total_size += size;
+        files.insert(partition, changes.into());
+    }
+    let system_prop = std::fs::read_to_string(path.join("system.prop"))
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    let sepolicy = get_sepolicy_rule_files(&path)
+        .iter()
+        .map(|rule| rule.display().to_string())
+        .collect::<Vec<_>>();
+
+    info["files"] = files.into();
+    info["total_size"] = total_size.into();
+    info["sepolicy"] = sepolicy.into();
+    info["system_prop"] = system_prop.into();
+    println!("{}", serde_json::to_string_pretty(&info)?);
+    Ok(())
+}
+
+pub fn list_modules(json: bool) -> Result<()> {
+    // a sandbox has the modules as plain dirs
+    if !is_sandboxed() {
+        ensure_module_dir_mounted();
+    }
+    let module_dir = defs::MODULE_DIR;
+    if !json {
+        let modules = _list_modules(module_dir);
+        println!("{}", serde_json::to_string_pretty(&modules)?);
+        return Ok(());
+    }
+
+    let partitions = crate::event::get_partitions();
+    let mut dirs = std::fs::read_dir(&module_dir)
+        .map(|dir| dir.flatten().map(|e| e.path()).collect::<Vec<_>>())
+        .unwrap_or_default();
+    dirs.sort();
+    let modules = dirs
+        .iter()
+        .filter(|path| path.join("module.prop").exists())
+        .filter_map(|path| match get_module_info(path, &partitions) {
+            Ok(info) => Some(info),
+            Err(e) => {
+                warn!("{}: {e}", path.display());
+                None
+            }
+        })
+        .collect::<Vec<_>>();
     println!("{}", serde_json::to_string_pretty(&modules)?);
     Ok(())
 }
+
+// the files ksud creates at the root of a module dir, they are not part of the module
+fn is_runtime_file(name: &str) -> bool {
+    // the runonce markers and the logs are in the working dir, older versions kept them here
+    if name == defs::PARTITION_MANIFEST_NAME
+        || name == LEGACY_RUNONCE_DIR
+        || name.starts_with(defs::MODULE_LOG_FILE_NAME)
+    {
+        return true;
+    }
+    // including the temp names of set_flag
+    [
+        defs::DISABLE_FILE_NAME,
+        defs::UPDATE_FILE_NAME,
+        defs::REMOVE_FILE_NAME,
+    ]
+    .iter()
+    .any(|flag| name == *flag || name == format!(".{flag}.tmp") || name == format!(".{flag}.off"))
+}
+
+fn write_module_zip(module_dir: &Path, out: &str) -> Result<()> {
+    let file = File::create(out).with_context(|| format!("Failed to create {out}"))?;
+    let mut zip = zip::ZipWriter::new(file);
+    for entry in jwalk::WalkDir::new(module_dir)
+        .parallelism(jwalk::Parallelism::Serial)
+        .skip_hidden(false)
+        .sort(true)
+    {
+        let entry = entry?;
+        if entry.depth == 0
+            || (entry.depth == 1 && is_runtime_file(&entry.file_name().to_string_lossy()))
+        {
+            continue;
+        }
+        let path = entry.path();
+        let name = path.strip_prefix(module_dir)?.to_string_lossy().to_string();
+        let metadata = std::fs::symlink_metadata(&path)?;
+        let options = zip::write::FileOptions::default();
+        #[cfg(unix)]
+        let options = options.unix_permissions(metadata.permissions().mode() & 0o7777);
+        if metadata.is_dir() {
+            zip.add_directory(name, options)?;
+        } else if metadata.is_symlink() {
+            let target = std::fs::read_link(&path)?;
+            zip.add_symlink(name, target.to_string_lossy(), options)?;
+        } else if metadata.is_file() {
+            zip.start_file(name, options)?;
+            std::io::copy(&mut File::open(&path)?, &mut zip)?;
+        } else {
+            // the whiteouts made from the replace file on boot
+            info!("skip special file: {}", path.display());
+        }
+    }
+    zip.finish()?;
+    Ok(())
+}
+
+/// pack the installed module <id> into the zip <out>, without the files ksud creates at runtime,
+/// so that it can be installed on another device
+pub fn export_module(id: &str, out: &str) -> Result<()> {
+    ensure_module_dir_mounted();
+    let module_dir = Path::new(defs::MODULE_DIR).join(id);
+    ensure!(
+        module_dir.join("module.prop").exists(),
+        KsudError::ModuleNotFound(id.to_string())
+    );
+
+    let result = write_module_zip(&module_dir, out);
+    if result.is_err() {
+        let _ = std::fs::remove_file(out);
+    }
+    result?;
+    println!("- exported {id} to {out}");
+    Ok(())
+}
+
+pub fn module_status(id: &str) -> Result<()> {
+    let content = std::fs::read_to_string(defs::MOUNT_REPORT_PATH)
+        .with_context(|| "No mount report, modules are not mounted in this boot")?;
+    let report: serde_json::Value = serde_json::from_str(&content)?;
+    let Some(status) = report["modules"].get(id) else {
+        bail!("module: {} not found in mount report", id);
+    };
+    println!("{}", serde_json::to_string_pretty(status)?);
+    Ok(())
+}
@@ -0,0 +1,122 @@
+use anyhow::{bail, ensure, Context, Result};
+use ed25519_dalek::{pkcs8::DecodePublicKey, Signature, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt::Write,
+    fs::File,
+    io::{Read, Seek},
+};
+
+use crate::defs;
+
+// the detached ed25519 signature (64 raw bytes) of the manifest of the other zip entries
+pub const SIGNATURE_ENTRY: &str = "META-INF/ksu/signature";
+
+struct TrustedKey {
+    name: String,
+    key: VerifyingKey,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        let _ = write!(s, "{b:02x}");
+        s
+    })
+}
+
+// the ed25519 public keys in PEM, a `# name` line before a key names its signer
+fn read_trusted_keys(content: &str) -> Result<Vec<TrustedKey>> {
+    let mut keys = Vec::new();
+    let mut name = None;
+    let mut pem: Option<String> = None;
+    for line in content.lines().map(str::trim) {
+        if let Some(block) = pem.as_mut() {
+            block.push_str(line);
+            block.push('\n');
+            if line.starts_with("-----END ") {
+                let key = VerifyingKey::from_public_key_pem(block)
+                    .map_err(|e| anyhow::anyhow!("invalid public key: {e}"))?;
+                let name = name
+                    .take()
+                    .unwrap_or_else(|| to_hex(&Sha256::digest(key.as_bytes())[..8]));
+                keys.push(TrustedKey { name, key });
+                pem = None;
+            }
+        } else if line.starts_with("-----BEGIN ") {
+            pem = Some(format!("{line}\n"));
+        } else if let Some(comment) = line.strip_prefix('#') {
+            name = Some(comment.trim().to_string());
+        }
+    }
+    ensure!(pem.is_none(), "unterminated public key");
+    Ok(keys)
+}
+
+// "<sha256>  <name>" of every file entry but the signature, sorted by name
+fn get_zip_manifest(mut zip: &File) -> Result<(String, Option<Vec<u8>>)> {
+    zip.rewind()?;
+    let mut archive = zip::ZipArchive::new(zip)?;
+    let mut entries = Vec::new();
+    let mut signature = None;
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        if file.is_dir() {
+            continue;
+        }
+        // a name with a newline could forge the lines of the manifest
+        ensure!(
+            !file.name().contains('\n'),
+            "invalid entry name in zip: {:?}",
+            file.name()
+        );
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        if file.name() == SIGNATURE_ENTRY {
+            signature = Some(data);
+            continue;
+        }
+        entries.push((file.name().to_string(), to_hex(&Sha256::digest(&data))));
+    }
+    entries.sort();
+    let manifest = entries
+        .iter()
+        .map(|(name, hash)| format!("{hash}  {name}\n"))
+        .collect::<String>();
+    Ok((manifest, signature))
+}
+
+// the keys of MODULE_TRUSTED_KEYS_PATH, none if it doesn't exist
+fn get_trusted_keys() -> Result<Vec<TrustedKey>> {
+    let Ok(content) = std::fs::read_to_string(defs::MODULE_TRUSTED_KEYS_PATH) else {
+        return Ok(Vec::new());
+    };
+    read_trusted_keys(&content)
+        .with_context(|| format!("Failed to read {}", defs::MODULE_TRUSTED_KEYS_PATH))
+}
+
+/// if a trusted key is configured, then every module zip must be signed
+pub fn is_required() -> Result<bool> {
+    Ok(!get_trusted_keys()?.is_empty())
+}
+
+/// verify the open module zip against the trusted keys, return the signer, or None if no key is
+/// configured
+pub fn verify_module_zip(zip: &File) -> Result<Option<String>> {
+    let keys = get_trusted_keys()?;
+    if keys.is_empty() {
+        return Ok(None);
+    }
+
+    let (manifest, signature) = get_zip_manifest(zip)?;
+    let Some(signature) = signature else {
+        bail!("module is not signed, {SIGNATURE_ENTRY} not found");
+    };
+    let signature =
+        Signature::from_slice(&signature).map_err(|e| anyhow::anyhow!("invalid signature: {e}"))?;
+    for trusted in keys {
+        if trusted.key.verify(manifest.as_bytes(), &signature).is_ok() {
+            return Ok(Some(trusted.name));
+        }
+    }
+    bail!("signature doesn't match any trusted key");
+}
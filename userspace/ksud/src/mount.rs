@@ -1,16 +1,21 @@
-use anyhow::{Ok, Result};
-
-#[cfg(any(target_os = "linux", target_os = "android"))]
-use anyhow::Context;
+use anyhow::{ensure, Context, Ok, Result};
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use retry::delay::NoDelay;
+use std::process::{Command, Stdio};
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use sys_mount::{unmount, FilesystemType, Mount, MountFlags, Unmount, UnmountFlags};
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use jwalk::{Parallelism::Serial, WalkDir};
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use procfs::process::{MountInfo, Process};
 #[cfg(any(target_os = "linux", target_os = "android"))]
-use std::collections::HashSet;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use crate::error::KsudError;
 
 pub struct AutoMountExt4 {
     mnt: String,
@@ -62,6 +67,11 @@ impl AutoMountExt4 {
         unimplemented!()
     }
 
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn umount(&self) -> Result<()> {
+        unimplemented!()
+    }
+
     #[cfg(any(target_os = "linux", target_os = "android"))]
     pub fn umount(&self) -> Result<()> {
         if let Some(ref mount) = self.mount {
@@ -93,6 +103,153 @@ impl Drop for AutoMountExt4 {
     }
 }
 
+// read-only and compressed module image, see `module::rebuild_erofs`
+pub struct AutoMountErofs {
+    mnt: String,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    mount: Option<Mount>,
+    auto_umount: bool,
+}
+
+impl AutoMountErofs {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn try_new(src: &str, mnt: &str, auto_umount: bool) -> Result<Self> {
+        let result = Mount::builder()
+            .fstype(FilesystemType::from("erofs"))
+            .flags(MountFlags::RDONLY)
+            .mount(src, mnt);
+        let mount = match result {
+            std::result::Result::Ok(mount) => Some(mount),
+            Err(e) => {
+                println!("- Mount failed: {e}, retry with system mount");
+                let status = Command::new("mount")
+                    .args(["-t", "erofs", "-o", "ro", src, mnt])
+                    .status()
+                    .with_context(|| format!("mount partition: {src} -> {mnt} failed"))?;
+                ensure!(
+                    status.success(),
+                    "mount partition: {src} -> {mnt} failed: {status}"
+                );
+                None
+            }
+        };
+        Ok(Self {
+            mnt: mnt.to_string(),
+            mount,
+            auto_umount,
+        })
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn try_new(_src: &str, _mnt: &str, _auto_umount: bool) -> Result<Self> {
+        unimplemented!()
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    pub fn umount(&self) -> Result<()> {
+        unimplemented!()
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    pub fn umount(&self) -> Result<()> {
+        if let Some(ref mount) = self.mount {
+            mount
+                .unmount(UnmountFlags::empty())
+                .map_err(|e| anyhow::anyhow!(e))
+        } else {
+            let result = Command::new("umount").arg(&self.mnt).status();
+            if let Err(e) = result {
+                Err(anyhow::anyhow!("umount: {} failed: {e}", self.mnt))
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+impl Drop for AutoMountErofs {
+    fn drop(&mut self) {
+        log::info!(
+            "AutoMountErofs drop: {}, auto_umount: {}",
+            self.mnt,
+            self.auto_umount
+        );
+        if self.auto_umount {
+            let _ = self.umount();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageType {
+    Ext4,
+    Erofs,
+}
+
+// both superblocks start at 1024
+const EXT4_MAGIC_OFFSET: u64 = 1024 + 0x38;
+const EXT4_MAGIC: [u8; 2] = 0xEF53u16.to_le_bytes();
+const EROFS_MAGIC_OFFSET: u64 = 1024;
+const EROFS_MAGIC: [u8; 4] = 0xE0F5_E1E2u32.to_le_bytes();
+
+/// detect the filesystem of the module image by the superblock magic
+pub fn get_image_type(img: &str) -> Result<ImageType> {
+    use std::io::{Read, Seek, SeekFrom};
+
+    let mut file =
+        std::fs::File::open(img).with_context(|| format!("Failed to open image: {img}"))?;
+    let mut read_at = |offset: u64, buf: &mut [u8]| -> Result<()> {
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)
+            .with_context(|| format!("Failed to read superblock of {img}"))
+    };
+
+    let mut magic = [0u8; 4];
+    read_at(EROFS_MAGIC_OFFSET, &mut magic)?;
+    if magic == EROFS_MAGIC {
+        return Ok(ImageType::Erofs);
+    }
+    let mut magic = [0u8; 2];
+    read_at(EXT4_MAGIC_OFFSET, &mut magic)?;
+    if magic == EXT4_MAGIC {
+        return Ok(ImageType::Ext4);
+    }
+    anyhow::bail!(KsudError::UnknownImage(img.to_string()))
+}
+
+/// if overlayfs is in /proc/filesystems, e.g. "nodev	overlay". a loadable overlay is listed after
+/// its first mount, so a missing entry doesn't mean there is none
+pub fn is_overlayfs_listed() -> bool {
+    let std::result::Result::Ok(filesystems) = std::fs::read_to_string("/proc/filesystems") else {
+        return false;
+    };
+    filesystems
+        .lines()
+        .any(|line| line.split_whitespace().last() == Some("overlay"))
+}
+
+// resize2fs works on 16MB boundaries so that the image doesn't need to grow every time
+const EXT4_RESIZE_ALIGN: u64 = 16 * 1024 * 1024;
+
+/// resize the ext4 image to `size` bytes, rounded up to the next 16MB.
+/// the image must not be mounted.
+pub fn resize_ext4(img: &str, size: u64) -> Result<()> {
+    let size = (size + EXT4_RESIZE_ALIGN - 1) / EXT4_RESIZE_ALIGN * EXT4_RESIZE_ALIGN;
+
+    crate::module::check_image(img)?;
+
+    log::info!("resize {img} to {size}");
+    let result = Command::new("resize2fs")
+        .args([img, &format!("{}K", size / 1024)])
+        .stdout(Stdio::null())
+        .status()
+        .with_context(|| format!("Failed to exec resize2fs {img}"))?;
+    ensure!(result.success(), "Failed to resize2fs: {}", result);
+
+    crate::module::check_image(img)
+}
+
 #[allow(dead_code)]
 #[cfg(any(target_os = "linux", target_os = "android"))]
 fn mount_image(src: &str, target: &str, autodrop: bool) -> Result<()> {
@@ -127,15 +284,487 @@ pub fn umount_dir(src: &str) -> Result<()> {
     Ok(())
 }
 
+// lazy umount, the submounts are detached together
 #[cfg(any(target_os = "linux", target_os = "android"))]
-pub fn mount_overlay(lowerdir: &str, mnt: &str) -> Result<()> {
+pub fn detach_dir(src: &str) -> Result<()> {
+    unmount(src, UnmountFlags::DETACH).with_context(|| format!("Failed to detach {src}"))?;
+    Ok(())
+}
+
+// mount points at or below dir, stacked mounts of one path are listed once per mount
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn get_mount_points_under(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mountinfos = Process::myself()?.mountinfo()?;
+    Ok(mountinfos
+        .into_iter()
+        .map(|m| m.mount_point)
+        .filter(|mnt| mnt.starts_with(dir))
+        .collect())
+}
+
+/// umount everything mounted at or below dir, the deepest first
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn umount_dir_recursive(dir: &str) -> Result<()> {
+    // stacked mounts may need a few rounds
+    const MAX_ROUNDS: usize = 8;
+    let dir = Path::new(dir);
+    for _ in 0..MAX_ROUNDS {
+        let mut mounts = get_mount_points_under(dir)?;
+        if mounts.is_empty() {
+            return Ok(());
+        }
+        mounts.sort_by_key(|mnt| std::cmp::Reverse(mnt.components().count()));
+        for mnt in mounts {
+            log::info!("umount {} under {}", mnt.display(), dir.display());
+            if let Err(e) = unmount(&mnt, UnmountFlags::DETACH) {
+                log::warn!("umount {} failed: {e}", mnt.display());
+            }
+        }
+    }
+    ensure!(
+        get_mount_points_under(dir)?.is_empty(),
+        "{} is still mounted",
+        dir.display()
+    );
+    Ok(())
+}
+
+/// make `path` an overlayfs whiteout, it hides the same path of the lower layers
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn create_whiteout<P: AsRef<Path>>(path: P) -> Result<()> {
+    use std::os::unix::{ffi::OsStrExt, fs::FileTypeExt, fs::MetadataExt};
+
+    let path = path.as_ref();
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())?;
+    // a whiteout is a 0/0 char device
+    let ret = unsafe { libc::mknod(c_path.as_ptr(), libc::S_IFCHR, 0) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to create whiteout {}", path.display()));
+    }
+    crate::restorecon::setsyscon(path)?;
+
+    let metadata = std::fs::symlink_metadata(path)?;
+    let con = crate::restorecon::getcon(path)?;
+    log::info!(
+        "whiteout: {} mode: {:o} rdev: {} context: {con}",
+        path.display(),
+        metadata.mode(),
+        metadata.rdev()
+    );
+    ensure!(
+        metadata.file_type().is_char_device() && metadata.rdev() == 0,
+        "{} is not a whiteout",
+        path.display()
+    );
+    Ok(())
+}
+
+/// mark the dir opaque, the same dir of the lower layers is hidden instead of merged
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_opaque<P: AsRef<Path>>(dir: P) -> Result<()> {
+    extattr::setxattr(
+        dir.as_ref(),
+        "trusted.overlay.opaque",
+        "y",
+        extattr::Flags::empty(),
+    )
+    .with_context(|| format!("Failed to set opaque of {}", dir.as_ref().display()))
+}
+
+/// if something is mounted at dir
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn is_mounted(dir: &str) -> bool {
+    let dir = Path::new(dir);
+    Process::myself()
+        .and_then(|p| p.mountinfo())
+        .map_or(false, |mounts| mounts.iter().any(|m| m.mount_point == dir))
+}
+
+/// remount the filesystem mounted at dir read-only or read-write, the superblock is changed so
+/// it applies to the mounts of it in every namespace
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn remount(dir: &str, readonly: bool) -> Result<()> {
+    let c_dir = std::ffi::CString::new(dir)?;
+    let mut flags = libc::MS_REMOUNT;
+    if readonly {
+        flags |= libc::MS_RDONLY;
+    }
+    let ret = unsafe {
+        libc::mount(
+            std::ptr::null(),
+            c_dir.as_ptr(),
+            std::ptr::null(),
+            flags,
+            std::ptr::null(),
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error()).with_context(|| {
+            format!(
+                "Failed to remount {dir} {}",
+                if readonly { "ro" } else { "rw" }
+            )
+        });
+    }
+    Ok(())
+}
+
+/// if the filesystem mounted at dir is read-only
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn is_readonly(dir: &str) -> bool {
+    let dir = Path::new(dir);
+    Process::myself()
+        .and_then(|p| p.mountinfo())
+        .map_or(false, |mounts| {
+            mounts
+                .iter()
+                .rev()
+                .find(|m| m.mount_point == dir)
+                .map_or(false, |m| m.super_options.contains_key("ro"))
+        })
+}
+
+/// run f with the filesystem mounted at dir writable, it is made read-only again after f if it
+/// was. an erofs image can't be written, that is KsudError::ReadOnly before f is run
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn with_writable<T>(dir: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    if !is_readonly(dir) {
+        return f();
+    }
+    let path = Path::new(dir);
+    let erofs = Process::myself()
+        .and_then(|p| p.mountinfo())
+        .map_or(false, |mounts| {
+            mounts
+                .iter()
+                .rev()
+                .find(|m| m.mount_point == path)
+                .map_or(false, |m| m.fs_type == "erofs")
+        });
+    ensure!(!erofs, KsudError::ReadOnly(dir.to_string()));
+
+    log::info!("{dir} is read-only, remount it read-write for a moment");
+    remount(dir, false)?;
+    let result = f();
+    if let Err(e) = remount(dir, true) {
+        log::warn!("make {dir} read-only again failed: {e:#}");
+    }
+    result
+}
+
+/// the image is attached to a loop device, e.g. it is mounted in some namespace
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn is_image_mounted(img: &str) -> bool {
+    !get_loop_devs(img).is_empty()
+}
+
+// `major:minor` of the loop devices backed by img
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn get_loop_devs(img: &str) -> Vec<String> {
+    let Some(img) = std::fs::canonicalize(img).ok() else {
+        return Vec::new();
+    };
+    let Some(dir) = std::fs::read_dir("/sys/block").ok() else {
+        return Vec::new();
+    };
+    dir.flatten()
+        .filter(|entry| {
+            std::fs::read_to_string(entry.path().join("loop/backing_file"))
+                .map_or(false, |file| Path::new(file.trim()) == img)
+        })
+        .filter_map(|entry| std::fs::read_to_string(entry.path().join("dev")).ok())
+        .map(|dev| dev.trim().to_string())
+        .collect()
+}
+
+/// where img is mounted through a loop device, None if it isn't
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn get_image_mount_point(img: &str) -> Option<String> {
+    let devs = get_loop_devs(img);
+    if devs.is_empty() {
+        return None;
+    }
+    Process::myself()
+        .and_then(|p| p.mountinfo())
+        .ok()?
+        .into_iter()
+        .find(|m| devs.contains(&m.majmin))
+        .map(|m| m.mount_point.to_string_lossy().into_owned())
+}
+
+/// discard the free blocks of the filesystem mounted at dir, a loop device punches them out of
+/// its image file. the bytes discarded
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn fstrim(dir: &str) -> Result<u64> {
+    use std::os::fd::AsRawFd;
+
+    // struct fstrim_range and FITRIM of linux/fs.h
+    #[repr(C)]
+    struct FstrimRange {
+        start: u64,
+        len: u64,
+        minlen: u64,
+    }
+    const FITRIM: u64 = 0xc018_5879;
+
+    let file = std::fs::File::open(dir).with_context(|| format!("Failed to open {dir}"))?;
+    let mut range = FstrimRange {
+        start: 0,
+        len: u64::MAX,
+        minlen: 0,
+    };
+    if unsafe { libc::ioctl(file.as_raw_fd(), FITRIM as _, &mut range) } != 0 {
+        anyhow::bail!("Failed to trim {dir}: {}", std::io::Error::last_os_error());
+    }
+    Ok(range.len)
+}
+
+/// mount the module image read-only, so that it can be read without changing it
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn mount_image_readonly(img: &str, mnt: &str) -> Result<()> {
+    let fstype = match get_image_type(img)? {
+        ImageType::Ext4 => "ext4",
+        ImageType::Erofs => "erofs",
+    };
+    Mount::builder()
+        .fstype(FilesystemType::from(fstype))
+        .flags(MountFlags::RDONLY)
+        .mount(img, mnt)
+        .with_context(|| format!("Failed to mount {img} to {mnt}"))?;
+    Ok(())
+}
+
+// mount points outside of dir which are bind mounted from the filesystem mounted at dir,
+// e.g. the files of magic mount
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn get_bind_mounts_of(dir: &str) -> Vec<String> {
+    let std::result::Result::Ok(process) = Process::myself() else {
+        return Vec::new();
+    };
+    let std::result::Result::Ok(mountinfos) = process.mountinfo() else {
+        return Vec::new();
+    };
+    let dir = Path::new(dir);
+    let Some(majmin) = mountinfos
+        .iter()
+        .find(|m| m.mount_point == dir)
+        .map(|m| m.majmin.clone())
+    else {
+        return Vec::new();
+    };
+    mountinfos
+        .into_iter()
+        .filter(|m| m.majmin == majmin && !m.mount_point.starts_with(dir))
+        .filter_map(|m| m.mount_point.to_str().map(ToString::to_string))
+        .collect()
+}
+
+// all overlay mount points and their lowerdirs, the top layer comes first
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn get_overlay_mounts() -> Vec<(String, Vec<String>)> {
+    let std::result::Result::Ok(process) = Process::myself() else {
+        return Vec::new();
+    };
+    let std::result::Result::Ok(mountinfos) = process.mountinfo() else {
+        return Vec::new();
+    };
+    mountinfos
+        .into_iter()
+        .filter(|m| m.fs_type == "overlay")
+        .filter_map(|m| {
+            let mnt = m.mount_point.to_str()?.to_string();
+            let lowerdir = m.super_options.get("lowerdir")?.as_ref()?;
+            let lowerdirs = lowerdir.split(':').map(ToString::to_string).collect();
+            Some((mnt, lowerdirs))
+        })
+        .collect()
+}
+
+// overlay_options of the config, the dirs are always set by ksud
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn get_overlay_options() -> Vec<String> {
+    crate::config::get()
+        .overlay_options
+        .iter()
+        .filter(|opt| {
+            let name = opt.split('=').next().unwrap_or_default();
+            let dir = matches!(name, "lowerdir" | "upperdir" | "workdir");
+            if dir {
+                log::warn!("overlay option: {opt} is not allowed, ignore it");
+            }
+            !dir
+        })
+        .cloned()
+        .collect()
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn mount_overlay_with_data(data: &str, mnt: &str) -> Result<()> {
     Mount::builder()
         .fstype(FilesystemType::from("overlay"))
         .flags(MountFlags::RDONLY)
-        .data(&format!("lowerdir={lowerdir}"))
+        .data(data)
         .mount("overlay", mnt)
         .map(|_| ())
-        .map_err(|e| anyhow::anyhow!("mount partition: {mnt} overlay failed: {e}"))
+        .map_err(|e| {
+            // ENODEV is the kernel without overlayfs, a loadable one is loaded by this mount
+            if e.raw_os_error() == Some(libc::ENODEV) {
+                anyhow::Error::from(KsudError::OverlayUnsupported)
+            } else {
+                anyhow::anyhow!("mount partition: {mnt} overlay failed: {e}")
+            }
+        })
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn mount_overlay(lowerdir: &str, mnt: &str) -> Result<()> {
+    let minimal = format!("lowerdir={lowerdir}");
+    let options = get_overlay_options();
+    if options.is_empty() {
+        return mount_overlay_with_data(&minimal, mnt);
+    }
+    let extended = format!("{minimal},{}", options.join(","));
+    // not every kernel supports them, fallback to the minimal options
+    mount_overlay_with_data(&extended, mnt).or_else(|e| {
+        if matches!(
+            e.downcast_ref::<KsudError>(),
+            Some(KsudError::OverlayUnsupported)
+        ) {
+            return Err(e);
+        }
+        log::warn!(
+            "{e}, retry {mnt} without the options: {}",
+            options.join(",")
+        );
+        mount_overlay_with_data(&minimal, mnt)
+    })
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn bind_mount<P: AsRef<Path>, Q: AsRef<Path>>(src: P, target: Q) -> Result<()> {
+    Mount::builder()
+        .fstype(FilesystemType::from("none"))
+        .flags(MountFlags::BIND)
+        .mount(src.as_ref(), target.as_ref())
+        .map(|_| ())
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "bind mount: {} -> {} failed: {e}",
+                src.as_ref().display(),
+                target.as_ref().display()
+            )
+        })
+}
+
+// bind mount every file in `lowerdirs` over the same file in `target`, the first lowerdir wins.
+// files which don't exist in `target` can't be added in this way and are skipped.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn magic_mount(lowerdirs: &[String], target: &str) -> Result<()> {
+    let mut mounted: HashSet<PathBuf> = HashSet::new();
+    for lowerdir in lowerdirs {
+        for entry in WalkDir::new(lowerdir).parallelism(Serial) {
+            let std::result::Result::Ok(entry) = entry else {
+                continue;
+            };
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let src = entry.path();
+            let std::result::Result::Ok(relative) = src.strip_prefix(lowerdir) else {
+                continue;
+            };
+            let dst = Path::new(target).join(relative);
+            if !dst.is_file() {
+                log::warn!("magic mount: {} not exist, skip", dst.display());
+                continue;
+            }
+            if !mounted.insert(dst.clone()) {
+                // already covered by an upper layer
+                continue;
+            }
+            if let Err(e) = bind_mount(&src, &dst) {
+                log::warn!("magic mount: {e}");
+            }
+        }
+    }
+    log::info!("magic mount: {} files to {target}", mounted.len());
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn bind_mount<P: AsRef<std::path::Path>, Q: AsRef<std::path::Path>>(
+    _src: P,
+    _target: Q,
+) -> Result<()> {
+    unimplemented!()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn magic_mount(_lowerdirs: &[String], _target: &str) -> Result<()> {
+    unimplemented!()
+}
+
+// the kernel copies at most one page of mount data, including the trailing NUL
+const MAX_OVERLAY_DATA_LEN: usize = 4095;
+
+// mount `layers` (top first) over `mnt` with `mnt` itself as the lowest layer.
+// if the lowerdir option is too long, the layers are split and mounted as chained overlays,
+// each one on top of the previous. return how many overlays are stacked on `mnt`.
+pub fn mount_overlay_layers(layers: &[String], mnt: &str) -> Result<usize> {
+    mount_overlay_layers_at(layers, mnt, mnt)
+}
+
+// same as `mount_overlay_layers` but `lowest` is the lowest layer, e.g. to overlay
+// a partition on a throwaway directory instead of itself
+pub fn mount_overlay_layers_at(layers: &[String], lowest: &str, mnt: &str) -> Result<usize> {
+    // chained overlays use the previous one on `mnt` as the lowest layer
+    let base_len = "lowerdir=".len() + lowest.len().max(mnt.len());
+    let mut chunks: Vec<Vec<&str>> = Vec::new();
+    let mut chunk: Vec<&str> = Vec::new();
+    let mut len = base_len;
+    // fill the chunks from the bottom layer
+    for layer in layers.iter().rev() {
+        anyhow::ensure!(
+            base_len + layer.len() + 1 <= MAX_OVERLAY_DATA_LEN,
+            "lowerdir: {layer} is too long"
+        );
+        if len + layer.len() + 1 > MAX_OVERLAY_DATA_LEN {
+            chunks.push(std::mem::take(&mut chunk));
+            len = base_len;
+        }
+        len += layer.len() + 1;
+        chunk.push(layer);
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+
+    if chunks.len() > 1 {
+        log::warn!(
+            "lowerdir of {mnt} exceeds {MAX_OVERLAY_DATA_LEN} bytes, chain {} overlays",
+            chunks.len()
+        );
+    }
+
+    for (i, chunk) in chunks.iter().enumerate() {
+        let bottom = if i == 0 { lowest } else { mnt };
+        let lowerdir = chunk
+            .iter()
+            .rev()
+            .chain(std::iter::once(&bottom))
+            .copied()
+            .collect::<Vec<_>>()
+            .join(":");
+        if let Err(e) = mount_overlay(&lowerdir, mnt) {
+            // don't leave a half stacked overlay
+            for _ in 0..i {
+                let _ = umount_dir(mnt);
+            }
+            return Err(e);
+        }
+    }
+    Ok(chunks.len())
 }
 
 #[cfg(not(any(target_os = "linux", target_os = "android")))]
@@ -148,6 +777,76 @@ pub fn umount_dir(_src: &str) -> Result<()> {
     unimplemented!()
 }
 
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn detach_dir(_src: &str) -> Result<()> {
+    unimplemented!()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn umount_dir_recursive(_dir: &str) -> Result<()> {
+    unimplemented!()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn is_mounted(_dir: &str) -> bool {
+    unimplemented!()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn remount(_dir: &str, _readonly: bool) -> Result<()> {
+    unimplemented!()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn with_writable<T>(_dir: &str, _f: impl FnOnce() -> Result<T>) -> Result<T> {
+    unimplemented!()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn is_readonly(_dir: &str) -> bool {
+    unimplemented!()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn is_image_mounted(_img: &str) -> bool {
+    unimplemented!()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn get_image_mount_point(_img: &str) -> Option<String> {
+    unimplemented!()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn fstrim(_dir: &str) -> Result<u64> {
+    unimplemented!()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn mount_image_readonly(_img: &str, _mnt: &str) -> Result<()> {
+    unimplemented!()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn create_whiteout<P: AsRef<std::path::Path>>(_path: P) -> Result<()> {
+    unimplemented!()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn set_opaque<P: AsRef<std::path::Path>>(_dir: P) -> Result<()> {
+    unimplemented!()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn get_bind_mounts_of(_dir: &str) -> Vec<String> {
+    unimplemented!()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn get_overlay_mounts() -> Vec<(String, Vec<String>)> {
+    unimplemented!()
+}
+
 #[cfg(not(any(target_os = "linux", target_os = "android")))]
 pub fn mount_overlay(_lowerdir: &str, _mnt: &str) -> Result<()> {
     unimplemented!()
@@ -164,6 +863,10 @@ impl StockOverlay {
         unimplemented!()
     }
 
+    pub fn new_for(_mount_points: &[String]) -> Self {
+        unimplemented!()
+    }
+
     pub fn mount_all(&self) {
         unimplemented!()
     }
@@ -190,6 +893,21 @@ impl StockOverlay {
         Self { mountinfos: vec![] }
     }
 
+    /// only the stock overlays on, above or below `mount_points`, the other ones are not touched
+    pub fn new_for(mount_points: &[String]) -> Self {
+        let mut stock = Self::new();
+        stock.mountinfos.retain(|m| {
+            let intersects = mount_points
+                .iter()
+                .any(|p| m.mount_point.starts_with(p) || Path::new(p).starts_with(&m.mount_point));
+            if !intersects {
+                log::info!("stock overlay: {} is not touched", m.mount_point.display());
+            }
+            intersects
+        });
+        stock
+    }
+
     pub fn mount_all(&self) {
         log::info!("stock overlay: mount all: {:?}", self.mountinfos);
         for mount in self.mountinfos.clone() {
@@ -258,19 +976,57 @@ impl StockOverlay {
 // so we to remount these mounts when we do overlay mount.
 // this is a workaround, we should find a better way to do this.
 #[derive(Debug)]
+// the path which a bind mount at `dst` was made from, found through another mount of the same
+// filesystem outside of `mnt`, the overlay hides the ones inside it. None if `dst` holds the
+// root of its filesystem, Err if it is a bind whose source isn't visible
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn get_bind_source(mountinfos: &[MountInfo], mnt: &str, dst: &Path) -> Result<Option<PathBuf>> {
+    let Some(info) = mountinfos.iter().rev().find(|m| m.mount_point == dst) else {
+        return Ok(None);
+    };
+    if info.root == "/" {
+        return Ok(None);
+    }
+    let root = Path::new(&info.root);
+    let source = mountinfos
+        .iter()
+        .filter(|m| m.majmin == info.majmin && !m.mount_point.starts_with(mnt))
+        .filter(|m| root.starts_with(&m.root))
+        .max_by_key(|m| m.root.len())
+        .and_then(|m| {
+            let relative = root.strip_prefix(&m.root).ok()?;
+            Some(m.mount_point.join(relative))
+        });
+    match source {
+        Some(source) => Ok(Some(source)),
+        None => anyhow::bail!(
+            "{} is a bind of {} in {}, which is not mounted elsewhere",
+            dst.display(),
+            info.root,
+            info.mount_source.as_deref().unwrap_or_default()
+        ),
+    }
+}
+
 pub struct StockMount {
     mnt: String,
     #[cfg(any(target_os = "linux", target_os = "android"))]
     mountlist: proc_mounts::MountList,
+    // /proc/mounts has no root of a bind mount, a bind of a sub dir looks like a mount of the
+    // whole filesystem there
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    mountinfos: Vec<MountInfo>,
 }
 
 #[cfg(any(target_os = "linux", target_os = "android"))]
 impl StockMount {
     pub fn new(mnt: &str) -> Result<Self> {
         let mountlist = proc_mounts::MountList::new()?;
+        let mountinfos = Process::myself()?.mountinfo()?;
         Ok(Self {
             mnt: mnt.to_string(),
             mountlist,
+            mountinfos,
         })
     }
 
@@ -289,6 +1045,23 @@ impl StockMount {
         mounts.reverse(); // remount it in order
         log::info!("remount stock for {} : {:?}", self.mnt, mounts);
         for m in mounts {
+            // a bind of a sub dir, e.g. /vendor/firmware from /data/vendor/firmware, is made
+            // again from the same dir instead of mounting the whole filesystem
+            match get_bind_source(&self.mountinfos, &self.mnt, &m.dest) {
+                std::result::Result::Ok(Some(source)) => {
+                    log::info!("begin rebind: {} -> {}", source.display(), m.dest.display());
+                    if let Err(e) = bind_mount(&source, &m.dest) {
+                        log::error!("rebind failed: {e}");
+                    }
+                    continue;
+                }
+                std::result::Result::Ok(None) => {}
+                Err(e) => {
+                    log::error!("remount skipped: {e}");
+                    continue;
+                }
+            }
+
             let src = std::fs::canonicalize(&m.source)?;
 
             let src = src.to_str().ok_or(anyhow::anyhow!("Failed to get src"))?;
@@ -331,3 +1104,42 @@ impl StockMount {
         unimplemented!()
     }
 }
+
+#[cfg(all(test, any(target_os = "linux", target_os = "android")))]
+mod tests {
+    use super::*;
+
+    fn mountinfos(lines: &[&str]) -> Vec<MountInfo> {
+        lines
+            .iter()
+            .map(|line| MountInfo::from_line(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn bind_source_is_the_dir_of_another_mount() {
+        let infos = mountinfos(&[
+            "30 1 253:5 / /data rw,nosuid - f2fs /dev/block/dm-5 rw",
+            "40 31 253:5 /vendor/firmware /vendor/firmware ro - f2fs /dev/block/dm-5 rw",
+        ]);
+        let source = get_bind_source(&infos, "/vendor/", Path::new("/vendor/firmware")).unwrap();
+        assert_eq!(source, Some(PathBuf::from("/data/vendor/firmware")));
+    }
+
+    #[test]
+    fn bind_source_of_a_filesystem_root_is_none() {
+        let infos = mountinfos(&["41 31 8:17 / /vendor/dsp ro - ext4 /dev/block/sdb1 ro"]);
+        let source = get_bind_source(&infos, "/vendor/", Path::new("/vendor/dsp")).unwrap();
+        assert_eq!(source, None);
+    }
+
+    #[test]
+    fn bind_source_which_is_not_mounted_elsewhere_fails() {
+        let infos = mountinfos(&[
+            "42 31 8:18 / /vendor/bt ro - ext4 /dev/block/sdb2 ro",
+            "43 31 8:18 /bt_firmware /vendor/bt_firmware ro - ext4 /dev/block/sdb2 ro",
+        ]);
+        // the only other mount of the filesystem is inside the partition
+        assert!(get_bind_source(&infos, "/vendor/", Path::new("/vendor/bt_firmware")).is_err());
+    }
+}
@@ -0,0 +1,145 @@
+use anyhow::{Context, Result};
+use std::{
+    io::Write,
+    sync::{mpsc, Mutex},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::defs;
+
+// (name, milliseconds) of each timed step in this run, in the order they finished
+static TIMINGS: Mutex<Vec<(String, u128)>> = Mutex::new(Vec::new());
+
+// a stage which runs longer than this is logged by the watchdog
+const STAGE_SLOW_SECS: u64 = 30;
+
+// the watchdog records the stages in BOOT_STAGE_PATH, so that the next boot knows where it hung
+static WATCHDOG: Mutex<Option<(mpsc::Sender<String>, JoinHandle<()>)>> = Mutex::new(None);
+
+fn write_stage(stage: &str) {
+    let result = std::fs::File::create(defs::BOOT_STAGE_PATH).and_then(|mut f| {
+        f.write_all(stage.as_bytes())?;
+        f.sync_all()
+    });
+    if let Err(e) = result {
+        log::warn!("write boot stage {stage} failed: {e}");
+    }
+}
+
+/// warn if the last boot didn't finish post-fs-data, and start recording the stages of this one
+pub fn start_watchdog() {
+    if let Ok(stage) = std::fs::read_to_string(defs::BOOT_STAGE_PATH) {
+        if stage.trim() != defs::BOOT_STAGE_COMPLETE {
+            log::warn!("the last boot hung at stage: {}", stage.trim());
+        }
+    }
+
+    let (sender, receiver) = mpsc::channel::<String>();
+    let handle = std::thread::spawn(move || {
+        let mut stage = String::from("start");
+        let mut since = Instant::now();
+        write_stage(&stage);
+        loop {
+            match receiver.recv_timeout(Duration::from_secs(STAGE_SLOW_SECS)) {
+                Ok(next) => {
+                    stage = next;
+                    since = Instant::now();
+                    write_stage(&stage);
+                    if stage == defs::BOOT_STAGE_COMPLETE {
+                        return;
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    log::warn!(
+                        "stage {stage} is running for {}s",
+                        since.elapsed().as_secs()
+                    );
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => return,
+            }
+        }
+    });
+    if let Ok(mut watchdog) = WATCHDOG.lock() {
+        *watchdog = Some((sender, handle));
+    }
+}
+
+fn enter_stage(stage: &str) {
+    if let Ok(watchdog) = WATCHDOG.lock() {
+        if let Some((sender, _)) = watchdog.as_ref() {
+            let _ = sender.send(stage.to_string());
+        }
+    }
+}
+
+/// record that post-fs-data finished and wait for the watchdog to write it
+pub fn stop_watchdog() {
+    let watchdog = WATCHDOG.lock().ok().and_then(|mut w| w.take());
+    if let Some((sender, handle)) = watchdog {
+        let _ = sender.send(defs::BOOT_STAGE_COMPLETE.to_string());
+        let _ = handle.join();
+    }
+}
+
+/// run `f` and record how long it takes as `name`, it is also the stage reported to the watchdog
+pub fn time<T>(name: &str, f: impl FnOnce() -> T) -> T {
+    enter_stage(name);
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed().as_millis();
+    log::info!("{name} took {elapsed}ms");
+    if let Ok(mut timings) = TIMINGS.lock() {
+        timings.push((name.to_string(), elapsed));
+    }
+    result
+}
+
+/// write the timings of this boot to `defs::BOOT_TIMING_PATH`
+pub fn save() -> Result<()> {
+    let timings = TIMINGS
+        .lock()
+        .map_err(|_| anyhow::anyhow!("boot timings are poisoned"))?;
+    let steps = timings
+        .iter()
+        .map(|(name, ms)| serde_json::json!({ "name": name, "ms": *ms as u64 }))
+        .collect::<Vec<_>>();
+    let content = serde_json::to_string_pretty(&serde_json::json!({
+        "version": crate::ksu::get_version_info(),
+        "steps": steps,
+    }))?;
+    std::fs::write(defs::BOOT_TIMING_PATH, content)
+        .with_context(|| format!("Failed to write {}", defs::BOOT_TIMING_PATH))
+}
+
+/// print the timings saved in the last boot
+pub fn print_last_boot() -> Result<()> {
+    let content = std::fs::read_to_string(defs::BOOT_TIMING_PATH).with_context(|| {
+        format!(
+            "Failed to read {}, no boot recorded",
+            defs::BOOT_TIMING_PATH
+        )
+    })?;
+    let timings: serde_json::Value = serde_json::from_str(&content)?;
+    let version = &timings["version"];
+    if version.is_object() {
+        println!(
+            "ksud: {} kernel: {}",
+            version["ksud"].as_str().unwrap_or_default(),
+            version["kernel"]
+        );
+    }
+    let steps = timings["steps"].as_array().cloned().unwrap_or_default();
+    let width = steps
+        .iter()
+        .filter_map(|step| step["name"].as_str())
+        .map(str::len)
+        .max()
+        .unwrap_or(0);
+    for step in steps {
+        let name = step["name"].as_str().unwrap_or_default();
+        let ms = step["ms"].as_u64().unwrap_or_default();
+        println!("{name:width$} {ms:>6}ms");
+    }
+    Ok(())
+}
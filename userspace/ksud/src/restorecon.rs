@@ -5,10 +5,10 @@ use std::path::Path;
 #[cfg(any(target_os = "linux", target_os = "android"))]
 use anyhow::{Context, Ok};
 #[cfg(any(target_os = "linux", target_os = "android"))]
-use extattr::{setxattr, Flags as XattrFlags};
+use extattr::{lgetxattr, lsetxattr, setxattr, Flags as XattrFlags};
 
 const SYSTEM_CON: &str = "u:object_r:system_file:s0";
-const _ADB_CON: &str = "u:object_r:adb_data_file:s0";
+pub const ADB_CON: &str = "u:object_r:adb_data_file:s0";
 const SELINUX_XATTR: &str = "security.selinux";
 
 pub fn setcon<P: AsRef<Path>>(path: P, con: &str) -> Result<()> {
@@ -22,6 +22,52 @@ pub fn setcon<P: AsRef<Path>>(path: P, con: &str) -> Result<()> {
     Ok(())
 }
 
+/// set the context of the path itself, a symlink is not followed
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn lsetcon<P: AsRef<Path>>(path: P, con: &str) -> Result<()> {
+    lsetxattr(&path, SELINUX_XATTR, con, XattrFlags::empty()).with_context(|| {
+        format!(
+            "Failed to change SELinux context for {}",
+            path.as_ref().display()
+        )
+    })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn lsetcon<P: AsRef<Path>>(_path: P, _con: &str) -> Result<()> {
+    unimplemented!()
+}
+
+/// label the ksud binaries with the context the daemon runs them with, and log what we got
+pub fn set_binary_con<P: AsRef<Path>>(path: P) -> Result<()> {
+    let path = path.as_ref();
+    lsetcon(path, ADB_CON)?;
+    let con = getcon(path)?;
+    if con == ADB_CON {
+        log::info!("context of {}: {con}", path.display());
+    } else {
+        log::warn!("context of {}: {con}, expected: {ADB_CON}", path.display());
+    }
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn getcon<P: AsRef<Path>>(path: P) -> Result<String> {
+    let con = lgetxattr(&path, SELINUX_XATTR).with_context(|| {
+        format!(
+            "Failed to get SELinux context of {}",
+            path.as_ref().display()
+        )
+    })?;
+    let con = String::from_utf8_lossy(&con);
+    Ok(con.trim_end_matches('\0').to_string())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn getcon<P: AsRef<Path>>(_path: P) -> Result<String> {
+    unimplemented!()
+}
+
 #[cfg(any(target_os = "linux", target_os = "android"))]
 pub fn setsyscon<P: AsRef<Path>>(path: P) -> Result<()> {
     setcon(path, SYSTEM_CON)
@@ -32,6 +78,10 @@ pub fn setsyscon<P: AsRef<Path>>(path: P) -> Result<()> {
     unimplemented!()
 }
 
+pub fn lsetsyscon<P: AsRef<Path>>(path: P) -> Result<()> {
+    lsetcon(path, SYSTEM_CON)
+}
+
 pub fn restore_syscon<P: AsRef<Path>>(dir: P) -> Result<()> {
     for dir_entry in WalkDir::new(dir).parallelism(Serial) {
         if let Some(path) = dir_entry.ok().map(|dir_entry| dir_entry.path()) {
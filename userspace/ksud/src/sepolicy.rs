@@ -739,6 +739,32 @@ pub fn apply_file<P: AsRef<Path>>(path: P) -> Result<()> {
     live_patch(&input)
 }
 
+/// apply the rule file line by line, a line which fails to parse or load is logged with its
+/// line number and the rest are still applied, return each rule with its error if it failed
+pub fn apply_file_lenient<P: AsRef<Path>>(path: P) -> Result<Vec<(String, Option<String>)>> {
+    let path = path.as_ref();
+    let input = std::fs::read_to_string(path)?;
+    let mut rules = Vec::new();
+    for (number, line) in input.lines().enumerate() {
+        for rule in line.split(';').map(str::trim) {
+            if rule.is_empty() || rule.starts_with('#') {
+                continue;
+            }
+            let result = parse_sepolicy(rule, true).and_then(|statements| {
+                statements
+                    .iter()
+                    .try_for_each(|statement| apply_one_rule(statement, true))
+            });
+            let error = result.err().map(|e| {
+                log::warn!("{}:{}: {rule}: {e}", path.display(), number + 1);
+                e.to_string()
+            });
+            rules.push((rule.to_string(), error));
+        }
+    }
+    Ok(rules)
+}
+
 pub fn check_rule(policy: &str) -> Result<()> {
     let path = Path::new(policy);
     let policy = if path.exists() {
@@ -0,0 +1,78 @@
+use anyhow::{bail, Result};
+use log::warn;
+use std::path::Path;
+
+use crate::{defs, module};
+
+// a slow server must not keep the cli waiting
+#[cfg(feature = "update-check")]
+const FETCH_TIMEOUT_SECS: u64 = 10;
+
+#[cfg(feature = "update-check")]
+fn fetch_json(url: &str) -> Result<serde_json::Value> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout(std::time::Duration::from_secs(FETCH_TIMEOUT_SECS))
+        .build();
+    let body = agent.get(url).call()?.into_string()?;
+    Ok(serde_json::from_str(&body)?)
+}
+
+#[cfg(not(feature = "update-check"))]
+fn fetch_json(_url: &str) -> Result<serde_json::Value> {
+    bail!("ksud is built without the update-check feature");
+}
+
+/// fetch `updateJson` of each module and print the ones which have a higher versionCode as json,
+/// it is never called on boot
+pub fn check_updates() -> Result<()> {
+    if Path::new(defs::NO_NETWORK_FLAG_PATH).exists() {
+        bail!(
+            "network is disabled by {}, remove it to check updates",
+            defs::NO_NETWORK_FLAG_PATH
+        );
+    }
+
+    let mut updates = Vec::new();
+    let mut dirs = std::fs::read_dir(defs::MODULE_DIR)?
+        .flatten()
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    dirs.sort();
+    for path in dirs {
+        let Ok(prop) = module::read_module_prop(&path) else {
+            continue;
+        };
+        let (Some(id), Some(url)) = (prop.get("id"), prop.get("updateJson")) else {
+            continue;
+        };
+        let installed = prop
+            .get("versionCode")
+            .and_then(|v| v.trim().parse::<i64>().ok())
+            .unwrap_or(0);
+        let latest = match fetch_json(url.trim()) {
+            Ok(latest) => latest,
+            Err(e) => {
+                warn!("check update of {id} from {url} failed: {e}");
+                continue;
+            }
+        };
+        // some servers give versionCode as a string
+        let latest_code = latest["versionCode"]
+            .as_i64()
+            .or_else(|| latest["versionCode"].as_str()?.trim().parse().ok())
+            .unwrap_or(0);
+        if latest_code <= installed {
+            continue;
+        }
+        updates.push(serde_json::json!({
+            "id": id,
+            "versionCode": installed,
+            "latestVersion": latest["version"],
+            "latestVersionCode": latest_code,
+            "zipUrl": latest["zipUrl"],
+            "changelog": latest["changelog"],
+        }));
+    }
+    println!("{}", serde_json::to_string_pretty(&updates)?);
+    Ok(())
+}
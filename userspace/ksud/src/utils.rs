@@ -1,10 +1,12 @@
 use anyhow::{bail, Context, Error, Ok, Result};
 use std::{
     fs::{create_dir_all, write, File, OpenOptions},
-    io::{ErrorKind::AlreadyExists, Write},
+    io::Write,
     path::Path,
 };
 
+use crate::defs;
+
 #[allow(unused_imports)]
 use std::fs::{set_permissions, Permissions};
 #[cfg(unix)]
@@ -14,24 +16,44 @@ pub fn ensure_clean_dir(dir: &str) -> Result<()> {
     let path = Path::new(dir);
     log::debug!("ensure_clean_dir: {}", path.display());
     if path.exists() {
+        // never remove the contents of a live mount, e.g. the module image of the last run
+        crate::mount::umount_dir_recursive(dir)?;
         log::debug!("ensure_clean_dir: {} exists, remove it", path.display());
         std::fs::remove_dir_all(path)?;
     }
     Ok(std::fs::create_dir_all(path)?)
 }
 
-pub fn ensure_file_exists<T: AsRef<Path>>(file: T) -> Result<()> {
-    match File::options().write(true).create_new(true).open(&file) {
-        std::result::Result::Ok(_) => Ok(()),
-        Err(err) => {
-            if err.kind() == AlreadyExists && file.as_ref().is_file() {
-                Ok(())
-            } else {
-                Err(Error::from(err))
-                    .with_context(|| format!("{} is not a regular file", file.as_ref().display()))
-            }
+/// create or remove the flag file atomically, it is created under a temp name and renamed into
+/// place, so an interrupted write never leaves a flag the boot may read half way
+pub fn set_flag<T: AsRef<Path>>(flag: T, on: bool) -> Result<()> {
+    let flag = flag.as_ref();
+    let name = flag
+        .file_name()
+        .with_context(|| format!("invalid flag: {}", flag.display()))?
+        .to_string_lossy();
+    if on {
+        if flag.is_file() {
+            return Ok(());
         }
+        // a stale temp flag of an interrupted write is just overwritten
+        let tmp = flag.with_file_name(format!(".{name}.tmp"));
+        File::create(&tmp)
+            .and_then(|f| f.sync_all())
+            .with_context(|| format!("Failed to create {}", tmp.display()))?;
+        std::fs::rename(&tmp, flag)
+            .with_context(|| format!("Failed to rename {} to {}", tmp.display(), flag.display()))?;
+    } else if flag.exists() {
+        // the rename drops the flag at once, the removal after it can fail harmlessly
+        let off = flag.with_file_name(format!(".{name}.off"));
+        std::fs::rename(flag, &off)
+            .with_context(|| format!("Failed to rename {} to {}", flag.display(), off.display()))?;
+        let _ = std::fs::remove_file(&off);
+    }
+    if let Some(parent) = flag.parent() {
+        let _ = File::open(parent).and_then(|dir| dir.sync_all());
     }
+    Ok(())
 }
 
 pub fn ensure_dir_exists<T: AsRef<Path>>(dir: T) -> Result<()> {
@@ -45,8 +67,17 @@ pub fn ensure_dir_exists<T: AsRef<Path>>(dir: T) -> Result<()> {
     }
 }
 
+// the file on disk is the same as `contents`, the size is compared first so most mismatches don't need a read
+fn is_same_binary(path: &Path, contents: &[u8]) -> bool {
+    let std::result::Result::Ok(metadata) = std::fs::metadata(path) else {
+        return false;
+    };
+    metadata.len() == contents.len() as u64
+        && std::fs::read(path).map_or(false, |data| data == contents)
+}
+
 pub fn ensure_binary<T: AsRef<Path>>(path: T, contents: &[u8]) -> Result<()> {
-    if path.as_ref().exists() {
+    if is_same_binary(path.as_ref(), contents) {
         return Ok(());
     }
 
@@ -57,12 +88,101 @@ pub fn ensure_binary<T: AsRef<Path>>(path: T, contents: &[u8]) -> Result<()> {
         )
     })?)?;
 
-    write(&path, contents)?;
+    // the old binary may be running, replace it instead of writing in place
+    let tmp = path.as_ref().with_extension("tmp");
+    write(&tmp, contents)?;
     #[cfg(unix)]
-    set_permissions(&path, Permissions::from_mode(0o755))?;
+    set_permissions(&tmp, Permissions::from_mode(0o755))?;
+    std::fs::rename(&tmp, &path)?;
     Ok(())
 }
 
+// set once ksud runs in the sandbox of `--root-dir`, the processes spawned by it inherit it
+const SANDBOX_ENV: &str = "KSUD_SANDBOX";
+
+/// the sandbox dir set by `--root-dir` or env KSUD_ROOT, which ksud is not in yet
+pub fn root_dir() -> Option<String> {
+    std::env::var("KSUD_ROOT")
+        .ok()
+        .filter(|root| !root.is_empty())
+}
+
+/// if ksud runs in the sandbox of `--root-dir`
+pub fn is_sandboxed() -> bool {
+    std::env::var_os(SANDBOX_ENV).is_some()
+}
+
+/// chroot into `root` in a private mount namespace, and a user namespace if we are not root.
+/// every path, e.g. all of defs, is then looked up under `root`, and the mounts made after it
+/// stay in the namespace and never reach the live system. it needs a single threaded process
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn enter_root_dir(root: &str) -> Result<()> {
+    let root = std::fs::canonicalize(root).with_context(|| format!("Failed to open {root}"))?;
+    let (uid, gid) = unsafe { (libc::getuid(), libc::getgid()) };
+    let mut flags = libc::CLONE_NEWNS;
+    if uid != 0 {
+        flags |= libc::CLONE_NEWUSER;
+    }
+    if unsafe { libc::unshare(flags) } != 0 {
+        bail!(
+            "unshare for {} failed: {}",
+            root.display(),
+            std::io::Error::last_os_error()
+        );
+    }
+    if uid != 0 {
+        // root in the namespace is the caller outside of it
+        std::fs::write("/proc/self/setgroups", "deny")?;
+        std::fs::write("/proc/self/uid_map", format!("0 {uid} 1"))?;
+        std::fs::write("/proc/self/gid_map", format!("0 {gid} 1"))?;
+    }
+
+    let mount = |src: &str, target: &str, flags: libc::c_ulong| -> Result<()> {
+        let src = std::ffi::CString::new(src)?;
+        let target = std::ffi::CString::new(target)?;
+        let ret = unsafe {
+            libc::mount(
+                src.as_ptr(),
+                target.as_ptr(),
+                std::ptr::null(),
+                flags,
+                std::ptr::null(),
+            )
+        };
+        anyhow::ensure!(
+            ret == 0,
+            "mount {src:?} on {target:?} failed: {}",
+            std::io::Error::last_os_error()
+        );
+        Ok(())
+    };
+    // the mounts of the sandbox don't propagate to the live system
+    mount("none", "/", libc::MS_REC | libc::MS_PRIVATE)?;
+    // the mount lookups read /proc, the loop devices are in /dev and /sys
+    for dir in ["/proc", "/dev", "/sys"] {
+        let target = root.join(dir.trim_start_matches('/'));
+        create_dir_all(&target)?;
+        mount(
+            dir,
+            &target.display().to_string(),
+            libc::MS_BIND | libc::MS_REC,
+        )?;
+    }
+    std::os::unix::fs::chroot(&root)
+        .with_context(|| format!("Failed to chroot {}", root.display()))?;
+    std::env::set_current_dir("/")?;
+
+    // the ksud processes spawned in it must not enter it again
+    std::env::remove_var("KSUD_ROOT");
+    std::env::set_var(SANDBOX_ENV, &root);
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn enter_root_dir(_root: &str) -> Result<()> {
+    unimplemented!()
+}
+
 #[cfg(any(target_os = "linux", target_os = "android"))]
 pub fn getprop(prop: &str) -> Option<String> {
     android_properties::getprop(prop).value()
@@ -89,6 +209,77 @@ pub fn is_safe_mode() -> bool {
     safemode
 }
 
+/// the path of modules.img, the configured location is used if the storage is available,
+/// otherwise the default one
+pub fn get_module_img() -> String {
+    let Some(location) = std::fs::read_to_string(defs::MODULE_IMG_LOCATION_PATH)
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+    else {
+        return defs::MODULE_IMG.to_string();
+    };
+    let external = Path::new(&location).join(defs::MODULE_IMG_NAME);
+    // the image should be on the storage if it is mounted, if neither image exists the
+    // configured location is used so that the first module is installed there
+    let ready = Path::new(&location).is_dir()
+        && (external.exists() || !Path::new(defs::MODULE_IMG).exists());
+    if !ready {
+        log::warn!(
+            "external storage {location} is not ready, use {}",
+            defs::MODULE_IMG
+        );
+        return defs::MODULE_IMG.to_string();
+    }
+    external.display().to_string()
+}
+
+/// fail if modules.img is configured on storage the boot can't mount it from, external and
+/// adopted storage are mounted after post-fs-data and the credential encrypted dirs of /data
+/// are locked until the user unlocks
+pub fn ensure_module_img_reachable() -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    let Some(location) = std::fs::read_to_string(defs::MODULE_IMG_LOCATION_PATH)
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+    else {
+        return Ok(());
+    };
+    let path = Path::new(&location)
+        .canonicalize()
+        .with_context(|| format!("module image location {location} is not available"))?;
+    let same_fs = std::fs::metadata(&path)?.dev() == std::fs::metadata(defs::WORKING_DIR)?.dev();
+    let locked = path.starts_with("/data/media") || path.starts_with("/data/user");
+    if !same_fs || locked {
+        bail!(
+            "post-fs-data can't reach {location}, remove {} to keep modules.img in {}",
+            defs::MODULE_IMG_LOCATION_PATH,
+            defs::WORKING_DIR
+        );
+    }
+    Ok(())
+}
+
+/// count this boot as unfinished until `reset_boot_count`, return how many boots in a row didn't complete before it
+pub fn record_boot_attempt() -> Result<u32> {
+    let count = std::fs::read_to_string(defs::BOOT_COUNT_PATH)
+        .ok()
+        .and_then(|c| c.trim().parse::<u32>().ok())
+        .unwrap_or(0);
+    write(defs::BOOT_COUNT_PATH, (count + 1).to_string())
+        .with_context(|| format!("Failed to write {}", defs::BOOT_COUNT_PATH))?;
+    Ok(count)
+}
+
+pub fn reset_boot_count() -> Result<()> {
+    if Path::new(defs::BOOT_COUNT_PATH).exists() {
+        std::fs::remove_file(defs::BOOT_COUNT_PATH)
+            .with_context(|| format!("Failed to remove {}", defs::BOOT_COUNT_PATH))?;
+    }
+    Ok(())
+}
+
 pub fn get_zip_uncompressed_size(zip_path: &str) -> Result<u64> {
     let mut zip = zip::ZipArchive::new(std::fs::File::open(zip_path)?)?;
     let total: u64 = (0..zip.len())
@@ -97,6 +288,23 @@ pub fn get_zip_uncompressed_size(zip_path: &str) -> Result<u64> {
     Ok(total)
 }
 
+// available space of the filesystem which `path` is on
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn get_free_space(path: &str) -> Result<u64> {
+    use anyhow::ensure;
+    let c_path = std::ffi::CString::new(path)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    let ret = unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) };
+    ensure!(ret == 0, "statvfs {path} failed");
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn get_free_space(_path: &str) -> Result<u64> {
+    unimplemented!()
+}
+
 #[cfg(any(target_os = "linux", target_os = "android"))]
 pub fn switch_mnt_ns(pid: i32) -> Result<()> {
     use anyhow::ensure;
@@ -108,6 +316,15 @@ pub fn switch_mnt_ns(pid: i32) -> Result<()> {
     Ok(())
 }
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn is_global_mnt_ns(pid: i32) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let ns = std::fs::metadata(format!("/proc/{pid}/ns/mnt"))
+        .with_context(|| format!("Failed to get mnt ns of process {pid}"))?;
+    let global_ns = std::fs::metadata("/proc/1/ns/mnt")?;
+    Ok(ns.dev() == global_ns.dev() && ns.ino() == global_ns.ino())
+}
+
 #[cfg(any(target_os = "linux", target_os = "android"))]
 pub fn unshare_mnt_ns() -> Result<()> {
     use anyhow::ensure;
@@ -142,9 +359,45 @@ pub fn switch_cgroups() {
     }
 }
 
+/// script_umask of the config, or DEFAULT_SCRIPT_UMASK
+pub fn get_script_umask() -> u32 {
+    crate::config::get().script_umask
+}
+
 #[cfg(any(target_os = "linux", target_os = "android"))]
 pub fn umask(mask: u32) {
-    unsafe { libc::umask(mask) };
+    // only the permission bits
+    unsafe { libc::umask(mask & 0o777) };
+}
+
+/// lower the cpu and io priority of the calling thread and what it spawns if low_priority of the
+/// config is set, the service stage which the boot does not wait for uses it
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn set_low_priority() {
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+    const IOPRIO_CLASS_BE: libc::c_int = 2;
+
+    if !crate::config::get().low_priority {
+        return;
+    }
+    // the lowest best-effort io level
+    let nice = 10;
+    let ioprio = (IOPRIO_CLASS_BE << IOPRIO_CLASS_SHIFT) | 7;
+    unsafe {
+        if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0 {
+            log::warn!("setpriority failed: {}", std::io::Error::last_os_error());
+        }
+        if libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, ioprio) != 0 {
+            log::warn!("ioprio_set failed: {}", std::io::Error::last_os_error());
+        }
+    }
+    log::info!("priority: nice {nice}, ioprio {ioprio:#x}");
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn set_low_priority() {
+    unimplemented!()
 }
 
 #[cfg(not(any(target_os = "linux", target_os = "android")))]
@@ -152,6 +405,136 @@ pub fn umask(_mask: u32) {
     unimplemented!("umask is not supported on this platform")
 }
 
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn kill_process_group(pgid: u32) {
+    unsafe {
+        #[allow(clippy::cast_possible_wrap)]
+        libc::killpg(pgid as libc::pid_t, libc::SIGKILL);
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn kill_process_group(_pgid: u32) {
+    unimplemented!("kill_process_group is not supported on this platform")
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MagiskState {
+    NotFound,
+    // Magisk is not running but leaves some files, such as an uninstalled Magisk
+    Stale(String),
+    // Magisk is installed and managing mounts
+    Active(String),
+}
+
+const MAGISK_DATA_ARTIFACTS: [&str; 2] = ["/data/adb/magisk", "/data/adb/magisk.db"];
+
+// the magisk binary is only in PATH when Magisk is booted, so it is a reliable sign of active Magisk
+pub fn get_magisk_state() -> MagiskState {
+    if let std::result::Result::Ok(magisk) = which::which("magisk") {
+        return MagiskState::Active(magisk.display().to_string());
+    }
+    if is_process_running("magiskd") {
+        return MagiskState::Active("magiskd process".to_string());
+    }
+    if let Some(artifact) = MAGISK_DATA_ARTIFACTS
+        .iter()
+        .find(|artifact| Path::new(artifact).exists())
+    {
+        return MagiskState::Stale((*artifact).to_string());
+    }
+    MagiskState::NotFound
+}
+
+fn is_process_running(name: &str) -> bool {
+    let Some(dir) = std::fs::read_dir("/proc").ok() else {
+        return false;
+    };
+    dir.flatten().any(|entry| {
+        std::fs::read_to_string(entry.path().join("comm"))
+            .map(|comm| comm.trim() == name)
+            .unwrap_or(false)
+    })
+}
+
 pub fn has_magisk() -> bool {
-    which::which("magisk").is_ok()
+    match get_magisk_state() {
+        MagiskState::NotFound => false,
+        MagiskState::Stale(artifact) => {
+            log::warn!("stale Magisk file: {artifact}, Magisk is not running, ignore it");
+            false
+        }
+        MagiskState::Active(artifact) => {
+            log::warn!("Magisk is active, detected by: {artifact}");
+            true
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn ensure_binary_twice_is_noop() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("busybox");
+        ensure_binary(&path, b"binary").unwrap();
+        let ino = std::fs::metadata(&path).unwrap().ino();
+        ensure_binary(&path, b"binary").unwrap();
+        // a rewrite renames a new file into place, which changes the inode
+        assert_eq!(std::fs::metadata(&path).unwrap().ino(), ino);
+        assert!(!path.with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn set_flag_over_an_interrupted_write() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let flag = dir.join(defs::DISABLE_FILE_NAME);
+        let tmp = dir.join(format!(".{}.tmp", defs::DISABLE_FILE_NAME));
+        let off = dir.join(format!(".{}.off", defs::DISABLE_FILE_NAME));
+
+        // a write killed before the rename leaves only the temp flag, which is not the flag
+        write(&tmp, "").unwrap();
+        assert!(!flag.exists());
+        set_flag(&flag, true).unwrap();
+        assert!(flag.is_file());
+        assert!(!tmp.exists());
+
+        // a removal killed after the rename has dropped the flag already
+        std::fs::rename(&flag, &off).unwrap();
+        assert!(!flag.exists());
+        set_flag(&flag, true).unwrap();
+        set_flag(&flag, false).unwrap();
+        assert!(!flag.exists());
+        assert!(!off.exists());
+    }
+
+    #[test]
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[ignore = "needs root"]
+    fn ensure_clean_dir_does_not_remove_through_a_bind_mount() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path();
+        let tmpfs = base.join("tmpfs");
+        let dir = base.join("modules");
+        create_dir_all(&tmpfs).unwrap();
+        create_dir_all(dir.join("module")).unwrap();
+        let tmpfs_str = tmpfs.display().to_string();
+        sys_mount::Mount::builder()
+            .fstype(sys_mount::FilesystemType::from("tmpfs"))
+            .mount("tmpfs", &tmpfs)
+            .unwrap();
+        write(tmpfs.join("file"), "keep").unwrap();
+        crate::mount::bind_mount(&tmpfs, dir.join("module")).unwrap();
+
+        ensure_clean_dir(&dir.display().to_string()).unwrap();
+        assert!(std::fs::read_dir(&dir).unwrap().next().is_none());
+        // the file is still in the tmpfs, only the bind mount of it was taken down
+        assert_eq!(std::fs::read_to_string(tmpfs.join("file")).unwrap(), "keep");
+
+        crate::mount::umount_dir(&tmpfs_str).unwrap();
+    }
 }
@@ -0,0 +1,48 @@
+// `ksud --root-dir` against a sandbox tree, as CI runs it without root or a device
+use std::{path::Path, process::Command};
+
+fn write_file(path: &Path, content: &str) {
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    std::fs::write(path, content).unwrap();
+}
+
+#[test]
+fn mount_list_of_a_sandbox() {
+    let tmp = tempfile::tempdir().unwrap();
+    let root = tmp.path();
+    std::fs::create_dir_all(root.join("system/bin")).unwrap();
+    std::fs::create_dir_all(root.join("vendor")).unwrap();
+    let modules = root.join("data/adb/modules");
+    write_file(&modules.join("demo/module.prop"), "id=demo\n");
+    write_file(&modules.join("demo/system/bin/demo"), "");
+    write_file(&modules.join("demo/vendor/lib/demo.so"), "");
+    write_file(&modules.join("off/module.prop"), "id=off\n");
+    write_file(&modules.join("off/system/bin/off"), "");
+    write_file(&modules.join("off/disable"), "");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_ksud"))
+        .arg("--root-dir")
+        .arg(root)
+        .args(["mount", "list"])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() && stderr.contains("unshare") {
+        // no user namespaces on this host
+        eprintln!("skip: {stderr}");
+        return;
+    }
+    assert!(output.status.success(), "{stderr}");
+
+    // the paths are the ones in the sandbox
+    let layout: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(layout["module_dir"], "/data/adb/modules/");
+    assert_eq!(
+        layout["partitions"],
+        serde_json::json!({
+            "system": ["/data/adb/modules/demo/system"],
+            "vendor": ["/data/adb/modules/demo/vendor"],
+        })
+    );
+    assert_eq!(layout["hosts"], serde_json::json!([]));
+}